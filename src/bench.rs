@@ -0,0 +1,158 @@
+// Standard workloads for telling whether a change made things faster or
+// slower, run with `simple-db bench [rows]`. Each one runs against the same
+// database in a temp directory, the inserts first so the rest have rows to
+// work with, and every operation is timed on its own for the percentiles.
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use tempdir::TempDir;
+
+use super::{DbError, Row, Table};
+
+// Rows in each scan of the range scan workload
+const SCAN_ROWS : usize = 100;
+
+pub struct BenchResult {
+    pub name : &'static str,
+    pub elapsed : Duration,
+    // one per operation, sorted
+    pub latencies : Vec<Duration>,
+}
+
+impl BenchResult {
+    pub fn ops_per_sec(&self) -> f64 {
+        self.latencies.len() as f64 / self.elapsed.as_secs_f64().max(1e-9)
+    }
+
+    // p between 0 and 100
+    pub fn percentile(&self, p : f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::default();
+        }
+        let i = ((self.latencies.len() - 1) as f64 * p / 100.0).round() as usize;
+        self.latencies[i]
+    }
+}
+
+// xorshift64, good enough to pick ids and not worth a dependency
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n : usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n as u64) as usize
+    }
+}
+
+struct Workload {
+    name : &'static str,
+    started : Instant,
+    latencies : Vec<Duration>,
+}
+
+impl Workload {
+    fn new(name : &'static str) -> Workload {
+        Workload { name, started : Instant::now(), latencies : vec![] }
+    }
+
+    fn time<T>(&mut self, op : impl FnOnce() -> Result<T, DbError>) -> Result<T, DbError> {
+        let start = Instant::now();
+        let result = op()?;
+        self.latencies.push(start.elapsed());
+        Ok(result)
+    }
+
+    fn finish(mut self) -> BenchResult {
+        let elapsed = self.started.elapsed();
+        self.latencies.sort();
+        BenchResult { name : self.name, elapsed, latencies : self.latencies }
+    }
+}
+
+fn row(id : usize) -> Row {
+    Row {
+        id : id as u32,
+        user_id : format!("user{}", id),
+        email : format!("person{}@example.com", id),
+    }
+}
+
+// Rows with an id of at least key, at most count of them
+fn scan(table : &mut Table, key : u32, count : usize) -> Result<usize, DbError> {
+    let mut cursor = table.find(key)?;
+    let mut seen = 0;
+    while seen < count && cursor.value()?.is_some() {
+        seen += 1;
+        cursor.advance()?;
+    }
+    Ok(seen)
+}
+
+pub fn run(rows : usize) -> Result<Vec<BenchResult>, DbError> {
+    let rows = rows.max(1);
+    let tmp_dir = TempDir::new("simple-db-bench")?;
+    let mut table = Table::db_open(tmp_dir.path().join("bench.db"))?;
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    let mut results = vec![];
+
+    let mut workload = Workload::new("sequential insert");
+    for id in 0..rows {
+        workload.time(|| table.add_row(&row(id)))?;
+    }
+    table.flush()?;
+    results.push(workload.finish());
+
+    let mut workload = Workload::new("random point read");
+    for _ in 0..rows {
+        let id = rng.below(rows) as u32;
+        workload.time(|| scan(&mut table, id, 1))?;
+    }
+    results.push(workload.finish());
+
+    let mut workload = Workload::new("range scan");
+    for _ in 0..rows.div_ceil(SCAN_ROWS) {
+        let id = rng.below(rows) as u32;
+        workload.time(|| scan(&mut table, id, SCAN_ROWS))?;
+    }
+    results.push(workload.finish());
+
+    // mostly reads, new rows go past the end and deletes
+    // hit ids that may already be gone
+    let mut workload = Workload::new("mixed");
+    let mut next_id = rows;
+    for _ in 0..rows {
+        match rng.below(10) {
+            0..=6 => {
+                let id = rng.below(next_id) as u32;
+                workload.time(|| scan(&mut table, id, 1))?;
+            },
+            7 | 8 => {
+                workload.time(|| table.add_row(&row(next_id)))?;
+                next_id += 1;
+            },
+            _ => {
+                let id = rng.below(next_id) as u32;
+                workload.time(|| table.delete(id))?;
+            },
+        }
+    }
+    table.flush()?;
+    results.push(workload.finish());
+
+    table.close()?;
+    Ok(results)
+}
+
+pub fn report(results : &[BenchResult], writer : &mut dyn Write) -> io::Result<()> {
+    writeln!(writer, "{:<20} {:>8} {:>12} {:>10} {:>10} {:>10}",
+             "workload", "ops", "ops/sec", "p50 us", "p99 us", "max us")?;
+    for result in results {
+        writeln!(writer, "{:<20} {:>8} {:>12.0} {:>10} {:>10} {:>10}",
+                 result.name, result.latencies.len(), result.ops_per_sec(),
+                 result.percentile(50.0).as_micros(), result.percentile(99.0).as_micros(),
+                 result.percentile(100.0).as_micros())?;
+    }
+    writer.flush()
+}
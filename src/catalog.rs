@@ -0,0 +1,168 @@
+// A directory holding any number of databases, <name>.db each, or
+// <name>.log for those created for the log engine. Names are kept to
+// letters, digits, '_' and '-' so they can't point outside of it.
+use std::fs;
+use std::path::PathBuf;
+
+use storage::sync_dir;
+use super::{columns, execute, parse, query, DbError, Engine, LogTable, ResultSink, Statement,
+            Table, TableInfo, TableOptions};
+
+const EXTENSION: &str = "db";
+const LOG_EXTENSION: &str = "log";
+
+pub struct Catalog {
+    dir : PathBuf,
+    // used for every database opened through the catalog
+    options : TableOptions,
+}
+
+impl Catalog {
+    pub fn open(dir : PathBuf) -> Result<Catalog, DbError> {
+        Catalog::open_with(dir, TableOptions::default())
+    }
+
+    // Creates the directory if it doesn't exist
+    pub fn open_with(dir : PathBuf, options : TableOptions) -> Result<Catalog, DbError> {
+        if !dir.exists() {
+            fs::create_dir_all(&dir)?;
+            sync_dir(&dir)?;
+        }
+        Ok(Catalog { dir, options })
+    }
+
+    // Names of every database, sorted
+    pub fn list_databases(&self) -> Result<Vec<String>, DbError> {
+        let mut names = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == EXTENSION || ext == LOG_EXTENSION) {
+                if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+                    if valid_name(name) {
+                        names.push(name.to_string());
+                    }
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    // A database holds a single table, known by the database's name
+    pub fn tables(&self) -> Result<Vec<TableInfo>, DbError> {
+        Ok(self.list_databases()?.into_iter()
+               .map(|name| TableInfo { name, columns : columns() })
+               .collect())
+    }
+
+    pub fn create_database(&self, name : &str) -> Result<Table, DbError> {
+        let path = self.new_path(name, EXTENSION)?;
+        Table::db_open_with(path, self.options.clone())
+    }
+
+    // A new database kept by the log engine instead of a B-tree, which
+    // stays with it. The table options don't apply to it.
+    pub fn create_log_database(&self, name : &str) -> Result<LogTable, DbError> {
+        let path = self.new_path(name, LOG_EXTENSION)?;
+        LogTable::open(path)
+    }
+
+    // A new database holding what a select statement returns from source,
+    // to keep an intermediate result around. Every database has the same
+    // columns so the rows go in whole, selects of some of the columns or
+    // distinct ones are refused. Fails without leaving the database behind.
+    pub fn create_database_as(&self, name : &str, source : &mut dyn Engine,
+                              statement : &Statement) -> Result<Table, DbError> {
+        match *statement {
+            Statement::Select | Statement::SelectId(_) | Statement::SelectRange(..) |
+            Statement::Sorted(..) | Statement::Limited(..) => (),
+            _ => return Err(DbError::StatementSyntaxError),
+        }
+        let mut table = self.create_database(name)?;
+        let result = query(statement, source, &mut |r| table.insert(&r));
+        if let Err(err) = result.and_then(|_| table.flush()) {
+            drop(table);
+            // what went wrong in the first place is the error worth reporting
+            let _ = self.drop_database(name);
+            return Err(err);
+        }
+        Ok(table)
+    }
+
+    // Run create table <name> as <select> with the select reading from source
+    pub fn create_table_as(&self, statement : &Statement,
+                           source : &mut dyn Engine) -> Result<Table, DbError> {
+        match *statement {
+            Statement::CreateTableAs(ref name, ref select) =>
+                self.create_database_as(name, source, select),
+            _ => Err(DbError::StatementUnrecognized),
+        }
+    }
+
+    // statement_command for a table in the catalog, create table ... as
+    // select makes its database here and the rest run against table
+    pub fn statement_command(&self, input : &str, table : &mut dyn Engine,
+                             sink : &mut dyn ResultSink) -> Result<(), DbError> {
+        let result = parse(input).and_then(|statement| match statement {
+            Statement::CreateTableAs(..) => self.create_table_as(&statement, table)?.close(),
+            _ => execute(&statement, table, sink),
+        });
+        if let Err(ref err) = result {
+            sink.error(err)?;
+        }
+        result
+    }
+
+    pub fn open_database(&self, name : &str) -> Result<Table, DbError> {
+        let path = self.path(name, EXTENSION)?;
+        if !path.exists() {
+            return Err(DbError::UnknownDatabase(name.to_string()));
+        }
+        Table::db_open_with(path, self.options.clone())
+    }
+
+    // Opens a database with whichever engine it was created for
+    pub fn open_engine(&self, name : &str) -> Result<Box<dyn Engine>, DbError> {
+        let path = self.path(name, LOG_EXTENSION)?;
+        if path.exists() {
+            return Ok(Box::new(LogTable::open(path)?));
+        }
+        Ok(Box::new(self.open_database(name)?))
+    }
+
+    // Tables already open on it keep working until they are dropped
+    pub fn drop_database(&self, name : &str) -> Result<(), DbError> {
+        let mut path = self.path(name, EXTENSION)?;
+        if !path.exists() {
+            path = self.path(name, LOG_EXTENSION)?;
+        }
+        if !path.exists() {
+            return Err(DbError::UnknownDatabase(name.to_string()));
+        }
+        fs::remove_file(&path)?;
+        sync_dir(&path)?;
+        Ok(())
+    }
+
+    fn path(&self, name : &str, extension : &str) -> Result<PathBuf, DbError> {
+        if !valid_name(name) {
+            return Err(DbError::InvalidDatabaseName(name.to_string()));
+        }
+        Ok(self.dir.join(name).with_extension(extension))
+    }
+
+    // Where a database that doesn't exist yet under either engine goes
+    fn new_path(&self, name : &str, extension : &str) -> Result<PathBuf, DbError> {
+        for ext in &[EXTENSION, LOG_EXTENSION] {
+            if self.path(name, ext)?.exists() {
+                return Err(DbError::DatabaseExists(name.to_string()));
+            }
+        }
+        self.path(name, extension)
+    }
+}
+
+fn valid_name(name : &str) -> bool {
+    !name.is_empty() &&
+        name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
@@ -0,0 +1,118 @@
+// Torn page protection. A crash part way through overwriting a page can
+// leave half of the old and half of the new version behind, which the page
+// checksum catches but can't undo. DoubleWriteStorage holds on to written
+// pages until sync, then writes them to a separate buffer and syncs that
+// before overwriting anything in place, and syncs the database before the
+// buffer is reused. Whichever page a crash tears, a whole copy of it is in
+// the buffer, and opening puts back every page that fails its checksum.
+//
+// Page 0 of the buffer lists the pages of the last batch, page i + 1
+// holds a copy of the i-th of them.
+use std::collections::BTreeMap;
+use std::io;
+
+use node::{read_u32, write_u32};
+use pager::{checksum_ok, set_checksum};
+use storage::Storage;
+use super::{PAGE_SIZE, PAGE_USABLE_SIZE};
+
+const MAGIC: &[u8; 16] = b"simple-db dwb\0\0\0";
+const COUNT_OFFSET: usize = 16;
+const PAGES_OFFSET: usize = 20;
+const MAX_BATCH: usize = (PAGE_USABLE_SIZE - PAGES_OFFSET) / 4;
+
+pub struct DoubleWriteStorage {
+    inner : Box<dyn Storage>,
+    buffer : Box<dyn Storage>,
+    // written since the last sync
+    pending : BTreeMap<usize, Vec<u8>>,
+}
+
+impl DoubleWriteStorage {
+    // Repairs any page of inner torn by a crash during the last sync
+    pub fn new(mut inner : Box<dyn Storage>,
+               mut buffer : Box<dyn Storage>) -> io::Result<DoubleWriteStorage> {
+        let mut list = vec![0; PAGE_SIZE];
+        buffer.read_page(0, &mut list)?;
+        if &list[..MAGIC.len()] == MAGIC && checksum_ok(&list) {
+            let count = (read_u32(&list, COUNT_OFFSET) as usize).min(MAX_BATCH);
+            let mut copy = vec![0; PAGE_SIZE];
+            let mut page = vec![0; PAGE_SIZE];
+            let mut repaired = false;
+            for i in 0..count {
+                let page_num = read_u32(&list, PAGES_OFFSET + i * 4) as usize;
+                buffer.read_page(i + 1, &mut copy)?;
+                inner.read_page(page_num, &mut page)?;
+                if checksum_ok(&copy) && !checksum_ok(&page) {
+                    inner.write_page(page_num, &copy)?;
+                    repaired = true;
+                }
+            }
+            if repaired {
+                inner.sync()?;
+            }
+        }
+        Ok(DoubleWriteStorage { inner, buffer, pending : BTreeMap::new() })
+    }
+}
+
+impl Storage for DoubleWriteStorage {
+    fn read_page(&mut self, page_num : usize, data : &mut [u8]) -> io::Result<()> {
+        match self.pending.get(&page_num) {
+            Some(page) => {
+                data.copy_from_slice(page);
+                Ok(())
+            },
+            None => self.inner.read_page(page_num, data),
+        }
+    }
+
+    fn write_page(&mut self, page_num : usize, data : &[u8]) -> io::Result<()> {
+        self.pending.insert(page_num, data.to_vec());
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        while !self.pending.is_empty() {
+            let batch : Vec<usize> = self.pending.keys().take(MAX_BATCH).cloned().collect();
+            let mut list = vec![0; PAGE_SIZE];
+            list[..MAGIC.len()].copy_from_slice(MAGIC);
+            write_u32(&mut list, COUNT_OFFSET, batch.len() as u32);
+            for (i, &page_num) in batch.iter().enumerate() {
+                write_u32(&mut list, PAGES_OFFSET + i * 4, page_num as u32);
+                self.buffer.write_page(i + 1, &self.pending[&page_num])?;
+            }
+            set_checksum(&mut list);
+            self.buffer.write_page(0, &list)?;
+            self.buffer.sync()?;
+            for &page_num in &batch {
+                self.inner.write_page(page_num, &self.pending[&page_num])?;
+            }
+            self.inner.sync()?;
+            for page_num in batch {
+                self.pending.remove(&page_num);
+            }
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        let pending = self.pending.keys().next_back()
+            .map_or(0, |&page_num| ((page_num + 1) * PAGE_SIZE) as u64);
+        Ok(self.inner.len()?.max(pending))
+    }
+
+    fn prefetch(&mut self, page_num : usize, count : usize) {
+        self.inner.prefetch(page_num, count);
+    }
+}
+
+impl Drop for DoubleWriteStorage {
+    // pages only reach the database on sync, which a table that
+    // is dropped without being closed never asks for
+    fn drop(&mut self) {
+        if let Err(err) = self.sync() {
+            eprintln!("simple-db: could not write pages: {}", err);
+        }
+    }
+}
@@ -0,0 +1,146 @@
+// Page 0 of the file is a header, the tree starts on page 1. The header
+// is a magic string, the format version, the page size the file was
+// written with and a flags word, then the first page of the free list
+// (0 when it is empty) and how many pages are on it, the sequence
+// number of the last change made to the table and how many rows the
+// table holds, both as u64s. Three more u64s keep the table's history:
+// rows ever inserted, vacuums run and the unix time of the last flush
+// (0 before the first). Files written before they were added have zeroes
+// there, which reads as a history starting now. The rest of the page is
+// zeroed apart from the page checksum.
+// Integers are little endian, like in the nodes.
+use node::{read_u32, write_u32};
+use super::{DbError, PAGE_SIZE};
+
+pub const MAGIC: &[u8; 16] = b"simple-db file\0\0";
+pub const FORMAT_VERSION: u32 = 4;
+pub const ROOT_PAGE_NUM: usize = 1;
+// Written by Table::seal, the file is opened read-only and mapped
+pub const FLAG_SEALED: u32 = 1;
+
+const MAGIC_OFFSET: usize = 0;
+const VERSION_OFFSET: usize = MAGIC_OFFSET + 16;
+const PAGE_SIZE_OFFSET: usize = VERSION_OFFSET + 4;
+const FLAGS_OFFSET: usize = PAGE_SIZE_OFFSET + 4;
+const FREE_LIST_HEAD_OFFSET: usize = FLAGS_OFFSET + 4;
+const FREE_PAGE_COUNT_OFFSET: usize = FREE_LIST_HEAD_OFFSET + 4;
+const LSN_OFFSET: usize = FREE_PAGE_COUNT_OFFSET + 4;
+const ROW_COUNT_OFFSET: usize = LSN_OFFSET + 8;
+const ROWS_INSERTED_OFFSET: usize = ROW_COUNT_OFFSET + 8;
+const VACUUM_COUNT_OFFSET: usize = ROWS_INSERTED_OFFSET + 8;
+const CHECKPOINT_TIME_OFFSET: usize = VACUUM_COUNT_OFFSET + 8;
+
+pub fn initialize_header(page: &mut [u8]) {
+    for b in page.iter_mut() {
+        *b = 0;
+    }
+    page[MAGIC_OFFSET..MAGIC_OFFSET + MAGIC.len()].copy_from_slice(MAGIC);
+    write_u32(page, VERSION_OFFSET, FORMAT_VERSION);
+    write_u32(page, PAGE_SIZE_OFFSET, PAGE_SIZE as u32);
+    write_u32(page, FLAGS_OFFSET, 0);
+}
+
+pub fn version(page: &[u8]) -> u32 {
+    read_u32(page, VERSION_OFFSET)
+}
+
+pub fn page_size(page: &[u8]) -> u32 {
+    read_u32(page, PAGE_SIZE_OFFSET)
+}
+
+pub fn flags(page: &[u8]) -> u32 {
+    read_u32(page, FLAGS_OFFSET)
+}
+
+pub fn set_flags(page: &mut [u8], flags: u32) {
+    write_u32(page, FLAGS_OFFSET, flags);
+}
+
+pub fn free_list_head(page: &[u8]) -> usize {
+    read_u32(page, FREE_LIST_HEAD_OFFSET) as usize
+}
+
+pub fn set_free_list_head(page: &mut [u8], page_num: usize) {
+    write_u32(page, FREE_LIST_HEAD_OFFSET, page_num as u32);
+}
+
+pub fn free_page_count(page: &[u8]) -> usize {
+    read_u32(page, FREE_PAGE_COUNT_OFFSET) as usize
+}
+
+pub fn set_free_page_count(page: &mut [u8], count: usize) {
+    write_u32(page, FREE_PAGE_COUNT_OFFSET, count as u32);
+}
+
+pub fn lsn(page: &[u8]) -> u64 {
+    read_u64(page, LSN_OFFSET)
+}
+
+pub fn set_lsn(page: &mut [u8], lsn: u64) {
+    write_u64(page, LSN_OFFSET, lsn);
+}
+
+pub fn row_count(page: &[u8]) -> u64 {
+    read_u64(page, ROW_COUNT_OFFSET)
+}
+
+pub fn set_row_count(page: &mut [u8], count: u64) {
+    write_u64(page, ROW_COUNT_OFFSET, count);
+}
+
+pub fn rows_inserted(page: &[u8]) -> u64 {
+    read_u64(page, ROWS_INSERTED_OFFSET)
+}
+
+pub fn set_rows_inserted(page: &mut [u8], count: u64) {
+    write_u64(page, ROWS_INSERTED_OFFSET, count);
+}
+
+pub fn vacuum_count(page: &[u8]) -> u64 {
+    read_u64(page, VACUUM_COUNT_OFFSET)
+}
+
+pub fn set_vacuum_count(page: &mut [u8], count: u64) {
+    write_u64(page, VACUUM_COUNT_OFFSET, count);
+}
+
+pub fn checkpoint_time(page: &[u8]) -> u64 {
+    read_u64(page, CHECKPOINT_TIME_OFFSET)
+}
+
+pub fn set_checkpoint_time(page: &mut [u8], secs: u64) {
+    write_u64(page, CHECKPOINT_TIME_OFFSET, secs);
+}
+
+// Copy the history fields from one header to another
+pub fn copy_history(from: &[u8], to: &mut [u8]) {
+    to[ROWS_INSERTED_OFFSET..CHECKPOINT_TIME_OFFSET + 8]
+        .copy_from_slice(&from[ROWS_INSERTED_OFFSET..CHECKPOINT_TIME_OFFSET + 8]);
+}
+
+fn read_u64(page: &[u8], offset: usize) -> u64 {
+    read_u32(page, offset) as u64 | (read_u32(page, offset + 4) as u64) << 32
+}
+
+fn write_u64(page: &mut [u8], offset: usize, value: u64) {
+    write_u32(page, offset, value as u32);
+    write_u32(page, offset + 4, (value >> 32) as u32);
+}
+
+// Make sure page 0 was written by a simple-db this version can read
+pub fn validate(page: &[u8]) -> Result<(), DbError> {
+    if &page[MAGIC_OFFSET..MAGIC_OFFSET + MAGIC.len()] != MAGIC {
+        return Err(DbError::NotADatabase);
+    }
+    if version(page) != FORMAT_VERSION {
+        return Err(DbError::UnsupportedVersion(version(page)));
+    }
+    if page_size(page) != PAGE_SIZE as u32 {
+        return Err(DbError::Corrupt(format!("page size is {}, expected {}",
+                                            page_size(page), PAGE_SIZE)));
+    }
+    if flags(page) & !FLAG_SEALED != 0 {
+        return Err(DbError::Corrupt(format!("unknown flags {:#x}", flags(page))));
+    }
+    Ok(())
+}
@@ -1,15 +1,48 @@
+#[cfg(unix)]
+extern crate libc;
 extern crate tempdir;
 
+pub mod bench;
+mod catalog;
+mod double_write;
+mod header;
+mod log_table;
+mod lsm_table;
+mod mmap;
+mod node;
+#[cfg(feature = "object-store")]
+mod object_storage;
+mod overflow;
+mod pager;
+mod shared_table;
+mod sink;
+mod storage;
+
+use std::cmp::Ordering;
+use std::collections::HashSet;
 use std::fmt;
 use std::error;
 use std::str;
-use std::io::Write;
-use std::io::Read;
-use std::io::Seek;
-use std::io::SeekFrom;
-use std::fs::File;
-use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::fs::{self, OpenOptions};
+use std::ffi::OsString;
+use std::mem;
 use std::path::PathBuf;
+use std::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use node::NodeType;
+use pager::Pager;
+pub use pager::CacheStats;
+pub use sink::{ResultSink, Value, TextSink, CsvSink, JsonSink, MemorySink};
+pub use storage::{Storage, FileStorage, MemoryStorage, ByteStorage};
+pub use double_write::DoubleWriteStorage;
+pub use catalog::Catalog;
+pub use shared_table::SharedTable;
+pub use log_table::LogTable;
+pub use lsm_table::{LsmTable, LsmOptions};
+#[cfg(feature = "object-store")]
+pub use object_storage::{ObjectStore, ObjectStorage};
 
 
 #[derive(Debug)]
@@ -18,7 +51,24 @@ pub enum DbError {
     StatementUnrecognized,
     StatementSyntaxError,
     TableFull,
+    QuotaExceeded,
+    // a row over max_row_size, how many bytes it takes
+    RowTooLarge(usize),
+    ReadOnly,
+    UnknownDatabase(String),
+    DatabaseExists(String),
+    InvalidDatabaseName(String),
+    DuplicateKey,
+    // delete of an id with no row
+    NotFound(u32),
+    // what was found to be wrong
+    Corrupt(String),
+    NotADatabase,
+    UnsupportedVersion(u32),
+    // another table has the file open
+    Locked,
     ParsingError(std::num::ParseIntError),
+    IoError(std::io::Error),
 }
 
 impl fmt::Display for DbError {
@@ -29,24 +79,32 @@ impl fmt::Display for DbError {
             DbError::StatementSyntaxError => 
                 write!(f, "Statement has syntax error"),
             DbError::TableFull => write!(f, "Table is full"),
+            DbError::QuotaExceeded => write!(f, "Database size quota exceeded"),
+            DbError::RowTooLarge(size) => write!(f, "Row of {} bytes is over the row size limit", size),
+            DbError::ReadOnly => write!(f, "Database is read-only"),
+            DbError::UnknownDatabase(ref name) => write!(f, "No database named {}", name),
+            DbError::DatabaseExists(ref name) =>
+                write!(f, "Database {} already exists", name),
+            DbError::InvalidDatabaseName(ref name) =>
+                write!(f, "Invalid database name {:?}", name),
+            DbError::DuplicateKey => write!(f, "Duplicate key"),
+            DbError::NotFound(id) => write!(f, "No row with id {}", id),
+            DbError::Corrupt(ref problem) => write!(f, "Database is corrupt: {}", problem),
+            DbError::NotADatabase => write!(f, "File is not a simple-db database"),
+            DbError::UnsupportedVersion(version) =>
+                write!(f, "Unsupported database format version {}", version),
+            DbError::Locked => write!(f, "Database file is in use"),
             DbError::ParsingError(ref err) => err.fmt(f),
+            DbError::IoError(ref err) => err.fmt(f),
         }
     }
 }
 
 impl error::Error for DbError {
-    fn description(&self) -> &str {
-        match *self {
-            DbError::MetaUnrecognized => "Unrecognized",
-            DbError::StatementUnrecognized => "Unrecognized",
-            DbError::StatementSyntaxError => "Syntax Error",
-            DbError::TableFull => "Table full",
-            DbError::ParsingError(ref err) => err.description(),
-        }
-    }
-    fn cause(&self) -> Option<&error::Error> {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match *self {
             DbError::ParsingError(ref err) => Some(err),
+            DbError::IoError(ref err) => Some(err),
             _ => None,
         }
     }
@@ -58,372 +116,5041 @@ impl From<std::num::ParseIntError> for DbError {
     }
 }
 
+impl From<std::io::Error> for DbError {
+    fn from(err: std::io::Error) -> DbError {
+        DbError::IoError(err)
+    }
+}
+
 const USERID_SIZE: usize = 31;
 const EMAIL_SIZE: usize = 254;
-// Store size of email/id instead of null terminating
-// This means we need 2 extra bytes for serialization,
-// and we still need a paging table of some sort to actually
-// make this dynamic sizing useful...
+// Longest value accepted for either column
+const MAX_VALUE_SIZE: usize = 1 << 20;
+// A row is its id, the length of user_id and email, the first overflow
+// page (0 if none) and then the start of each value. Whatever doesn't fit
+// in the two slots goes to overflow pages, user_id's tail first.
 // To sync with the tutorial, I am going to use 31 and 254
 // as the userid and email size instead of 32 and 255
-const ROW_SIZE: usize = EMAIL_SIZE + USERID_SIZE + 4 + 2;
+const ROW_ID_OFFSET: usize = 0;
+const ROW_USERID_LEN_OFFSET: usize = ROW_ID_OFFSET + 4;
+const ROW_EMAIL_LEN_OFFSET: usize = ROW_USERID_LEN_OFFSET + 4;
+const ROW_OVERFLOW_OFFSET: usize = ROW_EMAIL_LEN_OFFSET + 4;
+const ROW_USERID_OFFSET: usize = ROW_OVERFLOW_OFFSET + 4;
+const ROW_EMAIL_OFFSET: usize = ROW_USERID_OFFSET + USERID_SIZE;
+const ROW_SIZE: usize = ROW_EMAIL_OFFSET + EMAIL_SIZE;
 const PAGE_SIZE: usize = 4096;
-const ROWS_PER_PAGE: usize = PAGE_SIZE / ROW_SIZE;
-const TABLE_MAX_PAGES: usize = 100;
-const TABLE_MAX_ROWS: usize = ROWS_PER_PAGE * TABLE_MAX_PAGES;
+// The end of every page is taken up by its checksum
+const PAGE_CHECKSUM_SIZE: usize = 4;
+const PAGE_USABLE_SIZE: usize = PAGE_SIZE - PAGE_CHECKSUM_SIZE;
+const DEFAULT_CACHE_PAGES: usize = 100;
+// Changes Table::undo can take back, older ones are forgotten
+const UNDO_DEPTH: usize = 100;
+// Pages Table::quick_check reads checksums of, spread over the file
+const QUICK_CHECK_PAGES: usize = 64;
+// Opening a table by this name keeps it in memory instead of a file
+pub const MEMORY_DB: &str = ":memory:";
+const COLUMNS: [&str; 3] = ["id", "user_id", "email"];
+const ANALYZE_COLUMNS: [&str; 5] = ["operator", "rows", "pages_read", "time_us", "bytes"];
 
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ColumnType {
+    Integer,
+    Text,
+}
 
-#[derive(Debug)]
-struct Row {
-    id: u32,
-    user_id: String,
-    email: String,
+// What a column holds, for tools that would rather not hard code it
+#[derive(Debug, PartialEq, Clone)]
+pub struct ColumnInfo {
+    pub name : &'static str,
+    pub column_type : ColumnType,
+    // rows are stored and scanned in its order, and it must be unique
+    pub primary_key : bool,
+    // in bytes, for text
+    pub max_len : Option<usize>,
+}
+
+// What Table::salvage got out of a damaged file
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct SalvageReport {
+    pub rows : usize,
+    // pages that failed their checksum
+    pub bad_pages : usize,
+    // rows on good leaves that didn't decode, a broken overflow chain say
+    pub bad_rows : usize,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct TableInfo {
+    pub name : String,
+    pub columns : Vec<ColumnInfo>,
+}
+
+// Every table has the same columns, in COLUMNS order
+pub fn columns() -> Vec<ColumnInfo> {
+    let text = |name| ColumnInfo {
+        name,
+        column_type : ColumnType::Text,
+        primary_key : false,
+        max_len : Some(MAX_VALUE_SIZE),
+    };
+    vec![
+        ColumnInfo { name : COLUMNS[0], column_type : ColumnType::Integer,
+                     primary_key : true, max_len : None },
+        text(COLUMNS[1]),
+        text(COLUMNS[2]),
+    ]
+}
+
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Row {
+    pub id: u32,
+    pub user_id: String,
+    pub email: String,
 }
 
 impl Row {
-    fn deserialize(data : &[u8]) -> Row {
-        let mut id : u32 = 0;
-        id = id ^ (data[0] as u32);
-        id = id ^ ((data[1] as u32) << 8);
-        id = id ^ ((data[2] as u32) << 16);
-        id = id ^ ((data[3] as u32) << 24);
-        let user_id_len : usize = data[4] as usize;
-        let email_len : usize = data[5] as usize;
-        let user_id = str::from_utf8(&data[6..6+user_id_len]).unwrap();
-        let email = str::from_utf8(&data[6+user_id_len..
-                                         6+user_id_len+email_len]).unwrap();
-        Row { 
-            id, 
-            user_id : user_id.to_string(), 
-            email : email.to_string(), 
-        }
-    }
-
-    fn serialize(&self, data : &mut [u8]) -> () {
-        data[0] = self.id as u8;
-        data[1] = (self.id >> 8) as u8;
-        data[2] = (self.id >> 16) as u8;
-        data[3] = (self.id >> 24) as u8;
-        let user_id_len = self.user_id.len();
-        data[4] = user_id_len as u8;
-        let email_len = self.email.len();
-        data[5] = email_len as u8;
-        data[6..6+user_id_len].copy_from_slice(self.user_id.as_bytes());
-        data[6+user_id_len..6+user_id_len+email_len]
-            .copy_from_slice(self.email.as_bytes());
-    }
-}
-
-struct Pager {
-    file : File,
-    file_length : u64,
-    pages: Vec<Vec<u8>>,
-}
-
-// do I need a drop for Pager so file gets dropped?
-impl Pager {
-    fn open(filename : PathBuf) -> Pager {
-        let file = OpenOptions::new().read(true)
-                                     .write(true)
-                                     .create(true)
-                                     .open(filename)
-                                     .expect("Cannot open persistent file");
-        let meta = file.metadata().expect("Cannot open file metadata");
-        let mut pager = Pager {
-            file,
-            file_length : meta.len(),
-            pages: Vec::with_capacity(TABLE_MAX_PAGES),
-        };
-        for _i in 0..TABLE_MAX_PAGES {
-            // vec![] should be of capacity 0
-            pager.pages.push(vec![]);
-        }
-        pager
-    }
-
-    fn get(&mut self, page_num : usize) -> &mut [u8] {
-        if page_num > TABLE_MAX_PAGES {
-            panic!("Tried to fetch page number out of bounds. {} > {}\n", 
-                   page_num, TABLE_MAX_PAGES);
-        }
-        if self.pages[page_num].len() == 0 {
-            self.pages[page_num] = vec![0; PAGE_SIZE];
-            let mut num_pages : u64 = self.file_length / PAGE_SIZE as u64;
-            if self.file_length % PAGE_SIZE as u64 != 0 {
-                num_pages += 1;
-            }
-            if (page_num as u64) < num_pages {
-                let start_offset = (page_num * PAGE_SIZE) as u64;  
-                self.file.seek(SeekFrom::Start(start_offset))
-                    .expect("Unable to read page from file");
-                // if this is the last page, and not full
-                // then we can only read whatever we have
-                let mut size = PAGE_SIZE;
-                if self.file_length < start_offset + (size as u64) {
-                    size = (self.file_length - start_offset) as usize;
-                }
-                self.file.read_exact(&mut self.pages[page_num][..size])
-                    .expect("Unable to read page from file");
-            }
+    // A row that can't have been written by serialize is corruption
+    fn deserialize(data : &[u8], pager : &mut Pager,
+                   policy : TextPolicy) -> Result<Row, DbError> {
+        let id = node::read_u32(data, ROW_ID_OFFSET);
+        let user_id_len = node::read_u32(data, ROW_USERID_LEN_OFFSET) as usize;
+        let email_len = node::read_u32(data, ROW_EMAIL_LEN_OFFSET) as usize;
+        if user_id_len > MAX_VALUE_SIZE || email_len > MAX_VALUE_SIZE {
+            return Err(DbError::Corrupt(format!("row {}: field too long", id)));
+        }
+        let user_id_inline = user_id_len.min(USERID_SIZE);
+        let email_inline = email_len.min(EMAIL_SIZE);
+        let overflow = overflow::read(pager, Row::overflow_page(data),
+                                      Row::overflow_len(data))?;
+        let (user_id_tail, email_tail) = overflow.split_at(user_id_len - user_id_inline);
+        let mut user_id = data[ROW_USERID_OFFSET..ROW_USERID_OFFSET+user_id_inline].to_vec();
+        user_id.extend_from_slice(user_id_tail);
+        let mut email = data[ROW_EMAIL_OFFSET..ROW_EMAIL_OFFSET+email_inline].to_vec();
+        email.extend_from_slice(email_tail);
+        match (policy.decode(user_id), policy.decode(email)) {
+            (Some(user_id), Some(email)) => Ok(Row { id, user_id, email }),
+            _ => Err(DbError::Corrupt(format!("row {}: not valid UTF-8", id))),
         }
-        return &mut self.pages[page_num][..]; 
     }
 
-    fn flush(&mut self, page_num : usize, size : usize) {
-        if self.pages[page_num].len() == 0 {
-            return;
+    // First overflow page of a serialized row
+    fn overflow_page(data : &[u8]) -> usize {
+        node::read_u32(data, ROW_OVERFLOW_OFFSET) as usize
+    }
+
+    // How many bytes of a serialized row are on overflow pages
+    fn overflow_len(data : &[u8]) -> usize {
+        let user_id_len = node::read_u32(data, ROW_USERID_LEN_OFFSET) as usize;
+        let email_len = node::read_u32(data, ROW_EMAIL_LEN_OFFSET) as usize;
+        user_id_len.saturating_sub(USERID_SIZE) + email_len.saturating_sub(EMAIL_SIZE)
+    }
+
+    // Longer values would go in fine but deserialize refuses to read them back
+    fn check_size(&self) -> Result<(), DbError> {
+        if self.user_id.len() > MAX_VALUE_SIZE || self.email.len() > MAX_VALUE_SIZE {
+            return Err(DbError::StatementSyntaxError);
+        }
+        Ok(())
+    }
+
+    // Bytes the row takes in a table, its cell and the part of its
+    // values that goes to overflow pages
+    pub fn encoded_size(&self) -> usize {
+        ROW_SIZE + self.user_id.len().saturating_sub(USERID_SIZE)
+            + self.email.len().saturating_sub(EMAIL_SIZE)
+    }
+
+    // The bytes serialize leaves for the overflow pages
+    fn overflow(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        if self.user_id.len() > USERID_SIZE {
+            bytes.extend_from_slice(&self.user_id.as_bytes()[USERID_SIZE..]);
+        }
+        if self.email.len() > EMAIL_SIZE {
+            bytes.extend_from_slice(&self.email.as_bytes()[EMAIL_SIZE..]);
+        }
+        bytes
+    }
+
+    fn values(&self) -> Vec<Value> {
+        (0..COLUMNS.len()).map(|column| self.value(column)).collect()
+    }
+
+    // column is an index into COLUMNS
+    fn value(&self, column : usize) -> Value {
+        match column {
+            0 => Value::Integer(self.id),
+            1 => Value::Text(self.user_id.clone()),
+            _ => Value::Text(self.email.clone()),
         }
-        self.file.seek(SeekFrom::Start((page_num * PAGE_SIZE) as u64))
-            .expect("Cannot write to file");
-        self.file.write_all(&self.pages[page_num][..size])
-            .expect("Cannot write to file");
     }
 
+    fn serialize(&self, data : &mut [u8], overflow_page : usize) {
+        let user_id = self.user_id.as_bytes();
+        let email = self.email.as_bytes();
+        let user_id_inline = user_id.len().min(USERID_SIZE);
+        let email_inline = email.len().min(EMAIL_SIZE);
+        node::write_u32(data, ROW_ID_OFFSET, self.id);
+        node::write_u32(data, ROW_USERID_LEN_OFFSET, user_id.len() as u32);
+        node::write_u32(data, ROW_EMAIL_LEN_OFFSET, email.len() as u32);
+        node::write_u32(data, ROW_OVERFLOW_OFFSET, overflow_page as u32);
+        data[ROW_USERID_OFFSET..ROW_USERID_OFFSET+user_id_inline]
+            .copy_from_slice(&user_id[..user_id_inline]);
+        data[ROW_EMAIL_OFFSET..ROW_EMAIL_OFFSET+email_inline]
+            .copy_from_slice(&email[..email_inline]);
+    }
 }
 
-pub struct Table {
-    pager : Pager,
-    num_rows : usize,
+// A change to a table, lsn counts up by one per change for the life of the
+// file so a subscriber can tell where it left off
+#[derive(Debug, PartialEq, Clone)]
+pub struct ChangeEvent {
+    pub lsn : u64,
+    pub change : Change,
 }
 
-impl Table {
-    pub fn db_open(filename : PathBuf) -> Table {
-        let pager = Pager::open(filename);
-        // the tutorial is wrong
-        // let num_rows = pager.file_length / ROW_SIZE as u64;
-        let file_length = pager.file_length as usize; //well..
-        let pages = file_length / PAGE_SIZE;
-        let additional = (file_length - (pages * PAGE_SIZE)) / ROW_SIZE;
-        let num_rows = additional + pages * ROWS_PER_PAGE;
-        Table {
-            pager,
-            num_rows : num_rows as usize, 
-        } 
+#[derive(Debug, PartialEq, Clone)]
+pub enum Change {
+    Insert(Row),
+    // the row as it was before it was deleted
+    Delete(Row),
+    // old was swapped for new, which has the same id
+    Replace { old : Row, new : Row },
+}
+
+// When changes are fsynced, each level syncs everywhere the one before it does
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Durability {
+    // Never, the OS writes pages out whenever it likes
+    Off,
+    // On flush and close
+    #[default]
+    OnClose,
+    // After every insert and delete
+    OnCommit,
+    // After every page written
+    Always,
+}
+
+impl Durability {
+    pub fn from_name(name : &str) -> Option<Durability> {
+        match name {
+            "off" => Some(Durability::Off),
+            "on-close" => Some(Durability::OnClose),
+            "on-commit" => Some(Durability::OnCommit),
+            "always" => Some(Durability::Always),
+            _ => None,
+        }
     }
 
-    fn add_row(&mut self, row : &Row) -> Result<(), DbError> {
-        {
-            let mut cursor = Cursor::table_end(self);
-            let row_data = cursor.get_row()?;
-            row.serialize(row_data);
+    pub fn name(self) -> &'static str {
+        match self {
+            Durability::Off => "off",
+            Durability::OnClose => "on-close",
+            Durability::OnCommit => "on-commit",
+            Durability::Always => "always",
         }
-        self.num_rows += 1;
-        Ok(())
     }
+}
 
+// What reading a row does with text that isn't valid UTF-8. Statements
+// can only store valid text, so it got there through corruption or some
+// other program writing to the file.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum TextPolicy {
+    // Fail with DbError::Corrupt
+    #[default]
+    Strict,
+    // Replace every invalid sequence with U+FFFD
+    Lossy,
 }
 
-impl Drop for Table {
-    fn drop(&mut self) {
-        let full_pages = self.num_rows / ROWS_PER_PAGE;
-        for i in 0..full_pages {
-            self.pager.flush(i, PAGE_SIZE);
+impl TextPolicy {
+    pub fn decode(self, bytes : Vec<u8>) -> Option<String> {
+        match self {
+            TextPolicy::Strict => String::from_utf8(bytes).ok(),
+            TextPolicy::Lossy => Some(String::from_utf8(bytes).unwrap_or_else(|err| {
+                String::from_utf8_lossy(err.as_bytes()).into_owned()
+            })),
         }
-        let additional_rows = self.num_rows % ROWS_PER_PAGE;
-        if additional_rows > 0 {
-            self.pager.flush(full_pages, additional_rows * ROW_SIZE);
+    }
+}
+
+// What a table has been through since it was created, kept in the header
+// so it survives a restart. See Table::history.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct History {
+    // deletes don't take anything off
+    pub rows_inserted : u64,
+    pub vacuums : u64,
+    // the last flush that had anything to write, None before the first
+    pub last_checkpoint : Option<SystemTime>,
+}
+
+// Knobs for Table::db_open_with, db_open uses the defaults
+#[derive(Debug, Clone)]
+pub struct TableOptions {
+    // Most pages kept in memory at once
+    pub cache_pages : usize,
+    // Write and fsync changed pages from a background thread this
+    // often, None leaves it to flush, eviction and drop
+    pub flush_interval : Option<Duration>,
+    // Inserts fail with TableFull once the file would grow past this
+    // many pages, None grows it until the disk runs out
+    pub max_pages : Option<usize>,
+    // Writes fail with QuotaExceeded once the file would grow past
+    // this many bytes, None for no quota
+    pub max_file_size : Option<u64>,
+    // Inserts fail with RowTooLarge for a row taking more than this many
+    // bytes, see Row::encoded_size. None leaves just MAX_VALUE_SIZE.
+    pub max_row_size : Option<usize>,
+    // Read clean pages through a memory mapping of the file
+    pub mmap : bool,
+    // Inserts and deletes fail with ReadOnly
+    pub read_only : bool,
+    // Copy pages to <filename>-dwb and sync it before writing them to the
+    // database, so a crash can't leave a page half written
+    pub double_write : bool,
+    pub durability : Durability,
+    // Bypass the OS page cache where the platform allows it, see
+    // FileStorage::open_direct. Turns the mmap mode off.
+    pub direct_io : bool,
+    pub text_policy : TextPolicy,
+    // Pages a new database starts out with on top of the header and root,
+    // see Table::preallocate. Ignored when the file already exists.
+    pub preallocate : usize,
+    // Grow the file by at least this many pages at a time, the ones not
+    // needed yet go on the free list. 0 grows it by the pages an insert
+    // needs, most often one.
+    pub extent_pages : usize,
+    // Run Table::quick_check on an existing file before handing out the
+    // table, opening fails with Corrupt if it finds anything
+    pub verify_on_open : bool,
+}
+
+impl Default for TableOptions {
+    fn default() -> TableOptions {
+        TableOptions {
+            cache_pages : DEFAULT_CACHE_PAGES,
+            flush_interval : None,
+            max_pages : None,
+            max_file_size : None,
+            max_row_size : None,
+            mmap : false,
+            read_only : false,
+            double_write : false,
+            durability : Durability::default(),
+            direct_io : false,
+            text_policy : TextPolicy::default(),
+            preallocate : 0,
+            extent_pages : 0,
+            verify_on_open : false,
         }
     }
 }
 
-pub struct Cursor<'a> {
-    table : &'a mut Table,
-    row_num : usize,
-    end_of_table : bool,
+pub struct Table {
+    pager : Pager,
+    root_page_num : usize,
+    max_pages : Option<usize>,
+    max_file_size : Option<u64>,
+    read_only : bool,
+    // set by close, there's nothing left for drop to do
+    closed : bool,
+    // replication streams, dropped once their receiver is gone
+    subscribers : Vec<mpsc::Sender<ChangeEvent>>,
+    // changes made since the table was opened, the latest last
+    undo_log : Vec<Change>,
+    // the database file and what it was opened with, for vacuum to
+    // reopen it. None when the table isn't in a file.
+    path : Option<PathBuf>,
+    options : TableOptions,
 }
 
-impl<'a> Cursor<'a> {
-    fn table_start(table : &'a mut Table) -> Cursor {
-        let end_of_table = table.num_rows == 0;
-        Cursor {
-            table,
-            row_num : 0,
-            end_of_table, 
-        }
-    }
-    fn table_end(table : &'a mut Table) -> Cursor {
-        let row_num = table.num_rows;
-        Cursor {
-            table,
-            row_num,
-            end_of_table : true,
+impl Table {
+    pub fn db_open(filename : PathBuf) -> Result<Table, DbError> {
+        Table::db_open_with(filename, TableOptions::default())
+    }
+
+    pub fn db_open_with(filename : PathBuf,
+                        options : TableOptions) -> Result<Table, DbError> {
+        if filename.as_os_str() == MEMORY_DB {
+            return Table::db_open_storage(Box::new(MemoryStorage::new()), options);
+        }
+        let storage = if options.direct_io {
+            FileStorage::open_direct(filename.clone(), options.read_only)
+        } else if options.read_only {
+            FileStorage::open_read_only(filename.clone())
+        } else {
+            FileStorage::open(filename.clone())
+        };
+        let storage = storage.map_err(|err| match err.kind() {
+            io::ErrorKind::WouldBlock => DbError::Locked,
+            _ => DbError::IoError(err),
+        })?;
+        if options.read_only || !options.double_write {
+            let mut table = Table::db_open_storage(Box::new(storage), options)?;
+            table.path = Some(filename);
+            return Ok(table);
         }
+        let mut buffer_name = OsString::from(filename.as_os_str());
+        buffer_name.push("-dwb");
+        let buffer = FileStorage::open(PathBuf::from(buffer_name))?;
+        let storage = DoubleWriteStorage::new(Box::new(storage), Box::new(buffer))?;
+        let mut table = Table::db_open_storage(Box::new(storage), options)?;
+        table.path = Some(filename);
+        Ok(table)
     }
-    fn get_row(&mut self) -> Result<&mut [u8], DbError> {
-        let page_num = self.row_num / ROWS_PER_PAGE;
-        if page_num >= TABLE_MAX_PAGES {
-            return Err(DbError::TableFull);
+
+    // Query a database image without touching the filesystem,
+    // e.g. one embedded with include_bytes!
+    pub fn open_from_bytes(bytes : &'static [u8]) -> Result<Table, DbError> {
+        let options = TableOptions { read_only : true, ..TableOptions::default() };
+        Table::db_open_storage(Box::new(ByteStorage::new(bytes)), options)
+    }
+
+    // Same as db_open_with, over anything that can hold pages
+    pub fn db_open_storage(storage : Box<dyn Storage>,
+                           mut options : TableOptions) -> Result<Table, DbError> {
+        let mut pager = Pager::open(storage, options.cache_pages,
+                                    options.flush_interval, options.mmap)?;
+        pager.set_durability(options.durability);
+        let created = pager.num_pages == 0;
+        if pager.num_pages == 0 && options.read_only {
+            // nothing to read, and no way to create it
+            return Err(DbError::NotADatabase);
+        } else if pager.num_pages == 0 {
+            // new database file, write the header and
+            // an empty leaf that is the root of the tree
+            header::initialize_header(pager.get_mut(0)?);
+            let root = pager.get_mut(header::ROOT_PAGE_NUM)?;
+            node::initialize_leaf(root);
+            node::set_root(root, true);
+        } else {
+            // magic first, a file that isn't ours has no checksum either
+            header::validate(&pager.read_page(0)?)?;
+            pager.get(0)?;
+            // checked after the header so any old file is
+            // reported as not being a database at all
+            if !pager.whole_pages {
+                return Err(DbError::Corrupt("file is not a whole number of pages"
+                                            .to_string()));
+            }
+            if header::flags(pager.get(0)?) & header::FLAG_SEALED != 0 {
+                options.read_only = true;
+                pager.enable_mmap()?;
+            }
+        }
+        let preallocate = if created { options.preallocate } else { 0 };
+        let mut table = Table {
+            pager,
+            root_page_num : header::ROOT_PAGE_NUM,
+            max_pages : options.max_pages,
+            max_file_size : options.max_file_size,
+            read_only : options.read_only,
+            closed : false,
+            subscribers : vec![],
+            undo_log : vec![],
+            path : None,
+            options,
+        };
+        if preallocate > 0 {
+            table.preallocate(preallocate)?;
+        }
+        if !created && table.options.verify_on_open {
+            if let Some(problem) = table.quick_check() {
+                return Err(DbError::Corrupt(problem));
+            }
         }
-        let row_offset : usize = self.row_num % ROWS_PER_PAGE;
-        let byte_offset : usize = row_offset * ROW_SIZE;
-        return Ok(&mut self.table.pager.get(page_num)[byte_offset..byte_offset+ROW_SIZE]);
+        Ok(table)
     }
-    fn advance(&mut self) {
-        self.row_num += 1;
-        if self.row_num >= self.table.num_rows {
-            self.end_of_table = true;
+
+    // Grow the file by count pages in one go, ahead of an ingest of known
+    // size. They go on the free list so allocate hands them out in file
+    // order before growing the file any further. Vacuum gives back
+    // whatever is still unused.
+    pub fn preallocate(&mut self, count : usize) -> Result<(), DbError> {
+        self.check_writable()?;
+        // all of them on top of the pages already free
+        let free_pages = self.pager.free_pages()?;
+        self.reserve_pages(count + free_pages)?;
+        let first = self.pager.num_pages;
+        for page_num in (first..first + count).rev() {
+            self.pager.free(page_num)?;
         }
-    } 
-} 
+        Ok(())
+    }
 
-pub fn meta_command(_input : &str) -> Result<(), DbError> {
-    Err(DbError::MetaUnrecognized)
-}
+    // Cursor on the first row in key order
+    pub fn start(&mut self) -> Result<Cursor<'_>, DbError> {
+        let root_page_num = self.root_page_num;
+        let page_num = self.leftmost_leaf(root_page_num)?;
+        let num_cells = node::leaf_num_cells(self.pager.get(page_num)?);
+        Ok(Cursor {
+            table : self,
+            page_num,
+            cell_num : 0,
+            end_of_table : num_cells == 0,
+        })
+    }
 
-pub fn statement_command(input : &str, mut table : &mut Table, 
-                         writer : &mut Write) -> Result<(), DbError> {
-    if input.starts_with("select") {
-        let mut cursor = Cursor::table_start(&mut table);
-        while !cursor.end_of_table {
-            let r = Row::deserialize(&(cursor.get_row()?));
-            writer.write_fmt(format_args!("({}, {}, {})\n", 
-                                          r.id, r.user_id, r.email)).unwrap();
-            cursor.advance();
+    // Cursor just past the last row
+    pub fn end(&mut self) -> Result<Cursor<'_>, DbError> {
+        let mut page_num = self.root_page_num;
+        while node::node_type(self.pager.get(page_num)?) == NodeType::Internal {
+            let node = self.pager.get(page_num)?;
+            page_num = node::internal_child(node, node::internal_num_keys(node));
         }
-        writer.flush().unwrap();
-    } else if input.starts_with("insert") {
-        if table.num_rows >= TABLE_MAX_ROWS {
-            return Err(DbError::TableFull);
+        let num_cells = node::leaf_num_cells(self.pager.get(page_num)?);
+        Ok(Cursor {
+            table : self,
+            page_num,
+            cell_num : num_cells,
+            end_of_table : true,
+        })
+    }
+
+    // Position the cursor at the cell holding key, or at the
+    // cell where key would have to be inserted to keep the leaf sorted
+    fn find(&mut self, key : u32) -> Result<Cursor<'_>, DbError> {
+        let mut page_num = self.root_page_num;
+        while node::node_type(self.pager.get(page_num)?) == NodeType::Internal {
+            let node = self.pager.get(page_num)?;
+            page_num = node::internal_child(node, node::internal_find_child(node, key));
         }
-        let params : Vec<&str> = input.split_whitespace().collect();
-        if params.len() != 4 {
-            return Err(DbError::StatementSyntaxError);
+        let node = self.pager.get(page_num)?;
+        let num_cells = node::leaf_num_cells(node);
+        let cell_num = node::leaf_find(node, key);
+        Ok(Cursor {
+            table : self,
+            page_num,
+            cell_num,
+            end_of_table : cell_num == num_cells,
+        })
+    }
+
+    // Write every dirty page and fsync, so everything up to
+    // here survives a crash without closing the table.
+    // Durability::Off skips the fsync.
+    pub fn flush(&mut self) -> Result<(), DbError> {
+        self.write_dirty_pages()?;
+        if self.durability() != Durability::Off {
+            self.pager.sync()?;
         }
-        let id = params[1].parse::<u32>()?;
-        if params[2].len() > USERID_SIZE || params[3].len() > EMAIL_SIZE {
-            return Err(DbError::StatementSyntaxError);
+        Ok(())
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.pager.stats()
+    }
+
+    pub fn history(&mut self) -> Result<History, DbError> {
+        let page = self.pager.get(0)?;
+        let last_checkpoint = match header::checkpoint_time(page) {
+            0 => None,
+            secs => Some(UNIX_EPOCH + Duration::from_secs(secs)),
+        };
+        Ok(History {
+            rows_inserted : header::rows_inserted(page),
+            vacuums : header::vacuum_count(page),
+            last_checkpoint,
+        })
+    }
+
+    // Kept in the header, there's no need to count the leaves
+    pub fn num_rows(&mut self) -> Result<u64, DbError> {
+        Ok(header::row_count(self.pager.get(0)?))
+    }
+
+    pub fn columns(&self) -> Vec<ColumnInfo> {
+        columns()
+    }
+
+    pub fn durability(&self) -> Durability {
+        self.pager.durability()
+    }
+
+    pub fn set_durability(&mut self, durability : Durability) {
+        self.pager.set_durability(durability);
+    }
+
+    // Called once an insert or delete is done, flushes
+    // it if the durability level asks for that
+    fn commit(&mut self) -> Result<(), DbError> {
+        match self.durability() {
+            Durability::OnCommit | Durability::Always => self.flush(),
+            Durability::Off | Durability::OnClose => {
+                self.pager.hand_off();
+                Ok(())
+            },
         }
-        let row = Row {
-            id,
-            user_id : String::from(params[2]),
-            email : String::from(params[3]),
+    }
+
+    // Write everything out and fsync it. Unlike dropping the
+    // table this reports what went wrong, and never panics.
+    pub fn close(mut self) -> Result<(), DbError> {
+        self.closed = true;
+        self.flush()
+    }
+
+    fn write_dirty_pages(&mut self) -> Result<(), DbError> {
+        if !self.read_only && self.pager.is_dirty() {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+            header::set_checkpoint_time(self.pager.get_mut(0)?, now);
+        }
+        self.pager.flush_all()?;
+        Ok(())
+    }
+
+    fn add_row(&mut self, row : &Row) -> Result<(), DbError> {
+        self.put_row(row)?;
+        self.publish(Change::Insert(row.clone()))?;
+        self.commit()?;
+        // the row is in, but a page evicted on the way may not have made it
+        self.pager.take_error()?;
+        Ok(())
+    }
+
+    // add_row without telling anyone, for changes made up of several steps
+    fn put_row(&mut self, row : &Row) -> Result<(), DbError> {
+        self.check_writable()?;
+        self.check_row_size(row)?;
+        let mut cursor = self.find(row.id)?;
+        if cursor.value()?.is_some_and(|r| r.id == row.id) {
+            return Err(DbError::DuplicateKey);
+        }
+        cursor.leaf_insert(row.id, row)?;
+        let rows = header::row_count(self.pager.get(0)?);
+        let inserted = header::rows_inserted(self.pager.get(0)?);
+        let page = self.pager.get_mut(0)?;
+        header::set_row_count(page, rows + 1);
+        header::set_rows_inserted(page, inserted + 1);
+        Ok(())
+    }
+
+    // Swap the row with new's id for new as a single change, the old row
+    // if there was one. A new row that doesn't fit leaves the old one in place.
+    fn replace_row(&mut self, new : &Row) -> Result<Option<Row>, DbError> {
+        self.check_writable()?;
+        self.check_row_size(new)?;
+        let old = match self.take_row(new.id)? {
+            Some(old) => old,
+            None => {
+                self.add_row(new)?;
+                return Ok(None);
+            },
         };
-        table.add_row(&row)?;
-    } else {
-        return Err(DbError::StatementUnrecognized);
+        if let Err(err) = self.put_row(new) {
+            self.put_row(&old)?;
+            return Err(err);
+        }
+        self.publish(Change::Replace { old : old.clone(), new : new.clone() })?;
+        self.commit()?;
+        self.pager.take_error()?;
+        Ok(Some(old))
     }
-    Ok(())
-} 
 
+    // Every change made to the table from now on, in order
+    pub fn replication_stream(&mut self) -> mpsc::Receiver<ChangeEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.push(sender);
+        receiver
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempdir::TempDir;
-    #[test]
-    fn it_works() {
-        let tmp_dir = TempDir::new("simple-db").unwrap();
-        let file_path = tmp_dir.path().join("test1.db");
-        let mut table = Table::db_open(file_path);
-        let mut buf : Vec<u8> = vec![];
-        statement_command("insert 1 user1 person1@example.com", 
-                          &mut table, &mut buf).unwrap();
-        statement_command("select", &mut table, &mut buf).unwrap();
-        assert_eq!(String::from_utf8(buf).unwrap(), 
-                   String::from("(1, user1, person1@example.com)\n"));
+    // Stamp a change that just happened with the next lsn and pass it on
+    fn publish(&mut self, change : Change) -> Result<(), DbError> {
+        let lsn = header::lsn(self.pager.get(0)?) + 1;
+        header::set_lsn(self.pager.get_mut(0)?, lsn);
+        if self.undo_log.len() == UNDO_DEPTH {
+            self.undo_log.remove(0);
+        }
+        self.undo_log.push(change.clone());
+        let event = ChangeEvent { lsn, change };
+        self.subscribers.retain(|sender| sender.send(event.clone()).is_ok());
+        Ok(())
     }
 
-    #[test]
-    fn table_max() {
-        let tmp_dir = TempDir::new("simple-db").unwrap();
-        let file_path = tmp_dir.path().join("test1.db");
-        let mut table = Table::db_open(file_path);
-        for i in 0..1400 {
-            let mut buf : Vec<u8> = vec![];
-            let insert_str = format!("insert {} user{} person{}@example.com", 
-                                     i, i, i );
-            statement_command(&insert_str, &mut table, &mut buf).unwrap();
+    // Take back the latest change made since the table was opened, false
+    // if there is none left. Every insert, delete and replace is a change
+    // of its own, undoing one is a change too but can't be undone in turn.
+    pub fn undo(&mut self) -> Result<bool, DbError> {
+        let change = match self.undo_log.pop() {
+            Some(change) => change,
+            None => return Ok(false),
+        };
+        let depth = self.undo_log.len();
+        let result = match change {
+            // the row may be gone already, then there is nothing to take back
+            Change::Insert(ref row) => match self.delete(row.id) {
+                Ok(true) => Ok(()),
+                Ok(false) => Err(DbError::NotFound(row.id)),
+                Err(err) => Err(err),
+            },
+            Change::Delete(ref row) => self.add_row(row),
+            Change::Replace { ref old, .. } => match Engine::get(self, old.id) {
+                Ok(Some(_)) => self.replace_row(old).map(|_| ()),
+                Ok(None) => Err(DbError::NotFound(old.id)),
+                Err(err) => Err(err),
+            },
+        };
+        // the undo publishes a change of its own, even when it fails
+        // afterwards on commit
+        self.undo_log.truncate(depth);
+        match result {
+            Ok(()) => Ok(true),
+            Err(err) => {
+                self.undo_log.push(change);
+                Err(err)
+            },
         }
-        let mut buf : Vec<u8> = vec![];
-        statement_command("select", &mut table, &mut buf).unwrap(); 
-        let mut idx = 0;
-        let whole_str = String::from_utf8(buf).unwrap();
-        let lines = whole_str.lines();
-        for rec in lines {
-            assert_eq!(rec, format!("({}, user{}, person{}@example.com)", 
-                                    idx, idx, idx));
-            idx += 1;
+    }
+
+    fn check_writable(&self) -> Result<(), DbError> {
+        if self.read_only {
+            return Err(DbError::ReadOnly);
         }
-        assert_eq!(idx, 1400);
+        Ok(())
     }
 
-    #[test]
-    #[should_panic(expected = "Table is full")]
-    fn table_full() {
-        let tmp_dir = TempDir::new("simple-db").unwrap();
-        let file_path = tmp_dir.path().join("test1.db");
-        let mut table = Table::db_open(file_path);
-        for _i in 0..1401 {
-            let mut buf : Vec<u8> = vec![];
-            match statement_command("insert 1 user1 person1@example.com", 
-                                    &mut table, &mut buf) {
-                Ok(_) => (),
-                Err(DbError::TableFull) => panic!("Table is full"),
-                _ => panic!("incorrect panic"),
-            }
+    fn check_row_size(&self, row : &Row) -> Result<(), DbError> {
+        row.check_size()?;
+        let size = row.encoded_size();
+        if self.options.max_row_size.is_some_and(|max| size > max) {
+            return Err(DbError::RowTooLarge(size));
         }
+        Ok(())
     }
 
-    #[test]
-    fn long_name() {
-        let tmp_dir = TempDir::new("simple-db").unwrap();
-        let file_path = tmp_dir.path().join("test1.db");
-        let mut table = Table::db_open(file_path);
-        let mut buf : Vec<u8> = vec![];
-        let long_user = "a".repeat(31);
-        let long_email = "a".repeat(254);
-        let long_insert = format!("insert 1 {} {}", long_user, long_email);
-        statement_command(long_insert.as_str(), &mut table, &mut buf).unwrap();
-        statement_command("select", &mut table, &mut buf).unwrap();
-        assert_eq!(String::from_utf8(buf).unwrap(), 
-                   format!("(1, {}, {})\n", long_user, long_email));
+    // Everything add_row checks before it changes anything
+    fn validate_insert(&mut self, row : &Row) -> Result<(), DbError> {
+        self.check_writable()?;
+        self.check_row_size(row)?;
+        let mut cursor = self.find(row.id)?;
+        if cursor.value()?.is_some_and(|r| r.id == row.id) {
+            return Err(DbError::DuplicateKey);
+        }
+        let needed = cursor.pages_needed(row)?;
+        cursor.table.reserve_pages(needed)
     }
 
-    #[test]
-    #[should_panic(expected = "uint parse error")]
-    fn uint_parse() {
-        let tmp_dir = TempDir::new("simple-db").unwrap();
-        let file_path = tmp_dir.path().join("test1.db");
-        let mut table = Table::db_open(file_path);
-        let mut buf : Vec<u8> = vec![];
-        match statement_command("insert -1 x x", &mut table, &mut buf) {
-            Ok(_) => (),
-            Err(DbError::ParsingError(_)) => panic!("uint parse error"),
-            _ => panic!("incorrect panic"),
+    // Everything replace_row checks before it changes anything. The new
+    // row takes the old one's cell, so only its overflow pages can need
+    // room, less the ones the old row's chain gives back.
+    fn validate_replace(&mut self, row : &Row) -> Result<(), DbError> {
+        self.check_writable()?;
+        self.check_row_size(row)?;
+        let old = match self.find(row.id)?.value()? {
+            Some(old) if old.id == row.id => old,
+            _ => return self.validate_insert(row),
+        };
+        let needed = overflow::pages_needed(row.overflow().len());
+        let freed = overflow::pages_needed(old.overflow().len());
+        self.reserve_pages(needed.saturating_sub(freed))
+    }
+
+    // Largest key stored under page_num, internal nodes don't
+    // store a key for their right-most child so walk down to a leaf
+    fn max_key(&mut self, page_num : usize) -> Result<u32, DbError> {
+        let mut page_num = page_num;
+        loop {
+            let node = self.pager.get(page_num)?;
+            match node::node_type(node) {
+                NodeType::Leaf => {
+                    let num_cells = node::leaf_num_cells(node);
+                    return Ok(node::leaf_key(node, num_cells - 1));
+                },
+                NodeType::Internal => {
+                    page_num = node::internal_child(node, node::internal_num_keys(node));
+                },
+            }
         }
     }
 
-    #[test]
-    fn table_max_persist() {
-        let tmp_dir = TempDir::new("simple-db").unwrap();
-        for total_lines in 0..1400 {
-            let path1 = tmp_dir.path().join(format!("test{}.db",total_lines));
-            let path2 = path1.clone();
-            {
-                let mut table = Table::db_open(path1);
-                for i in 0..total_lines {
-                    let mut buf : Vec<u8> = vec![];
-                    let insert_str = format!("insert {} user{} person{}@example.com", 
-                                            i, i, i );
-                    statement_command(&insert_str, &mut table, &mut buf).unwrap();
-                }
+    fn leftmost_leaf(&mut self, page_num : usize) -> Result<usize, DbError> {
+        let mut page_num = page_num;
+        while node::node_type(self.pager.get(page_num)?) == NodeType::Internal {
+            page_num = node::internal_child(self.pager.get(page_num)?, 0);
+        }
+        Ok(page_num)
+    }
+
+    // Inserting into a full leaf can split every full node on the
+    // way up, plus one more page if the root itself has to split
+    fn pages_needed_for_split(&mut self, leaf_page_num : usize) -> Result<usize, DbError> {
+        let mut needed = 1;
+        let mut page_num = leaf_page_num;
+        loop {
+            if node::is_root(self.pager.get(page_num)?) {
+                return Ok(needed + 1);
             }
-            {
-                let mut table = Table::db_open(path2);
-                let mut buf : Vec<u8> = vec![];
-                statement_command("select", &mut table, &mut buf).unwrap(); 
-                let mut idx = 0;
-                let whole_str = String::from_utf8(buf).unwrap();
-                let lines = whole_str.lines();
-                for rec in lines {
-                    assert_eq!(rec, format!("({}, user{}, person{}@example.com)", 
-                                            idx, idx, idx));
-                    idx += 1;
-                }
-                assert_eq!(idx, total_lines);
+            page_num = node::parent(self.pager.get(page_num)?);
+            if node::internal_num_keys(self.pager.get(page_num)?)
+                < node::INTERNAL_NODE_MAX_CELLS {
+                return Ok(needed);
             }
+            needed += 1;
+        }
+    }
+
+    // Fail unless needed more pages fit under max_pages and the quota
+    fn reserve_pages(&mut self, needed : usize) -> Result<(), DbError> {
+        let num_pages = self.pager.num_pages + needed.saturating_sub(self.pager.free_pages()?);
+        if self.max_pages.is_some_and(|max_pages| num_pages > max_pages) {
+            return Err(DbError::TableFull);
+        }
+        if self.max_file_size.is_some_and(|max| (num_pages * PAGE_SIZE) as u64 > max) {
+            return Err(DbError::QuotaExceeded);
+        }
+        Ok(())
+    }
 
+    // Add an extent to the free list when it can't hand out needed pages,
+    // as much of one as max_pages and the quota leave room for
+    fn grow(&mut self, needed : usize) -> Result<(), DbError> {
+        let free_pages = self.pager.free_pages()?;
+        if self.options.extent_pages == 0 || needed <= free_pages {
+            return Ok(());
+        }
+        let num_pages = self.pager.num_pages;
+        let mut count = self.options.extent_pages;
+        if let Some(max_pages) = self.max_pages {
+            count = count.min(max_pages.saturating_sub(num_pages));
         }
+        if let Some(max) = self.max_file_size {
+            count = count.min((max as usize / PAGE_SIZE).saturating_sub(num_pages));
+        }
+        // reserve_pages already made sure these fit
+        self.preallocate(count.max(needed - free_pages))
+    }
 
+    fn leaf_split_and_insert(&mut self, page_num : usize, cell_num : usize,
+                             key : u32, row : &Row,
+                             overflow_page : usize) -> Result<(), DbError> {
+        // build the overfull leaf in a scratch buffer, then
+        // move the upper half of its cells into a new page
+        let mut scratch = self.pager.get(page_num)?.to_vec();
+        scratch.resize(PAGE_SIZE + node::LEAF_NODE_CELL_SIZE, 0);
+        row.serialize(node::leaf_insert_cell(&mut scratch, cell_num, key), overflow_page);
+        let total_cells = node::LEAF_NODE_MAX_CELLS + 1;
+        let left_count = total_cells - total_cells / 2;
+        let split_offset = node::leaf_cell_offset(left_count);
+        let end_offset = node::leaf_cell_offset(total_cells);
+
+        let parent = node::parent(&scratch);
+        let next_page_num = node::leaf_next(&scratch);
+        let new_page_num = self.pager.allocate()?;
+        {
+            let new_node = self.pager.get_mut(new_page_num)?;
+            node::initialize_leaf(new_node);
+            node::set_parent(new_node, parent);
+            node::set_leaf_prev(new_node, page_num);
+            node::set_leaf_next(new_node, next_page_num);
+            node::set_leaf_num_cells(new_node, total_cells - left_count);
+            let start = node::leaf_cell_offset(0);
+            new_node[start..start + end_offset - split_offset]
+                .copy_from_slice(&scratch[split_offset..end_offset]);
+        }
+        {
+            let old_node = self.pager.get_mut(page_num)?;
+            old_node[..split_offset].copy_from_slice(&scratch[..split_offset]);
+            for b in old_node[split_offset..].iter_mut() {
+                *b = 0;
+            }
+            node::set_leaf_num_cells(old_node, left_count);
+            node::set_leaf_next(old_node, new_page_num);
+        }
+        if next_page_num != 0 {
+            node::set_leaf_prev(self.pager.get_mut(next_page_num)?, new_page_num);
+        }
+        self.finish_split(page_num, new_page_num)
+    }
+
+    // page_num has just been split, with its upper half moved into
+    // new_page_num. Hook the new page into the tree.
+    fn finish_split(&mut self, page_num : usize,
+                    new_page_num : usize) -> Result<(), DbError> {
+        if node::is_root(self.pager.get(page_num)?) {
+            self.create_new_root(new_page_num)
+        } else {
+            let parent = node::parent(self.pager.get(page_num)?);
+            self.internal_insert(parent, page_num, new_page_num)
+        }
+    }
+
+    // The root always stays at root_page_num, so its current content
+    // (the left half of the split) moves into a fresh page and the
+    // root becomes an internal node with two children.
+    fn create_new_root(&mut self, right_page_num : usize) -> Result<(), DbError> {
+        let root_page_num = self.root_page_num;
+        let left_page_num = self.pager.allocate()?;
+        let root_copy = self.pager.get(root_page_num)?.to_vec();
+        {
+            let left = self.pager.get_mut(left_page_num)?;
+            left.copy_from_slice(&root_copy);
+            node::set_root(left, false);
+        }
+        match node::node_type(&root_copy) {
+            NodeType::Internal => {
+                for child in node::internal_children(&root_copy) {
+                    node::set_parent(self.pager.get_mut(child)?, left_page_num);
+                }
+            },
+            NodeType::Leaf => {
+                // the root leaf has no left sibling, only the new right one
+                node::set_leaf_prev(self.pager.get_mut(right_page_num)?, left_page_num);
+            },
+        }
+        let left_max = self.max_key(left_page_num)?;
+        node::set_parent(self.pager.get_mut(left_page_num)?, root_page_num);
+        node::set_parent(self.pager.get_mut(right_page_num)?, root_page_num);
+        let root = self.pager.get_mut(root_page_num)?;
+        node::initialize_internal(root);
+        node::set_root(root, true);
+        node::set_internal_cells(root, &[left_page_num, right_page_num],
+                                 &[left_max]);
+        Ok(())
+    }
+
+    // Add right_page_num to parent_page_num as the sibling directly
+    // after left_page_num, splitting the parent if it overflows
+    fn internal_insert(&mut self, parent_page_num : usize,
+                       left_page_num : usize,
+                       right_page_num : usize) -> Result<(), DbError> {
+        let left_max = self.max_key(left_page_num)?;
+        let (mut children, mut keys) = {
+            let parent = self.pager.get(parent_page_num)?;
+            (node::internal_children(parent), node::internal_keys(parent))
+        };
+        let idx = children.iter().position(|&c| c == left_page_num).unwrap();
+        // the old key of left (if any) is still the max of right
+        keys.insert(idx, left_max);
+        children.insert(idx + 1, right_page_num);
+        node::set_parent(self.pager.get_mut(right_page_num)?, parent_page_num);
+        if keys.len() <= node::INTERNAL_NODE_MAX_CELLS {
+            node::set_internal_cells(self.pager.get_mut(parent_page_num)?,
+                                     &children, &keys);
+            return Ok(());
+        }
+        // keys[split] is the max of the left half and becomes
+        // implicit as it no longer has a right sibling there
+        let split = keys.len() / 2;
+        let grandparent = node::parent(self.pager.get(parent_page_num)?);
+        let new_page_num = self.pager.allocate()?;
+        {
+            let new_node = self.pager.get_mut(new_page_num)?;
+            node::initialize_internal(new_node);
+            node::set_parent(new_node, grandparent);
+            node::set_internal_cells(new_node, &children[split + 1..],
+                                     &keys[split + 1..]);
+        }
+        for child in &children[split + 1..] {
+            node::set_parent(self.pager.get_mut(*child)?, new_page_num);
+        }
+        node::set_internal_cells(self.pager.get_mut(parent_page_num)?,
+                                 &children[..split + 1], &keys[..split]);
+        self.finish_split(parent_page_num, new_page_num)
+    }
+
+    // Remove the row with the given id, false if there was none
+    pub fn delete(&mut self, id : u32) -> Result<bool, DbError> {
+        let row = match self.take_row(id)? {
+            Some(row) => row,
+            None => return Ok(false),
+        };
+        self.publish(Change::Delete(row))?;
+        self.commit()?;
+        Ok(true)
+    }
+
+    // delete without telling anyone, the row that was removed
+    fn take_row(&mut self, id : u32) -> Result<Option<Row>, DbError> {
+        self.check_writable()?;
+        let row = match self.remove(id)? {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let rows = header::row_count(self.pager.get(0)?);
+        header::set_row_count(self.pager.get_mut(0)?, rows - 1);
+        Ok(Some(row))
+    }
+
+    // The row that was removed, if there was one
+    fn remove(&mut self, id : u32) -> Result<Option<Row>, DbError> {
+        let (page_num, cell_num, row) = {
+            let mut cursor = self.find(id)?;
+            match cursor.value()? {
+                Some(row) if row.id == id => (cursor.page_num, cursor.cell_num, row),
+                _ => return Ok(None),
+            }
+        };
+        let overflow_page = Row::overflow_page(
+            node::leaf_value(self.pager.get(page_num)?, cell_num));
+        overflow::free(&mut self.pager, overflow_page)?;
+        let num_cells = {
+            let node = self.pager.get_mut(page_num)?;
+            node::leaf_remove_cell(node, cell_num);
+            node::leaf_num_cells(node)
+        };
+        if page_num == self.root_page_num {
+            return Ok(Some(row));
+        }
+        if num_cells > 0 {
+            self.update_max_key(page_num)?;
+        }
+        if num_cells < node::LEAF_NODE_MIN_CELLS {
+            self.rebalance(page_num)?;
+        }
+        Ok(Some(row))
+    }
+
+    fn internal_cells(&mut self,
+                      page_num : usize) -> Result<(Vec<usize>, Vec<u32>), DbError> {
+        let node = self.pager.get(page_num)?;
+        Ok((node::internal_children(node), node::internal_keys(node)))
+    }
+
+    fn num_entries(&mut self, page_num : usize) -> Result<usize, DbError> {
+        let node = self.pager.get(page_num)?;
+        Ok(match node::node_type(node) {
+            NodeType::Leaf => node::leaf_num_cells(node),
+            NodeType::Internal => node::internal_num_keys(node),
+        })
+    }
+
+    // The largest key under page_num changed, fix the separator
+    // key above it. Only the right-most child has no separator in
+    // its parent, in that case the parent's own max changed too.
+    fn update_max_key(&mut self, page_num : usize) -> Result<(), DbError> {
+        let max = self.max_key(page_num)?;
+        let mut child = page_num;
+        while child != self.root_page_num {
+            let parent = node::parent(self.pager.get(child)?);
+            let (children, mut keys) = self.internal_cells(parent)?;
+            let idx = children.iter().position(|&c| c == child).unwrap();
+            if idx < keys.len() {
+                keys[idx] = max;
+                node::set_internal_cells(self.pager.get_mut(parent)?, &children, &keys);
+                return Ok(());
+            }
+            child = parent;
+        }
+        Ok(())
+    }
+
+    // page_num dropped below the minimum fill, take an entry from a
+    // sibling if it can spare one, otherwise merge the two
+    fn rebalance(&mut self, page_num : usize) -> Result<(), DbError> {
+        let parent = node::parent(self.pager.get(page_num)?);
+        let (children, _) = self.internal_cells(parent)?;
+        let idx = children.iter().position(|&c| c == page_num).unwrap();
+        let (left, right) = if idx > 0 {
+            (children[idx - 1], page_num)
+        } else {
+            (page_num, children[idx + 1])
+        };
+        let sibling = if left == page_num { right } else { left };
+        let is_leaf = node::node_type(self.pager.get(page_num)?) == NodeType::Leaf;
+        let min = if is_leaf {
+            node::LEAF_NODE_MIN_CELLS
+        } else {
+            node::INTERNAL_NODE_MIN_CELLS
+        };
+        if self.num_entries(sibling)? > min {
+            if is_leaf {
+                self.leaf_borrow(page_num, sibling, sibling == left)?;
+            } else {
+                self.internal_borrow(page_num, sibling, sibling == left)?;
+            }
+            self.update_max_key(left)?;
+            self.update_max_key(right)
+        } else {
+            self.merge(parent, left, right)
+        }
+    }
+
+    fn leaf_borrow(&mut self, page_num : usize, sibling : usize,
+                   from_left : bool) -> Result<(), DbError> {
+        let cell = {
+            let node = self.pager.get_mut(sibling)?;
+            let num_cells = node::leaf_num_cells(node);
+            let cell_num = if from_left { num_cells - 1 } else { 0 };
+            let cell = node::leaf_cell(node, cell_num).to_vec();
+            node::leaf_remove_cell(node, cell_num);
+            cell
+        };
+        let node = self.pager.get_mut(page_num)?;
+        let cell_num = if from_left { 0 } else { node::leaf_num_cells(node) };
+        node::leaf_insert_raw_cell(node, cell_num, &cell);
+        Ok(())
+    }
+
+    fn internal_borrow(&mut self, page_num : usize, sibling : usize,
+                       from_left : bool) -> Result<(), DbError> {
+        let (mut sibling_children, mut sibling_keys) = self.internal_cells(sibling)?;
+        let (mut children, mut keys) = self.internal_cells(page_num)?;
+        let moved = if from_left {
+            sibling_keys.pop();
+            let moved = sibling_children.pop().unwrap();
+            let moved_max = self.max_key(moved)?;
+            children.insert(0, moved);
+            keys.insert(0, moved_max);
+            moved
+        } else {
+            sibling_keys.remove(0);
+            let moved = sibling_children.remove(0);
+            // the old right-most child now needs an explicit key
+            let last_max = self.max_key(*children.last().unwrap())?;
+            children.push(moved);
+            keys.push(last_max);
+            moved
+        };
+        node::set_internal_cells(self.pager.get_mut(sibling)?, &sibling_children,
+                                 &sibling_keys);
+        node::set_internal_cells(self.pager.get_mut(page_num)?, &children, &keys);
+        node::set_parent(self.pager.get_mut(moved)?, page_num);
+        Ok(())
+    }
+
+    // Move everything in right into its left sibling and drop right
+    // from the parent, which may in turn become underfull
+    fn merge(&mut self, parent : usize, left : usize,
+             right : usize) -> Result<(), DbError> {
+        let right_node = self.pager.get(right)?.to_vec();
+        match node::node_type(&right_node) {
+            NodeType::Leaf => {
+                let next = node::leaf_next(&right_node);
+                {
+                    let node = self.pager.get_mut(left)?;
+                    for i in 0..node::leaf_num_cells(&right_node) {
+                        let cell_num = node::leaf_num_cells(node);
+                        node::leaf_insert_raw_cell(node, cell_num,
+                                                   node::leaf_cell(&right_node, i));
+                    }
+                    node::set_leaf_next(node, next);
+                }
+                if next != 0 {
+                    node::set_leaf_prev(self.pager.get_mut(next)?, left);
+                }
+            },
+            NodeType::Internal => {
+                let (mut children, mut keys) = self.internal_cells(left)?;
+                let last_max = self.max_key(*children.last().unwrap())?;
+                keys.push(last_max);
+                keys.extend(node::internal_keys(&right_node));
+                for child in node::internal_children(&right_node) {
+                    node::set_parent(self.pager.get_mut(child)?, left);
+                    children.push(child);
+                }
+                node::set_internal_cells(self.pager.get_mut(left)?, &children, &keys);
+            },
+        }
+        let (mut children, mut keys) = self.internal_cells(parent)?;
+        let idx = children.iter().position(|&c| c == right).unwrap();
+        children.remove(idx);
+        keys.remove(idx - 1);
+        node::set_internal_cells(self.pager.get_mut(parent)?, &children, &keys);
+        self.free_page(right)?;
+        if self.num_entries(left)? > 0 {
+            self.update_max_key(left)?;
+        }
+
+        if parent == self.root_page_num {
+            if keys.is_empty() {
+                self.collapse_root()?;
+            }
+        } else if keys.len() < node::INTERNAL_NODE_MIN_CELLS {
+            self.rebalance(parent)?;
+        }
+        Ok(())
+    }
+
+    // The root is down to a single child, pull that child up into the
+    // root page so the tree loses a level
+    fn collapse_root(&mut self) -> Result<(), DbError> {
+        let root_page_num = self.root_page_num;
+        let child = node::internal_child(self.pager.get(root_page_num)?, 0);
+        let child_node = self.pager.get(child)?.to_vec();
+        {
+            let root = self.pager.get_mut(root_page_num)?;
+            root.copy_from_slice(&child_node);
+            node::set_root(root, true);
+            node::set_parent(root, 0);
+        }
+        if node::node_type(&child_node) == NodeType::Internal {
+            for grandchild in node::internal_children(&child_node) {
+                node::set_parent(self.pager.get_mut(grandchild)?, root_page_num);
+            }
+        }
+        self.free_page(child)
+    }
+
+    fn free_page(&mut self, page_num : usize) -> Result<(), DbError> {
+        self.pager.free(page_num)
+    }
+
+    fn print_tree(&mut self, writer : &mut dyn Write, page_num : usize,
+                  indent : usize) -> Result<(), DbError> {
+        let node = self.pager.get(page_num)?.to_vec();
+        let pad = "  ".repeat(indent);
+        match node::node_type(&node) {
+            NodeType::Leaf => {
+                let num_cells = node::leaf_num_cells(&node);
+                writeln!(writer, "{}- leaf (page {}, size {})", pad, page_num, num_cells)?;
+                for i in 0..num_cells {
+                    writeln!(writer, "{}  - {}", pad, node::leaf_key(&node, i))?;
+                }
+            },
+            NodeType::Internal => {
+                let keys = node::internal_keys(&node);
+                writeln!(writer, "{}- internal (page {}, size {})", pad, page_num, keys.len())?;
+                for (i, child) in node::internal_children(&node).into_iter().enumerate() {
+                    self.print_tree(writer, child, indent + 1)?;
+                    if i < keys.len() {
+                        writeln!(writer, "{}  - key {}", pad, keys[i])?;
+                    }
+                }
+            },
+        }
+        Ok(())
+    }
+
+    // Copy the database page by page into a new file. The tree is checked
+    // first so a broken table isn't handed on as a good copy.
+    pub fn clone_to(&mut self, filename : PathBuf) -> Result<(), DbError> {
+        if let Some(problem) = self.check_integrity().into_iter().next() {
+            return Err(DbError::Corrupt(problem));
+        }
+        let mut file = OpenOptions::new().write(true)
+                                         .create_new(true)
+                                         .open(&filename)?;
+        // the header is written fresh rather than copied
+        // and reading every other page verifies its checksum
+        let mut page = vec![0; PAGE_SIZE];
+        header::initialize_header(&mut page);
+        {
+            let source = self.pager.get(0)?;
+            header::set_free_list_head(&mut page, header::free_list_head(source));
+            header::set_free_page_count(&mut page, header::free_page_count(source));
+            header::set_lsn(&mut page, header::lsn(source));
+            header::set_row_count(&mut page, header::row_count(source));
+            header::copy_history(source, &mut page);
+        }
+        pager::set_checksum(&mut page);
+        file.write_all(&page)?;
+        for page_num in 1..self.pager.num_pages {
+            page.copy_from_slice(self.pager.get(page_num)?);
+            pager::set_checksum(&mut page);
+            file.write_all(&page)?;
+        }
+        storage::sync_file(&file)?;
+        storage::sync_dir(&filename)?;
+        Ok(())
+    }
+
+    // Rewrite the database into a fresh file with no free pages and every
+    // leaf as full as it gets, then rename it over the old one. A crash
+    // before the rename leaves the old file as it was.
+    pub fn vacuum(&mut self) -> Result<(), DbError> {
+        self.check_writable()?;
+        let path = match self.path {
+            Some(ref path) => path.clone(),
+            None => return Err(DbError::IoError(io::Error::new(
+                io::ErrorKind::Unsupported, "only a database file can be vacuumed"))),
+        };
+        if let Some(problem) = self.check_integrity().into_iter().next() {
+            return Err(DbError::Corrupt(problem));
+        }
+        self.flush()?;
+        let mut tmp_name = OsString::from(path.as_os_str());
+        tmp_name.push(".vacuum");
+        let tmp_path = PathBuf::from(tmp_name);
+        // left over from a vacuum that crashed
+        if tmp_path.exists() {
+            fs::remove_file(&tmp_path)?;
+        }
+        if let Err(err) = self.write_compact(tmp_path.clone(), 0) {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(err);
+        }
+        fs::rename(&tmp_path, &path)?;
+        storage::sync_dir(&path)?;
+        let mut fresh = Table::db_open_with(path, self.options.clone())?;
+        mem::swap(&mut self.pager, &mut fresh.pager);
+        // fresh now holds the old pager, everything in it is written
+        fresh.closed = true;
+        let vacuums = header::vacuum_count(self.pager.get(0)?);
+        header::set_vacuum_count(self.pager.get_mut(0)?, vacuums + 1);
+        self.flush()
+    }
+
+    // Write a copy to ship with an application, as a file or through
+    // open_from_bytes. It is compacted the way vacuum does it and flagged
+    // as sealed, which makes every open of it read-only and memory mapped.
+    // A copy made with clone_to isn't sealed any more.
+    pub fn seal(&mut self, filename : PathBuf) -> Result<(), DbError> {
+        if filename.exists() {
+            return Err(DbError::IoError(io::Error::new(io::ErrorKind::AlreadyExists,
+                                                       "seal won't overwrite a file")));
+        }
+        if let Some(problem) = self.check_integrity().into_iter().next() {
+            return Err(DbError::Corrupt(problem));
+        }
+        if let Err(err) = self.write_compact(filename.clone(), header::FLAG_SEALED) {
+            let _ = fs::remove_file(&filename);
+            return Err(err);
+        }
+        storage::sync_dir(&filename)?;
+        Ok(())
+    }
+
+    // A read-only table with the rows as they are now, which keeps seeing
+    // them while this one goes on changing. Nothing is copied up front,
+    // each page this table changes afterwards is copied the first time,
+    // and the copies go away with the snapshot.
+    pub fn snapshot(&mut self) -> Result<Table, DbError> {
+        let storage = self.pager.snapshot();
+        let options = TableOptions {
+            read_only : true,
+            flush_interval : None,
+            ..self.options.clone()
+        };
+        Table::db_open_storage(Box::new(storage), options)
+    }
+
+    // Copy every row that can still be read out of a damaged database into
+    // a new one at dst, which must not exist yet. The tree isn't trusted
+    // at all, each page is looked at on its own and any that passes its
+    // checksum and looks like a leaf gives up the rows that decode. A row
+    // found twice, left behind by a crash part way through a split, is
+    // only copied once.
+    pub fn salvage(src : PathBuf, dst : PathBuf) -> Result<SalvageReport, DbError> {
+        if dst.exists() {
+            return Err(DbError::IoError(io::Error::new(io::ErrorKind::AlreadyExists,
+                                                       "salvage won't overwrite a file")));
+        }
+        let storage = FileStorage::open_read_only(src)?;
+        let mut pager = Pager::open(Box::new(storage), DEFAULT_CACHE_PAGES, None, false)?;
+        let mut copy = Table::db_open(dst)?;
+        let mut report = SalvageReport::default();
+        for page_num in 1..pager.num_pages {
+            let node = match pager.get(page_num) {
+                Ok(node) if node::is_plausible_leaf(node) => node.to_vec(),
+                Ok(_) => continue,
+                Err(_) => {
+                    report.bad_pages += 1;
+                    continue;
+                },
+            };
+            for cell_num in 0..node::leaf_num_cells(&node) {
+                let row = match Row::deserialize(node::leaf_value(&node, cell_num), &mut pager,
+                                               TextPolicy::Strict) {
+                    Ok(ref row) if row.id != node::leaf_key(&node, cell_num) => {
+                        report.bad_rows += 1;
+                        continue;
+                    },
+                    Ok(row) => row,
+                    Err(_) => {
+                        report.bad_rows += 1;
+                        continue;
+                    },
+                };
+                match copy.add_row(&row) {
+                    Ok(()) => report.rows += 1,
+                    Err(DbError::DuplicateKey) => (),
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        copy.close()?;
+        Ok(report)
+    }
+
+    // Load the rows into a new database at filename bottom up,
+    // the leaves first and then each level of internal nodes
+    fn write_compact(&mut self, filename : PathBuf, flags : u32) -> Result<(), DbError> {
+        let options = TableOptions { cache_pages : self.options.cache_pages,
+                                     ..TableOptions::default() };
+        let mut copy = Table::db_open_storage(Box::new(FileStorage::open(filename)?), options)?;
+        let source = self.pager.get(0)?.to_vec();
+        let page = copy.pager.get_mut(0)?;
+        header::set_lsn(page, header::lsn(&source));
+        header::copy_history(&source, page);
+
+        let root_page_num = self.root_page_num;
+        let mut num_rows = 0;
+        let mut page_num = self.leftmost_leaf(root_page_num)?;
+        while page_num != 0 {
+            let node = self.pager.get(page_num)?;
+            num_rows += node::leaf_num_cells(node);
+            page_num = node::leaf_next(node);
+        }
+        // rows are spread evenly so that no leaf ends up less than half full
+        let num_leaves = num_rows.div_ceil(node::LEAF_NODE_MAX_CELLS).max(1);
+        // page number and max key of every node on the level being built
+        let mut level = vec![];
+        let mut cursor = self.start()?;
+        for i in 0..num_leaves {
+            let page_num = if num_leaves == 1 {
+                copy.root_page_num
+            } else {
+                let page_num = copy.pager.allocate()?;
+                node::initialize_leaf(copy.pager.get_mut(page_num)?);
+                if let Some(&(prev, _)) = level.last() {
+                    node::set_leaf_prev(copy.pager.get_mut(page_num)?, prev);
+                    node::set_leaf_next(copy.pager.get_mut(prev)?, page_num);
+                }
+                page_num
+            };
+            let mut max_key = 0;
+            for cell_num in 0..num_rows * (i + 1) / num_leaves - num_rows * i / num_leaves {
+                let row = cursor.value()?.ok_or_else(|| {
+                    DbError::Corrupt("fewer rows than the leaves hold".to_string())
+                })?;
+                cursor.advance()?;
+                let overflow_page = overflow::write(&mut copy.pager, &row.overflow())?;
+                let node = copy.pager.get_mut(page_num)?;
+                row.serialize(node::leaf_insert_cell(node, cell_num, row.id), overflow_page);
+                max_key = row.id;
+            }
+            level.push((page_num, max_key));
+        }
+        while level.len() > 1 {
+            let num_nodes = level.len().div_ceil(node::INTERNAL_NODE_MAX_CELLS + 1);
+            let mut parents = vec![];
+            for i in 0..num_nodes {
+                let group = &level[level.len() * i / num_nodes..level.len() * (i + 1) / num_nodes];
+                let page_num = if num_nodes == 1 {
+                    copy.root_page_num
+                } else {
+                    copy.pager.allocate()?
+                };
+                let children : Vec<usize> = group.iter().map(|&(child, _)| child).collect();
+                let keys : Vec<u32> = group[..group.len() - 1].iter().map(|&(_, key)| key).collect();
+                let node = copy.pager.get_mut(page_num)?;
+                node::initialize_internal(node);
+                node::set_internal_cells(node, &children, &keys);
+                for &child in &children {
+                    node::set_parent(copy.pager.get_mut(child)?, page_num);
+                }
+                parents.push((page_num, group[group.len() - 1].1));
+            }
+            level = parents;
+        }
+        node::set_root(copy.pager.get_mut(copy.root_page_num)?, true);
+        header::set_row_count(copy.pager.get_mut(0)?, num_rows as u64);
+        header::set_flags(copy.pager.get_mut(0)?, flags);
+        copy.close()
+    }
+
+    // The part of check_integrity that doesn't take reading the whole
+    // tree: the root is flagged as root, the first and last leaf can be
+    // reached from it, and up to QUICK_CHECK_PAGES pages spread over the
+    // file pass their checksum. The first problem found, if any.
+    pub fn quick_check(&mut self) -> Option<String> {
+        let root_page_num = self.root_page_num;
+        match self.pager.get(root_page_num) {
+            Ok(root) if !node::is_root(root) =>
+                return Some(format!("page {}: root is not flagged as root", root_page_num)),
+            Ok(_) => (),
+            Err(err) => return Some(page_problem(root_page_num, err)),
+        }
+        if let Err(err) = self.leftmost_leaf(root_page_num).and_then(|_| self.end().map(|_| ())) {
+            return Some(page_problem(root_page_num, err));
+        }
+        let num_pages = self.pager.num_pages;
+        let step = num_pages.div_ceil(QUICK_CHECK_PAGES).max(1);
+        for page_num in (0..num_pages).step_by(step) {
+            match self.pager.read_page(page_num) {
+                Ok(ref page) if pager::checksum_ok(page) => (),
+                Ok(_) => return Some(format!("page {}: checksum mismatch", page_num)),
+                Err(err) => return Some(page_problem(page_num, err.into())),
+            }
+        }
+        None
+    }
+
+    // Walk the whole tree and describe every broken invariant found,
+    // an empty list means the tree is healthy
+    pub fn check_integrity(&mut self) -> Vec<String> {
+        let mut check = IntegrityCheck {
+            problems : vec![],
+            seen : vec![false; self.pager.num_pages],
+            leaves : vec![],
+            leaf_depth : None,
+        };
+        let root_page_num = self.root_page_num;
+        match self.pager.get(root_page_num) {
+            Ok(root) if !node::is_root(root) =>
+                check.problems.push(format!("page {}: root is not flagged as root",
+                                            root_page_num)),
+            Ok(_) => (),
+            Err(err) => {
+                check.problems.push(page_problem(root_page_num, err));
+                return check.problems;
+            },
+        }
+        self.check_node(&mut check, root_page_num, 0, None, None, 0);
+        for (i, &page_num) in check.leaves.iter().enumerate() {
+            let expected_prev = if i == 0 { 0 } else { check.leaves[i - 1] };
+            let expected_next = *check.leaves.get(i + 1).unwrap_or(&0);
+            // every leaf has been read once already by check_node
+            let node = self.pager.get(page_num).unwrap();
+            if node::leaf_prev(node) != expected_prev {
+                check.problems.push(format!("page {}: prev leaf is {}, expected {}",
+                                            page_num, node::leaf_prev(node),
+                                            expected_prev));
+            }
+            if node::leaf_next(node) != expected_next {
+                check.problems.push(format!("page {}: next leaf is {}, expected {}",
+                                            page_num, node::leaf_next(node),
+                                            expected_next));
+            }
+        }
+        self.check_free_list(&mut check);
+        self.check_row_count(&mut check);
+        for (page_num, &seen) in check.seen.iter().enumerate().skip(1) {
+            if !seen {
+                check.problems.push(format!("page {}: neither in the tree nor free",
+                                            page_num));
+            }
+        }
+        check.problems
+    }
+
+    fn check_row_count(&mut self, check : &mut IntegrityCheck) {
+        let mut rows = 0;
+        for &page_num in &check.leaves {
+            rows += node::leaf_num_cells(self.pager.get(page_num).unwrap()) as u64;
+        }
+        match self.pager.get(0) {
+            Ok(page) if header::row_count(page) != rows =>
+                check.problems.push(format!("page 0: row count is {}, the leaves hold {}",
+                                            header::row_count(page), rows)),
+            Ok(_) => (),
+            Err(err) => check.problems.push(page_problem(0, err)),
+        }
+    }
+
+    fn check_free_list(&mut self, check : &mut IntegrityCheck) {
+        let (mut page_num, count) = match self.pager.get(0) {
+            Ok(page) => (header::free_list_head(page), header::free_page_count(page)),
+            Err(err) => {
+                check.problems.push(page_problem(0, err));
+                return;
+            },
+        };
+        let mut found = 0;
+        while page_num != 0 {
+            if page_num >= check.seen.len() {
+                check.problems.push(format!("page {}: free page beyond the end of the file",
+                                            page_num));
+                return;
+            }
+            // also stops a free list that loops back on itself
+            if check.seen[page_num] {
+                check.problems.push(format!("page {}: on the free list but already used",
+                                            page_num));
+                return;
+            }
+            check.seen[page_num] = true;
+            found += 1;
+            page_num = match self.pager.next_free(page_num) {
+                Ok(next) => next,
+                Err(err) => {
+                    check.problems.push(page_problem(page_num, err));
+                    return;
+                },
+            };
+        }
+        if found != count {
+            check.problems.push(format!("page 0: {} pages on the free list, header says {}",
+                                        found, count));
+        }
+    }
+
+    // A row's overflow chain has the right length and no shared pages
+    fn check_overflow(&mut self, check : &mut IntegrityCheck, key : u32, cell : &[u8]) {
+        let pages = match overflow::pages(&mut self.pager, Row::overflow_page(cell)) {
+            Ok(pages) => pages,
+            Err(err) => {
+                check.problems.push(format!("row {}: {}", key, err));
+                return;
+            },
+        };
+        if pages.len() != overflow::pages_needed(Row::overflow_len(cell)) {
+            check.problems.push(format!("row {}: {} overflow pages, expected {}", key,
+                                        pages.len(),
+                                        overflow::pages_needed(Row::overflow_len(cell))));
+        }
+        for page_num in pages {
+            if check.seen[page_num] {
+                check.problems.push(format!("page {}: referenced more than once", page_num));
+            }
+            check.seen[page_num] = true;
+        }
+    }
+
+    // Every key under page_num must lie within (lower, upper].
+    // Returns the largest key in the subtree.
+    fn check_node(&mut self, check : &mut IntegrityCheck, page_num : usize,
+                  parent : usize, lower : Option<u32>, upper : Option<u32>,
+                  depth : usize) -> Option<u32> {
+        if page_num >= check.seen.len() {
+            check.problems.push(format!("page {}: beyond the end of the file", page_num));
+            return None;
+        }
+        if check.seen[page_num] {
+            check.problems.push(format!("page {}: referenced more than once", page_num));
+            return None;
+        }
+        check.seen[page_num] = true;
+        let node = match self.pager.get(page_num) {
+            Ok(node) => node.to_vec(),
+            Err(err) => {
+                check.problems.push(page_problem(page_num, err));
+                return None;
+            },
+        };
+        let is_root = page_num == self.root_page_num;
+        if !is_root {
+            if node::is_root(&node) {
+                check.problems.push(format!("page {}: flagged as root", page_num));
+            }
+            if node::parent(&node) != parent {
+                check.problems.push(format!("page {}: parent is {}, expected {}",
+                                            page_num, node::parent(&node), parent));
+            }
+        }
+        let keys : Vec<u32> = match node::node_type(&node) {
+            NodeType::Leaf => (0..node::leaf_num_cells(&node))
+                .map(|i| node::leaf_key(&node, i)).collect(),
+            NodeType::Internal => node::internal_keys(&node),
+        };
+        if keys.windows(2).any(|w| w[0] >= w[1]) {
+            check.problems.push(format!("page {}: keys out of order", page_num));
+        }
+        if keys.iter().any(|&k| lower.is_some_and(|l| k <= l) ||
+                                upper.is_some_and(|u| k > u)) {
+            check.problems.push(format!("page {}: keys outside of the range given by the parent",
+                                        page_num));
+        }
+        match node::node_type(&node) {
+            NodeType::Leaf => {
+                if !is_root && keys.len() < node::LEAF_NODE_MIN_CELLS {
+                    check.problems.push(format!("page {}: leaf underfull with {} cells",
+                                                page_num, keys.len()));
+                }
+                match check.leaf_depth {
+                    None => check.leaf_depth = Some(depth),
+                    Some(d) if d != depth =>
+                        check.problems.push(format!("page {}: leaf at depth {}, expected {}",
+                                                    page_num, depth, d)),
+                    _ => (),
+                }
+                for (i, &key) in keys.iter().enumerate() {
+                    self.check_overflow(check, key, node::leaf_value(&node, i));
+                }
+                check.leaves.push(page_num);
+                keys.last().cloned()
+            },
+            NodeType::Internal => {
+                if keys.is_empty() ||
+                   (!is_root && keys.len() < node::INTERNAL_NODE_MIN_CELLS) {
+                    check.problems.push(format!("page {}: internal node underfull with {} keys",
+                                                page_num, keys.len()));
+                }
+                let children = node::internal_children(&node);
+                let mut max = None;
+                for (i, &child) in children.iter().enumerate() {
+                    let child_lower = if i == 0 { lower } else { Some(keys[i - 1]) };
+                    let child_upper = if i < keys.len() { Some(keys[i]) } else { upper };
+                    max = self.check_node(check, child, page_num,
+                                          child_lower, child_upper, depth + 1);
+                    if i < keys.len() && max != Some(keys[i]) {
+                        check.problems.push(format!("page {}: key {} is not the max of child {}",
+                                                    page_num, keys[i], child));
+                    }
+                }
+                max
+            },
+        }
+    }
+
+}
+
+// Corrupt errors already name the page
+fn page_problem(page_num : usize, err : DbError) -> String {
+    match err {
+        DbError::Corrupt(problem) => problem,
+        err => format!("page {}: {}", page_num, err),
+    }
+}
+
+struct IntegrityCheck {
+    problems : Vec<String>,
+    seen : Vec<bool>,
+    leaves : Vec<usize>,
+    leaf_depth : Option<usize>,
+}
+
+impl Drop for Table {
+    // Best effort, drop may be running while a panic unwinds.
+    // Call close to find out whether everything was written.
+    fn drop(&mut self) {
+        if self.closed {
+            return;
+        }
+        if let Err(err) = self.write_dirty_pages() {
+            eprintln!("simple-db: could not write dirty pages: {}", err);
+        }
+    }
+}
+
+pub struct Cursor<'a> {
+    table : &'a mut Table,
+    page_num : usize,
+    cell_num : usize,
+    end_of_table : bool,
+}
+
+impl<'a> Cursor<'a> {
+    pub fn end_of_table(&self) -> bool {
+        self.end_of_table
+    }
+    // The row under the cursor, None once the cursor went past the last row
+    pub fn value(&mut self) -> Result<Option<Row>, DbError> {
+        if self.end_of_table {
+            return Ok(None);
+        }
+        let node = self.table.pager.get(self.page_num)?;
+        let cell = node::leaf_value(node, self.cell_num).to_vec();
+        let policy = self.table.options.text_policy;
+        Ok(Some(Row::deserialize(&cell, &mut self.table.pager, policy)?))
+    }
+    pub fn advance(&mut self) -> Result<(), DbError> {
+        if self.end_of_table {
+            return Ok(());
+        }
+        self.cell_num += 1;
+        let node = self.table.pager.get(self.page_num)?;
+        if self.cell_num >= node::leaf_num_cells(node) {
+            match node::leaf_next(node) {
+                0 => self.end_of_table = true,
+                next_page_num => {
+                    self.page_num = next_page_num;
+                    self.cell_num = 0;
+                },
+            }
+        }
+        Ok(())
+    } 
+    // Step back one row, a cursor from table.end() moves onto the last
+    // row. Stepping back from the first row ends the scan.
+    pub fn retreat(&mut self) -> Result<(), DbError> {
+        if self.cell_num > 0 {
+            self.cell_num -= 1;
+            self.end_of_table = false;
+            return Ok(());
+        }
+        match node::leaf_prev(self.table.pager.get(self.page_num)?) {
+            0 => self.end_of_table = true,
+            prev_page_num => {
+                let num_cells = node::leaf_num_cells(self.table.pager.get(prev_page_num)?);
+                self.page_num = prev_page_num;
+                self.cell_num = num_cells - 1;
+                self.end_of_table = false;
+            },
+        }
+        Ok(())
+    }
+    // New pages inserting row here takes, for its overflow and any splits
+    fn pages_needed(&mut self, row : &Row) -> Result<usize, DbError> {
+        let mut needed = overflow::pages_needed(row.overflow().len());
+        if node::leaf_num_cells(self.table.pager.get(self.page_num)?)
+            >= node::LEAF_NODE_MAX_CELLS {
+            needed += self.table.pages_needed_for_split(self.page_num)?;
+        }
+        Ok(needed)
+    }
+    fn leaf_insert(&mut self, key : u32, row : &Row) -> Result<(), DbError> {
+        let needed = self.pages_needed(row)?;
+        self.table.reserve_pages(needed)?;
+        self.table.grow(needed)?;
+        let overflow = row.overflow();
+        let full = node::leaf_num_cells(self.table.pager.get(self.page_num)?)
+            >= node::LEAF_NODE_MAX_CELLS;
+        let overflow_page = overflow::write(&mut self.table.pager, &overflow)?;
+        if full {
+            return self.table.leaf_split_and_insert(self.page_num, self.cell_num,
+                                                    key, row, overflow_page);
+        }
+        let node = self.table.pager.get_mut(self.page_num)?;
+        row.serialize(node::leaf_insert_cell(node, self.cell_num, key), overflow_page);
+        Ok(())
+    }
+} 
+
+pub fn meta_command(input : &str, table : &mut Table,
+                    writer : &mut dyn Write) -> Result<(), DbError> {
+    if input == ".btree" {
+        writeln!(writer, "Tree:")?;
+        let root_page_num = table.root_page_num;
+        table.print_tree(writer, root_page_num, 0)?;
+        writer.flush()?;
+        Ok(())
+    } else if input == ".flush" {
+        table.flush()
+    } else if input == ".undo" {
+        if !table.undo()? {
+            writeln!(writer, "Nothing to undo")?;
+            writer.flush()?;
+        }
+        Ok(())
+    } else if input == ".vacuum" {
+        table.vacuum()
+    } else if input == ".stats" {
+        let stats = table.cache_stats();
+        writeln!(writer, "Cache stats:")?;
+        writeln!(writer, "hits: {}", stats.hits)?;
+        writeln!(writer, "misses: {}", stats.misses)?;
+        writeln!(writer, "pages read: {}", stats.pages_read)?;
+        writeln!(writer, "pages written: {}", stats.pages_written)?;
+        writeln!(writer, "evictions: {}", stats.evictions)?;
+        writer.flush()?;
+        Ok(())
+    } else if input == ".durability" {
+        writeln!(writer, "durability: {}", table.durability().name())?;
+        writer.flush()?;
+        Ok(())
+    } else if let Some(name) = input.strip_prefix(".durability ") {
+        let durability = Durability::from_name(name.trim())
+            .ok_or(DbError::MetaUnrecognized)?;
+        table.set_durability(durability);
+        Ok(())
+    } else if input == ".dbinfo" {
+        writeln!(writer, "Database info:")?;
+        writeln!(writer, "page size: {}", PAGE_SIZE)?;
+        writeln!(writer, "pages: {}", table.pager.num_pages)?;
+        writeln!(writer, "free pages: {}", table.pager.free_pages()?)?;
+        writeln!(writer, "rows: {}", table.num_rows()?)?;
+        writeln!(writer, "size: {} bytes", table.pager.num_pages * PAGE_SIZE)?;
+        let history = table.history()?;
+        writeln!(writer, "rows inserted: {}", history.rows_inserted)?;
+        writeln!(writer, "vacuums: {}", history.vacuums)?;
+        match history.last_checkpoint.and_then(|t| t.duration_since(UNIX_EPOCH).ok()) {
+            Some(since) => writeln!(writer, "last checkpoint: {} (unix time)", since.as_secs())?,
+            None => writeln!(writer, "last checkpoint: never")?,
+        }
+        match table.max_file_size {
+            Some(max) => writeln!(writer, "quota: {} bytes", max)?,
+            None => writeln!(writer, "quota: none")?,
+        }
+        match table.options.max_row_size {
+            Some(max) => writeln!(writer, "max row size: {} bytes", max)?,
+            None => writeln!(writer, "max row size: none")?,
+        }
+        writer.flush()?;
+        Ok(())
+    } else if input == ".constants" {
+        writeln!(writer, "Constants:")?;
+        writeln!(writer, "ROW_SIZE: {}", ROW_SIZE)?;
+        writeln!(writer, "PAGE_SIZE: {}", PAGE_SIZE)?;
+        writeln!(writer, "COMMON_NODE_HEADER_SIZE: {}", node::COMMON_NODE_HEADER_SIZE)?;
+        writeln!(writer, "LEAF_NODE_HEADER_SIZE: {}", node::LEAF_NODE_HEADER_SIZE)?;
+        writeln!(writer, "LEAF_NODE_CELL_SIZE: {}", node::LEAF_NODE_CELL_SIZE)?;
+        writeln!(writer, "LEAF_NODE_SPACE_FOR_CELLS: {}", node::LEAF_NODE_SPACE_FOR_CELLS)?;
+        writeln!(writer, "LEAF_NODE_MAX_CELLS: {}", node::LEAF_NODE_MAX_CELLS)?;
+        writeln!(writer, "INTERNAL_NODE_HEADER_SIZE: {}", node::INTERNAL_NODE_HEADER_SIZE)?;
+        writeln!(writer, "INTERNAL_NODE_CELL_SIZE: {}", node::INTERNAL_NODE_CELL_SIZE)?;
+        writeln!(writer, "INTERNAL_NODE_MAX_CELLS: {}", node::INTERNAL_NODE_MAX_CELLS)?;
+        writer.flush()?;
+        Ok(())
+    } else {
+        Err(DbError::MetaUnrecognized)
+    }
+}
+
+// order by <column> [asc|desc], column is an index into COLUMNS
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct OrderBy {
+    pub column : usize,
+    pub descending : bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Statement {
+    Insert(Row),
+    // insert or replace <id> <user_id> <email>, overwrites a row with that id
+    InsertOrReplace(Row),
+    Select,
+    // select where id = <id>
+    SelectId(u32),
+    // select where id between <from> and <to>, or compared with < > <= >=.
+    // Both ends are included, from is past to when nothing can match.
+    SelectRange(u32, u32),
+    // one of the selects with its rows sorted, which means holding all of
+    // them in memory instead of handing each on as it is read
+    Sorted(Box<Statement>, OrderBy),
+    // limit <count> [offset <skip>] after any of the selects
+    Limited(Box<Statement>, usize, usize),
+    // select <column>, ... with any of the above, the columns
+    // are indexes into COLUMNS in the order they were asked for
+    Projected(Box<Statement>, Vec<usize>),
+    // select distinct ..., the limit and offset if there are any
+    // count the rows left once the duplicates are gone
+    Distinct(Box<Statement>, Option<(usize, usize)>),
+    Delete(u32),
+    // create table <name> as <select>, only a Catalog can run it
+    CreateTableAs(String, Box<Statement>),
+    // explain validate <statement>, checks it would run without running it
+    Validate(Box<Statement>),
+    // explain analyze <statement>, runs it and reports what that took
+    // instead of its rows
+    Analyze(Box<Statement>),
+}
+
+// Only checks the syntax of the statement, the table is not touched
+// so this is safe to call on anything the user is still typing
+pub fn parse(input : &str) -> Result<Statement, DbError> {
+    if let Some(rest) = input.strip_prefix("explain validate ") {
+        Ok(Statement::Validate(Box::new(parse(rest.trim_start())?)))
+    } else if let Some(rest) = input.strip_prefix("explain analyze ") {
+        Ok(Statement::Analyze(Box::new(parse(rest.trim_start())?)))
+    } else if let Some(rest) = input.strip_prefix("select") {
+        let (rest, limit) = match rest.find("limit") {
+            Some(at) => (&rest[..at], Some(parse_limit(&rest[at + "limit".len()..])?)),
+            None => (rest, None),
+        };
+        let (rest, order) = match rest.find("order by") {
+            Some(at) => (&rest[..at], Some(parse_order_by(&rest[at + "order by".len()..])?)),
+            None => (rest, None),
+        };
+        let (projection, condition) = match rest.find("where") {
+            Some(at) => (&rest[..at], Some(&rest[at + "where".len()..])),
+            None => (rest, None),
+        };
+        let (distinct, projection) = match projection.trim_start().strip_prefix("distinct") {
+            Some(columns) if columns.is_empty() || columns.starts_with(char::is_whitespace) =>
+                (true, columns),
+            _ => (false, projection),
+        };
+        let statement = match condition {
+            Some(condition) => parse_condition(condition)?,
+            None => Statement::Select,
+        };
+        let statement = match order {
+            Some(order) => Statement::Sorted(Box::new(statement), order),
+            None => statement,
+        };
+        let statement = match limit {
+            Some((count, skip)) if !distinct => Statement::Limited(Box::new(statement), count, skip),
+            _ => statement,
+        };
+        let statement = match parse_projection(projection)? {
+            Some(columns) => Statement::Projected(Box::new(statement), columns),
+            None => statement,
+        };
+        Ok(if distinct { Statement::Distinct(Box::new(statement), limit) } else { statement })
+    } else if input.starts_with("insert") {
+        let params : Vec<&str> = input.split_whitespace().collect();
+        if params.len() > 2 && params[1] == "or" && params[2] == "replace" {
+            return Ok(Statement::InsertOrReplace(parse_row(&params[3..])?));
+        }
+        Ok(Statement::Insert(parse_row(&params[1..])?))
+    } else if input.starts_with("delete") {
+        let params : Vec<&str> = input.split_whitespace().collect();
+        if params.len() != 2 {
+            return Err(DbError::StatementSyntaxError);
+        }
+        Ok(Statement::Delete(params[1].parse::<u32>()?))
+    } else if let Some(rest) = input.strip_prefix("create table ") {
+        let (name, rest) = rest.trim_start().split_once(char::is_whitespace)
+            .ok_or(DbError::StatementSyntaxError)?;
+        let select = match rest.trim_start().strip_prefix("as") {
+            Some(select) if select.starts_with(char::is_whitespace) => select.trim_start(),
+            _ => return Err(DbError::StatementSyntaxError),
+        };
+        if !select.starts_with("select") {
+            return Err(DbError::StatementSyntaxError);
+        }
+        Ok(Statement::CreateTableAs(name.to_string(), Box::new(parse(select)?)))
+    } else {
+        Err(DbError::StatementUnrecognized)
+    }
+}
+
+// The column list between select and the rest, None for every column
+fn parse_projection(projection : &str) -> Result<Option<Vec<usize>>, DbError> {
+    let projection = projection.trim();
+    if projection.is_empty() || projection == "*" {
+        return Ok(None);
+    }
+    projection.split(',')
+        .map(|name| COLUMNS.iter().position(|c| *c == name.trim())
+                           .ok_or(DbError::StatementSyntaxError))
+        .collect::<Result<Vec<_>, _>>()
+        .map(Some)
+}
+
+// The part of a select after limit, the count and the offset
+fn parse_limit(limit : &str) -> Result<(usize, usize), DbError> {
+    let words : Vec<&str> = limit.split_whitespace().collect();
+    match words[..] {
+        [count] => Ok((count.parse::<u32>()? as usize, 0)),
+        [count, "offset", skip] =>
+            Ok((count.parse::<u32>()? as usize, skip.parse::<u32>()? as usize)),
+        _ => Err(DbError::StatementSyntaxError),
+    }
+}
+
+// The part of a select after order by
+fn parse_order_by(order : &str) -> Result<OrderBy, DbError> {
+    let words : Vec<&str> = order.split_whitespace().collect();
+    let descending = match words.get(1) {
+        None | Some(&"asc") => false,
+        Some(&"desc") => true,
+        Some(_) => return Err(DbError::StatementSyntaxError),
+    };
+    match words.first().and_then(|name| COLUMNS.iter().position(|c| c == name)) {
+        Some(column) if words.len() <= 2 => Ok(OrderBy { column, descending }),
+        _ => Err(DbError::StatementSyntaxError),
+    }
+}
+
+// <id> <user_id> <email>
+fn parse_row(params : &[&str]) -> Result<Row, DbError> {
+    if params.len() != 3 {
+        return Err(DbError::StatementSyntaxError);
+    }
+    let id = params[0].parse::<u32>()?;
+    let row = Row {
+        id,
+        user_id : String::from(params[1]),
+        email : String::from(params[2]),
+    };
+    row.check_size()?;
+    Ok(row)
+}
+
+// The part of a select after where, only the id can be compared
+fn parse_condition(condition : &str) -> Result<Statement, DbError> {
+    let words : Vec<&str> = condition.split_whitespace().collect();
+    if words.len() > 1 && words[1] == "between" {
+        if words.len() != 5 || words[0] != "id" || words[3] != "and" {
+            return Err(DbError::StatementSyntaxError);
+        }
+        return Ok(Statement::SelectRange(words[2].parse::<u32>()?, words[4].parse::<u32>()?));
+    }
+    let condition : String = words.concat();
+    let condition = condition.strip_prefix("id").ok_or(DbError::StatementSyntaxError)?;
+    // the two character operators first, "<" is a prefix of "<="
+    let (op, value) = ["<=", ">=", "=", "<", ">"].iter()
+        .find_map(|op| condition.strip_prefix(op).map(|value| (*op, value)))
+        .ok_or(DbError::StatementSyntaxError)?;
+    let value = value.parse::<u32>()?;
+    Ok(match op {
+        "=" => Statement::SelectId(value),
+        "<=" => Statement::SelectRange(0, value),
+        ">=" => Statement::SelectRange(value, u32::MAX),
+        "<" if value == 0 => Statement::SelectRange(1, 0),
+        "<" => Statement::SelectRange(0, value - 1),
+        _ if value == u32::MAX => Statement::SelectRange(1, 0),
+        _ => Statement::SelectRange(value + 1, u32::MAX),
+    })
+}
+
+// What statements run against, the B-tree Table or a LogTable
+pub trait Engine {
+    fn insert(&mut self, row : &Row) -> Result<(), DbError>;
+    // false if there was no row with that id
+    fn delete(&mut self, id : u32) -> Result<bool, DbError>;
+    // Every row in id order
+    fn scan(&mut self, f : &mut dyn FnMut(Row) -> Result<(), DbError>)
+            -> Result<(), DbError>;
+    // Fail the way insert and delete would, without changing anything
+    fn validate_insert(&mut self, row : &Row) -> Result<(), DbError>;
+    fn validate_delete(&mut self, id : u32) -> Result<(), DbError>;
+    // The row with that id, engines that can't look it
+    // up by key go through every row instead
+    fn get(&mut self, id : u32) -> Result<Option<Row>, DbError> {
+        let mut found = None;
+        self.scan(&mut |r| {
+            if r.id == id {
+                found = Some(r);
+            }
+            Ok(())
+        })?;
+        Ok(found)
+    }
+    // Insert the row, or overwrite the one with its id. True if
+    // there was one. The default is a delete and an insert.
+    fn insert_or_replace(&mut self, row : &Row) -> Result<bool, DbError> {
+        row.check_size()?;
+        let replaced = self.delete(row.id)?;
+        self.insert(row)?;
+        Ok(replaced)
+    }
+    // Fail the way insert_or_replace would. The default checks the
+    // delete, or the insert when there is no row to delete.
+    fn validate_replace(&mut self, row : &Row) -> Result<(), DbError> {
+        row.check_size()?;
+        match self.get(row.id)? {
+            Some(_) => self.validate_delete(row.id),
+            None => self.validate_insert(row),
+        }
+    }
+    // Rows with an id from from to to, both included, in id order.
+    // Engines that can't seek go through every row instead.
+    fn range(&mut self, from : u32, to : u32,
+             f : &mut dyn FnMut(Row) -> Result<(), DbError>) -> Result<(), DbError> {
+        self.scan(&mut |r| {
+            if r.id >= from && r.id <= to {
+                f(r)?;
+            }
+            Ok(())
+        })
+    }
+    // At most limit of the rows range would hand on, after skipping the
+    // first offset of them. Engines that can stop early don't read the
+    // rows past the last one, the default goes through all of them.
+    fn range_limit(&mut self, from : u32, to : u32, offset : usize, limit : usize,
+                   f : &mut dyn FnMut(Row) -> Result<(), DbError>) -> Result<(), DbError> {
+        let mut seen = 0;
+        self.range(from, to, &mut |r| {
+            seen += 1;
+            if seen > offset && seen - offset <= limit {
+                f(r)?;
+            }
+            Ok(())
+        })
+    }
+    // None for engines without a page cache
+    fn cache_stats(&self) -> Option<CacheStats> {
+        None
+    }
+}
+
+impl Engine for Table {
+    fn insert(&mut self, row : &Row) -> Result<(), DbError> {
+        self.add_row(row)
+    }
+
+    fn delete(&mut self, id : u32) -> Result<bool, DbError> {
+        Table::delete(self, id)
+    }
+
+    fn insert_or_replace(&mut self, row : &Row) -> Result<bool, DbError> {
+        Ok(self.replace_row(row)?.is_some())
+    }
+
+    fn scan(&mut self, f : &mut dyn FnMut(Row) -> Result<(), DbError>)
+            -> Result<(), DbError> {
+        let mut cursor = self.start()?;
+        while let Some(r) = cursor.value()? {
+            f(r)?;
+            cursor.advance()?;
+        }
+        Ok(())
+    }
+
+    fn validate_insert(&mut self, row : &Row) -> Result<(), DbError> {
+        Table::validate_insert(self, row)
+    }
+
+    fn validate_delete(&mut self, _id : u32) -> Result<(), DbError> {
+        self.check_writable()
+    }
+
+    fn validate_replace(&mut self, row : &Row) -> Result<(), DbError> {
+        Table::validate_replace(self, row)
+    }
+
+    fn get(&mut self, id : u32) -> Result<Option<Row>, DbError> {
+        Ok(self.find(id)?.value()?.filter(|r| r.id == id))
+    }
+
+    fn range(&mut self, from : u32, to : u32,
+             f : &mut dyn FnMut(Row) -> Result<(), DbError>) -> Result<(), DbError> {
+        if from > to {
+            return Ok(());
+        }
+        let mut cursor = self.find(from)?;
+        while let Some(r) = cursor.value()? {
+            if r.id > to {
+                break;
+            }
+            f(r)?;
+            cursor.advance()?;
+        }
+        Ok(())
+    }
+
+    fn range_limit(&mut self, from : u32, to : u32, offset : usize, limit : usize,
+                   f : &mut dyn FnMut(Row) -> Result<(), DbError>) -> Result<(), DbError> {
+        if from > to {
+            return Ok(());
+        }
+        let mut cursor = self.find(from)?;
+        let mut seen = 0;
+        while seen < offset + limit {
+            match cursor.value()? {
+                Some(ref r) if r.id > to => break,
+                Some(r) if seen >= offset => f(r)?,
+                Some(_) => (),
+                None => break,
+            }
+            seen += 1;
+            cursor.advance()?;
+        }
+        Ok(())
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        Some(Table::cache_stats(self))
+    }
+}
+
+pub fn execute(statement : &Statement, table : &mut dyn Engine,
+               sink : &mut dyn ResultSink) -> Result<(), DbError> {
+    match *statement {
+        Statement::Select | Statement::SelectId(_) | Statement::SelectRange(..) |
+        Statement::Sorted(..) | Statement::Limited(..) => {
+            sink.begin_rows(&COLUMNS)?;
+            query(statement, table, &mut |r| sink.push_row(&r.values()))?;
+            sink.end()?;
+        },
+        Statement::Projected(ref statement, ref columns) => {
+            let names : Vec<&str> = columns.iter().map(|&c| COLUMNS[c]).collect();
+            sink.begin_rows(&names)?;
+            query(statement, table, &mut |r| {
+                let values : Vec<Value> = columns.iter().map(|&c| r.value(c)).collect();
+                sink.push_row(&values)
+            })?;
+            sink.end()?;
+        },
+        Statement::Distinct(ref statement, limit) => {
+            let (limit, offset) = limit.unwrap_or((usize::MAX, 0));
+            execute(statement, table, &mut DistinctSink {
+                sink,
+                seen : HashSet::new(),
+                offset,
+                limit,
+            })?;
+        },
+        Statement::Insert(ref row) => table.insert(row)?,
+        Statement::InsertOrReplace(ref row) => {
+            table.insert_or_replace(row)?;
+        },
+        Statement::Delete(id) => {
+            if !table.delete(id)? {
+                return Err(DbError::NotFound(id));
+            }
+        },
+        Statement::Validate(ref statement) => validate(statement, table)?,
+        Statement::Analyze(ref statement) => analyze(statement, table, sink)?,
+        // a table on its own has nowhere to put another one
+        Statement::CreateTableAs(..) => return Err(DbError::StatementUnrecognized),
+    }
+    Ok(())
+}
+
+// Passes each row on the first time its values come up, the ones
+// seen so far are kept in memory
+struct DistinctSink<'a> {
+    sink : &'a mut dyn ResultSink,
+    seen : HashSet<Vec<Value>>,
+    // distinct rows still to skip, and to pass on after that
+    offset : usize,
+    limit : usize,
+}
+
+impl<'a> ResultSink for DistinctSink<'a> {
+    fn begin_rows(&mut self, columns : &[&str]) -> Result<(), DbError> {
+        self.sink.begin_rows(columns)
+    }
+
+    fn push_row(&mut self, values : &[Value]) -> Result<(), DbError> {
+        if self.limit == 0 || !self.seen.insert(values.to_vec()) {
+            return Ok(());
+        }
+        if self.offset > 0 {
+            self.offset -= 1;
+            return Ok(());
+        }
+        self.limit -= 1;
+        self.sink.push_row(values)
+    }
+
+    fn end(&mut self) -> Result<(), DbError> {
+        self.sink.end()
+    }
+
+    fn error(&mut self, err : &DbError) -> Result<(), DbError> {
+        self.sink.error(err)
+    }
+}
+
+// The rows a select statement returns, in id order
+pub fn query(statement : &Statement, table : &mut dyn Engine,
+             f : &mut dyn FnMut(Row) -> Result<(), DbError>) -> Result<(), DbError> {
+    match *statement {
+        Statement::Select => table.scan(f),
+        Statement::SelectId(id) => match table.get(id)? {
+            Some(r) => f(r),
+            None => Ok(()),
+        },
+        Statement::SelectRange(from, to) => table.range(from, to, f),
+        Statement::Sorted(ref statement, order) => {
+            let mut rows = vec![];
+            query(statement, table, &mut |r| {
+                rows.push(r);
+                Ok(())
+            })?;
+            // stable, rows with the same value stay in id order
+            rows.sort_by(|a, b| {
+                let ordering = match order.column {
+                    0 => a.id.cmp(&b.id),
+                    1 => a.user_id.cmp(&b.user_id),
+                    _ => a.email.cmp(&b.email),
+                };
+                if order.descending { ordering.reverse() } else { ordering }
+            });
+            rows.into_iter().try_for_each(f)
+        },
+        Statement::Limited(ref statement, limit, offset) => match **statement {
+            Statement::Select => table.range_limit(0, u32::MAX, offset, limit, f),
+            Statement::SelectRange(from, to) => table.range_limit(from, to, offset, limit, f),
+            _ => {
+                let mut seen = 0;
+                query(statement, table, &mut |r| {
+                    seen += 1;
+                    if seen > offset && seen - offset <= limit {
+                        f(r)?;
+                    }
+                    Ok(())
+                })
+            },
+        },
+        _ => Err(DbError::StatementSyntaxError),
+    }
+}
+
+// One row for the statement's one operator, the rows it produced or
+// changed, pages it had to read from the storage and how long it took
+fn analyze(statement : &Statement, table : &mut dyn Engine,
+           sink : &mut dyn ResultSink) -> Result<(), DbError> {
+    let pages_read = |table : &dyn Engine| table.cache_stats().map_or(0, |s| s.pages_read);
+    let before = pages_read(table);
+    let start = Instant::now();
+    // encoded size of the rows read or written, deletes and distinct
+    // selects don't see whole rows
+    let mut bytes = 0;
+    let (operator, rows) = match *statement {
+        Statement::Select => {
+            let mut rows = 0;
+            table.scan(&mut |r| {
+                rows += 1;
+                bytes += r.encoded_size();
+                Ok(())
+            })?;
+            ("scan", rows)
+        },
+        Statement::SelectId(id) => {
+            let row = table.get(id)?;
+            bytes = row.as_ref().map_or(0, Row::encoded_size);
+            ("lookup", row.is_some() as u32)
+        },
+        Statement::SelectRange(from, to) => {
+            let mut rows = 0;
+            table.range(from, to, &mut |r| {
+                rows += 1;
+                bytes += r.encoded_size();
+                Ok(())
+            })?;
+            ("range", rows)
+        },
+        Statement::Sorted(..) => {
+            let mut rows = 0;
+            query(statement, table, &mut |r| {
+                rows += 1;
+                bytes += r.encoded_size();
+                Ok(())
+            })?;
+            ("sort", rows)
+        },
+        Statement::Limited(..) => {
+            let mut rows = 0;
+            query(statement, table, &mut |r| {
+                rows += 1;
+                bytes += r.encoded_size();
+                Ok(())
+            })?;
+            ("limit", rows)
+        },
+        Statement::Projected(ref statement, _) => {
+            let mut rows = 0;
+            query(statement, table, &mut |r| {
+                rows += 1;
+                bytes += r.encoded_size();
+                Ok(())
+            })?;
+            ("project", rows)
+        },
+        Statement::Distinct(..) => {
+            let mut sink = MemorySink::new();
+            execute(statement, table, &mut sink)?;
+            ("distinct", sink.rows.len() as u32)
+        },
+        Statement::Insert(ref row) => {
+            table.insert(row)?;
+            bytes = row.encoded_size();
+            ("insert", 1)
+        },
+        Statement::InsertOrReplace(ref row) => {
+            table.insert_or_replace(row)?;
+            bytes = row.encoded_size();
+            ("insert or replace", 1)
+        },
+        Statement::Delete(id) => {
+            if !table.delete(id)? {
+                return Err(DbError::NotFound(id));
+            }
+            ("delete", 1)
+        },
+        Statement::Validate(ref statement) => {
+            validate(statement, table)?;
+            ("validate", 0)
+        },
+        Statement::Analyze(ref statement) => {
+            analyze(statement, table, &mut MemorySink::new())?;
+            ("analyze", 1)
+        },
+        Statement::CreateTableAs(..) => return Err(DbError::StatementUnrecognized),
+    };
+    let elapsed = start.elapsed().as_micros().min(u32::MAX as u128) as u32;
+    let pages = (pages_read(table) - before).min(u32::MAX as u64) as u32;
+    sink.begin_rows(&ANALYZE_COLUMNS)?;
+    let bytes = bytes.min(u32::MAX as usize) as u32;
+    sink.push_row(&[Value::Text(operator.to_string()), Value::Integer(rows),
+                    Value::Integer(pages), Value::Integer(elapsed), Value::Integer(bytes)])?;
+    sink.end()
+}
+
+// Fail the way execute would, but leave the table alone
+pub fn validate(statement : &Statement, table : &mut dyn Engine) -> Result<(), DbError> {
+    match *statement {
+        Statement::Insert(ref row) => table.validate_insert(row),
+        Statement::InsertOrReplace(ref row) => table.validate_replace(row),
+        Statement::Select | Statement::SelectId(_) | Statement::SelectRange(..) => Ok(()),
+        Statement::Sorted(ref statement, _) | Statement::Limited(ref statement, ..) |
+        Statement::Projected(ref statement, _) | Statement::Distinct(ref statement, _) =>
+            validate(statement, table),
+        Statement::Delete(id) => {
+            table.validate_delete(id)?;
+            match table.get(id)? {
+                Some(_) => Ok(()),
+                None => Err(DbError::NotFound(id)),
+            }
+        },
+        Statement::Validate(ref statement) | Statement::Analyze(ref statement) =>
+            validate(statement, table),
+        Statement::CreateTableAs(..) => Err(DbError::StatementUnrecognized),
+    }
+}
+
+// Write the statements that turn from into to. Rows are matched by id,
+// a row that changed is deleted and inserted again.
+pub fn diff(from : &mut Table, to : &mut Table,
+            writer : &mut dyn Write) -> Result<(), DbError> {
+    let mut old = from.start()?;
+    let mut new = to.start()?;
+    let mut old_row = old.value()?;
+    let mut new_row = new.value()?;
+    loop {
+        let order = match (&old_row, &new_row) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(a), Some(b)) => a.id.cmp(&b.id),
+        };
+        let changed = order == Ordering::Equal && old_row != new_row;
+        if order == Ordering::Less || changed {
+            writeln!(writer, "delete {}", old_row.as_ref().unwrap().id)?;
+        }
+        if order == Ordering::Greater || changed {
+            let b = new_row.as_ref().unwrap();
+            writeln!(writer, "insert {} {} {}", b.id, b.user_id, b.email)?;
+        }
+        if order != Ordering::Greater {
+            old.advance()?;
+            old_row = old.value()?;
+        }
+        if order != Ordering::Less {
+            new.advance()?;
+            new_row = new.value()?;
+        }
+    }
+    Ok(())
+} 
+
+// Parse and execute in one go, failures are reported to the sink
+// as well as returned
+pub fn statement_command(input : &str, table : &mut dyn Engine,
+                         sink : &mut dyn ResultSink) -> Result<(), DbError> {
+    let result = parse(input).and_then(|statement| execute(&statement, table, sink));
+    if let Err(ref err) = result {
+        sink.error(err)?;
+    }
+    result
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use node::LEAF_NODE_MAX_CELLS;
+    use tempdir::TempDir;
+    #[test]
+    fn it_works() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let mut table = Table::db_open(file_path).unwrap();
+        let mut buf : Vec<u8> = vec![];
+        statement_command("insert 1 user1 person1@example.com", 
+                          &mut table, &mut TextSink::new(&mut buf)).unwrap();
+        statement_command("select", &mut table,
+                          &mut TextSink::new(&mut buf)).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), 
+                   String::from("(1, user1, person1@example.com)\n"));
+    }
+
+    #[test]
+    fn table_max() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let mut table = Table::db_open(file_path).unwrap();
+        for i in 0..1400 {
+            let mut buf : Vec<u8> = vec![];
+            let insert_str = format!("insert {} user{} person{}@example.com", 
+                                     i, i, i );
+            statement_command(&insert_str, &mut table,
+                              &mut TextSink::new(&mut buf)).unwrap();
+        }
+        let mut buf : Vec<u8> = vec![];
+        statement_command("select", &mut table,
+                          &mut TextSink::new(&mut buf)).unwrap();
+        let mut idx = 0;
+        let whole_str = String::from_utf8(buf).unwrap();
+        let lines = whole_str.lines();
+        for rec in lines {
+            assert_eq!(rec, format!("({}, user{}, person{}@example.com)", 
+                                    idx, idx, idx));
+            idx += 1;
+        }
+        assert_eq!(idx, 1400);
+    }
+
+    #[test]
+    #[should_panic(expected = "Table is full")]
+    fn table_full() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let options = TableOptions { max_pages : Some(1000), ..TableOptions::default() };
+        let mut table = Table::db_open_with(file_path, options).unwrap();
+        // every page but the root is at most a full leaf
+        for i in 0..1000 * LEAF_NODE_MAX_CELLS {
+            let mut buf : Vec<u8> = vec![];
+            let insert_str = format!("insert {} user{} person{}@example.com",
+                                     i, i, i );
+            match statement_command(&insert_str, &mut table,
+                                    &mut TextSink::new(&mut buf)) {
+                Ok(_) => (),
+                Err(DbError::TableFull) => panic!("Table is full"),
+                _ => panic!("incorrect panic"),
+            }
+        }
+    }
+
+    #[test]
+    fn no_page_limit() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let mut table = Table::db_open(file_path).unwrap();
+        for i in 0..1000 * LEAF_NODE_MAX_CELLS {
+            let insert_str = format!("insert {} user{} person{}@example.com",
+                                     i, i, i );
+            statement_command(&insert_str, &mut table, &mut MemorySink::new())
+                .unwrap();
+        }
+        // more pages than table_full is allowed
+        assert!(table.pager.num_pages > 1000);
+        assert_eq!(table.check_integrity(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn long_name() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let mut table = Table::db_open(file_path).unwrap();
+        let mut buf : Vec<u8> = vec![];
+        let long_user = "a".repeat(31);
+        let long_email = "a".repeat(254);
+        let long_insert = format!("insert 1 {} {}", long_user, long_email);
+        statement_command(long_insert.as_str(), &mut table,
+                          &mut TextSink::new(&mut buf)).unwrap();
+        statement_command("select", &mut table,
+                          &mut TextSink::new(&mut buf)).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), 
+                   format!("(1, {}, {})\n", long_user, long_email));
+    }
+
+    #[test]
+    #[should_panic(expected = "uint parse error")]
+    fn uint_parse() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let mut table = Table::db_open(file_path).unwrap();
+        let mut buf : Vec<u8> = vec![];
+        match statement_command("insert -1 x x", &mut table,
+                                &mut TextSink::new(&mut buf)) {
+            Ok(_) => (),
+            Err(DbError::ParsingError(_)) => panic!("uint parse error"),
+            _ => panic!("incorrect panic"),
+        }
+    }
+
+    #[test]
+    fn parse_only() {
+        assert_eq!(parse("select").unwrap(), Statement::Select);
+        assert_eq!(parse("insert 1 user1 person1@example.com").unwrap(),
+                   Statement::Insert(Row {
+                       id : 1,
+                       user_id : String::from("user1"),
+                       email : String::from("person1@example.com"),
+                   }));
+        match parse("insert 1 user1") {
+            Err(DbError::StatementSyntaxError) => (),
+            _ => panic!("expected syntax error"),
+        }
+        match parse("update 1") {
+            Err(DbError::StatementUnrecognized) => (),
+            _ => panic!("expected unrecognized statement"),
+        }
+    }
+
+    #[test]
+    fn sink_formats() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let mut table = Table::db_open(file_path).unwrap();
+        let mut sink = MemorySink::new();
+        statement_command("insert 1 user1 person1@example.com",
+                          &mut table, &mut sink).unwrap();
+        statement_command("insert 2 user2 \"quoted,\"@example.com",
+                          &mut table, &mut sink).unwrap();
+        statement_command("select", &mut table, &mut sink).unwrap();
+        assert_eq!(sink.columns, vec!["id", "user_id", "email"]);
+        assert_eq!(sink.rows[0], vec![Value::Integer(1),
+                                      Value::Text(String::from("user1")),
+                                      Value::Text(String::from("person1@example.com"))]);
+        assert_eq!(sink.rows.len(), 2);
+        assert!(statement_command("update", &mut table, &mut sink).is_err());
+        assert_eq!(sink.error, Some(String::from("Statement unrecognized")));
+
+        let mut buf : Vec<u8> = vec![];
+        statement_command("select", &mut table,
+                          &mut CsvSink::new(&mut buf)).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(),
+                   "id,user_id,email\r\n\
+                    1,user1,person1@example.com\r\n\
+                    2,user2,\"\"\"quoted,\"\"@example.com\"\r\n");
+
+        let mut buf : Vec<u8> = vec![];
+        statement_command("select", &mut table,
+                          &mut JsonSink::new(&mut buf)).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(),
+                   "[{\"id\":1,\"user_id\":\"user1\",\"email\":\"person1@example.com\"},\
+                    {\"id\":2,\"user_id\":\"user2\",\"email\":\"\\\"quoted,\\\"@example.com\"}]\n");
+
+        let mut buf : Vec<u8> = vec![];
+        statement_command("select", &mut table,
+                          &mut JsonSink::pretty(&mut buf)).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(),
+                   "[\n  {\"id\":1,\"user_id\":\"user1\",\"email\":\"person1@example.com\"},\n  \
+                    {\"id\":2,\"user_id\":\"user2\",\"email\":\"\\\"quoted,\\\"@example.com\"}\n]\n");
+        let mut buf : Vec<u8> = vec![];
+        statement_command("select where id = 3", &mut table,
+                          &mut JsonSink::pretty(&mut buf)).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), "[]\n");
+    }
+
+    #[test]
+    fn cursor_scan() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let mut table = Table::db_open(file_path).unwrap();
+        assert!(table.start().unwrap().end_of_table());
+        assert!(table.start().unwrap().value().unwrap().is_none());
+        for i in 0..100 {
+            let insert_str = format!("insert {} user{} person{}@example.com",
+                                     i, i, i );
+            statement_command(&insert_str, &mut table, &mut MemorySink::new())
+                .unwrap();
+        }
+        let mut cursor = table.start().unwrap();
+        for i in 0..100 {
+            assert!(!cursor.end_of_table());
+            assert_eq!(cursor.value().unwrap().unwrap().id, i);
+            cursor.advance().unwrap();
+        }
+        assert!(cursor.end_of_table());
+        assert!(cursor.value().unwrap().is_none());
+        let mut cursor = table.end().unwrap();
+        assert!(cursor.end_of_table());
+        assert!(cursor.value().unwrap().is_none());
+    }
+
+    #[test]
+    fn find_by_key() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let mut table = Table::db_open(file_path).unwrap();
+        // only even ids, in reverse
+        for i in (0..500).rev() {
+            let insert_str = format!("insert {} user{} person{}@example.com",
+                                     i * 2, i, i );
+            statement_command(&insert_str, &mut table, &mut MemorySink::new())
+                .unwrap();
+        }
+        for i in 0..500 {
+            assert_eq!(table.find(i * 2).unwrap().value().unwrap().unwrap().id, i * 2);
+            // a missing key lands on the next larger one
+            assert_eq!(table.find(i * 2 + 1).unwrap().value().unwrap().map(|r| r.id),
+                       if i < 499 { Some(i * 2 + 2) } else { None });
+        }
+        assert!(table.find(1000).unwrap().value().unwrap().is_none());
+    }
+
+    #[test]
+    fn reverse_scan() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let mut table = Table::db_open(file_path).unwrap();
+        let mut cursor = table.end().unwrap();
+        cursor.retreat().unwrap();
+        assert!(cursor.value().unwrap().is_none());
+        for i in 0..1400 {
+            let id = i * 7919 % 1400;
+            let insert_str = format!("insert {} user{} person{}@example.com",
+                                     id, id, id );
+            statement_command(&insert_str, &mut table, &mut MemorySink::new())
+                .unwrap();
+        }
+        let mut cursor = table.end().unwrap();
+        for i in (0..1400).rev() {
+            cursor.retreat().unwrap();
+            assert_eq!(cursor.value().unwrap().unwrap().id, i);
+        }
+        cursor.retreat().unwrap();
+        assert!(cursor.end_of_table());
+        assert!(cursor.value().unwrap().is_none());
+    }
+
+    #[test]
+    fn integrity() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let mut table = Table::db_open(file_path).unwrap();
+        assert!(table.check_integrity().is_empty());
+        for i in 0..1400 {
+            let id = i * 7919 % 1400;
+            let insert_str = format!("insert {} user{} person{}@example.com",
+                                     id, id, id );
+            statement_command(&insert_str, &mut table, &mut MemorySink::new())
+                .unwrap();
+            if i % 100 == 0 {
+                assert_eq!(table.check_integrity(), Vec::<String>::new());
+            }
+        }
+        assert_eq!(table.check_integrity(), Vec::<String>::new());
+        // swap the first two keys of the first leaf
+        let root_page_num = table.root_page_num;
+        let page_num = table.leftmost_leaf(root_page_num).unwrap();
+        let leaf = table.pager.get_mut(page_num).unwrap();
+        node::set_leaf_key(leaf, 0, 1);
+        node::set_leaf_key(leaf, 1, 0);
+        assert_eq!(table.check_integrity(),
+                   vec![format!("page {}: keys out of order", page_num)]);
+    }
+
+    #[test]
+    fn clone_table() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let clone_path = tmp_dir.path().join("clone.db");
+        let mut table = Table::db_open(file_path).unwrap();
+        for i in 0..500 {
+            let insert_str = format!("insert {} user{} person{}@example.com",
+                                     i, i, i );
+            statement_command(&insert_str, &mut table, &mut MemorySink::new())
+                .unwrap();
+        }
+        // pages still dirty in the cache make it into the copy
+        table.clone_to(clone_path.clone()).unwrap();
+        let mut copy = Table::db_open(clone_path.clone()).unwrap();
+        assert_eq!(copy.check_integrity(), Vec::<String>::new());
+        let mut rows = MemorySink::new();
+        let mut copy_rows = MemorySink::new();
+        statement_command("select", &mut table, &mut rows).unwrap();
+        statement_command("select", &mut copy, &mut copy_rows).unwrap();
+        assert_eq!(rows.rows, copy_rows.rows);
+        // an existing file is never overwritten
+        match table.clone_to(clone_path) {
+            Err(DbError::IoError(_)) => (),
+            _ => panic!("clone overwrote an existing file"),
+        }
+        let root_page_num = table.root_page_num;
+        let page_num = table.leftmost_leaf(root_page_num).unwrap();
+        node::set_leaf_key(table.pager.get_mut(page_num).unwrap(), 0, 1);
+        match table.clone_to(tmp_dir.path().join("broken.db")) {
+            Err(DbError::Corrupt(_)) => (),
+            _ => panic!("cloned a corrupt table"),
+        }
+        assert!(!tmp_dir.path().join("broken.db").exists());
+    }
+
+    #[test]
+    fn duplicate_key() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let mut table = Table::db_open(file_path).unwrap();
+        let mut sink = MemorySink::new();
+        for i in 0..100 {
+            let insert_str = format!("insert {} user{} person{}@example.com",
+                                     i, i, i );
+            statement_command(&insert_str, &mut table, &mut sink).unwrap();
+        }
+        for i in 0..100 {
+            let insert_str = format!("insert {} other{} other{}@example.com",
+                                     i, i, i );
+            match statement_command(&insert_str, &mut table, &mut sink) {
+                Err(DbError::DuplicateKey) => (),
+                _ => panic!("expected duplicate key"),
+            }
+        }
+        statement_command("select", &mut table, &mut sink).unwrap();
+        assert_eq!(sink.rows.len(), 100);
+        assert_eq!(sink.rows[42][1], Value::Text(String::from("user42")));
+        assert!(table.check_integrity().is_empty());
+    }
+
+    #[test]
+    fn delete_rows() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let path2 = file_path.clone();
+        {
+            let mut table = Table::db_open(file_path).unwrap();
+            assert!(!table.delete(1).unwrap());
+            for i in 0..1400 {
+                let id = i * 7919 % 1400;
+                let insert_str = format!("insert {} user{} person{}@example.com",
+                                         id, id, id );
+                statement_command(&insert_str, &mut table, &mut MemorySink::new())
+                    .unwrap();
+            }
+            // drop every odd id, in a scattered order
+            for i in 0..1400 {
+                let id = i * 7919 % 1400;
+                if id % 2 == 1 {
+                    assert!(table.delete(id).unwrap());
+                    assert!(!table.delete(id).unwrap());
+                }
+                if i % 50 == 0 {
+                    assert_eq!(table.check_integrity(), Vec::<String>::new());
+                }
+            }
+            assert_eq!(table.check_integrity(), Vec::<String>::new());
+        }
+        let mut table = Table::db_open(path2).unwrap();
+        let mut sink = MemorySink::new();
+        statement_command("select", &mut table, &mut sink).unwrap();
+        let ids : Vec<Value> = sink.rows.iter().map(|r| r[0].clone()).collect();
+        let expected : Vec<Value> = (0..700).map(|i| Value::Integer(i * 2)).collect();
+        assert_eq!(ids, expected);
+        // then everything else, from both ends
+        for i in 0..350 {
+            assert!(table.delete(i * 2).unwrap());
+            assert!(table.delete(1398 - i * 2).unwrap());
+            assert_eq!(table.check_integrity(), Vec::<String>::new());
+        }
+        assert!(table.start().unwrap().end_of_table());
+        let root = table.pager.get(table.root_page_num).unwrap();
+        assert_eq!(node::node_type(root), NodeType::Leaf);
+        // the tree still works after being emptied
+        statement_command("insert 5 user5 person5@example.com",
+                          &mut table, &mut MemorySink::new()).unwrap();
+        assert_eq!(table.start().unwrap().value().unwrap().unwrap().id, 5);
+    }
+
+    #[test]
+    fn btree_meta() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let mut table = Table::db_open(file_path).unwrap();
+        let mut buf : Vec<u8> = vec![];
+        meta_command(".btree", &mut table, &mut buf).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(),
+                   "Tree:\n- leaf (page 1, size 0)\n");
+        for i in 0..LEAF_NODE_MAX_CELLS + 1 {
+            let insert_str = format!("insert {} user{} person{}@example.com",
+                                     i, i, i );
+            statement_command(&insert_str, &mut table, &mut MemorySink::new())
+                .unwrap();
+        }
+        let mut buf : Vec<u8> = vec![];
+        meta_command(".btree", &mut table, &mut buf).unwrap();
+        let mut expected = String::from("Tree:\n- internal (page 1, size 1)\n");
+        expected.push_str("  - leaf (page 3, size 7)\n");
+        for i in 0..7 {
+            expected.push_str(&format!("    - {}\n", i));
+        }
+        expected.push_str("  - key 6\n");
+        expected.push_str("  - leaf (page 2, size 7)\n");
+        for i in 7..14 {
+            expected.push_str(&format!("    - {}\n", i));
+        }
+        assert_eq!(String::from_utf8(buf).unwrap(), expected);
+        match meta_command(".foo", &mut table, &mut vec![]) {
+            Err(DbError::MetaUnrecognized) => (),
+            _ => panic!("expected unrecognized meta command"),
+        }
+    }
+
+    #[test]
+    fn constants_meta() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let mut table = Table::db_open(file_path).unwrap();
+        let mut buf : Vec<u8> = vec![];
+        meta_command(".constants", &mut table, &mut buf).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(),
+                   "Constants:\n\
+                    ROW_SIZE: 301\n\
+                    PAGE_SIZE: 4096\n\
+                    COMMON_NODE_HEADER_SIZE: 6\n\
+                    LEAF_NODE_HEADER_SIZE: 18\n\
+                    LEAF_NODE_CELL_SIZE: 305\n\
+                    LEAF_NODE_SPACE_FOR_CELLS: 4074\n\
+                    LEAF_NODE_MAX_CELLS: 13\n\
+                    INTERNAL_NODE_HEADER_SIZE: 14\n\
+                    INTERNAL_NODE_CELL_SIZE: 8\n\
+                    INTERNAL_NODE_MAX_CELLS: 3\n");
+    }
+
+    #[test]
+    fn small_cache() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let path2 = file_path.clone();
+        let options = TableOptions { cache_pages : 4, ..TableOptions::default() };
+        {
+            let mut table = Table::db_open_with(file_path, options.clone()).unwrap();
+            for i in 0..1400 {
+                let id = i * 7919 % 1400;
+                let insert_str = format!("insert {} user{} person{}@example.com",
+                                         id, id, id );
+                statement_command(&insert_str, &mut table, &mut MemorySink::new())
+                    .unwrap();
+                assert!(table.pager.num_cached() <= 4);
+            }
+            for i in 0..700 {
+                assert!(table.delete(i * 2 + 1).unwrap());
+            }
+            assert_eq!(table.check_integrity(), Vec::<String>::new());
+        }
+        let mut table = Table::db_open_with(path2, options).unwrap();
+        let mut sink = MemorySink::new();
+        statement_command("select", &mut table, &mut sink).unwrap();
+        assert!(table.pager.num_cached() <= 4);
+        let ids : Vec<Value> = sink.rows.iter().map(|r| r[0].clone()).collect();
+        let expected : Vec<Value> = (0..700).map(|i| Value::Integer(i * 2)).collect();
+        assert_eq!(ids, expected);
+    }
+
+    #[test]
+    fn clean_pages_not_written() {
+        use std::fs::OpenOptions;
+        use std::io::{Seek, SeekFrom};
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        {
+            let mut table = Table::db_open(file_path.clone()).unwrap();
+            statement_command("insert 1 user1 person1@example.com",
+                              &mut table, &mut MemorySink::new()).unwrap();
+        }
+        {
+            let mut table = Table::db_open(file_path.clone()).unwrap();
+            statement_command("select", &mut table, &mut MemorySink::new()).unwrap();
+            // rewrite the user id behind the table's back, a read-only
+            // session must not put the cached copy back on close
+            let mut page = table.pager.read_page(1).unwrap();
+            let offset = node::leaf_cell_offset(0) + 4 + ROW_USERID_OFFSET;
+            page[offset..offset + 5].copy_from_slice(b"USER1");
+            pager::set_checksum(&mut page);
+            let mut file = OpenOptions::new().write(true).open(&file_path).unwrap();
+            file.seek(SeekFrom::Start(PAGE_SIZE as u64)).unwrap();
+            file.write_all(&page).unwrap();
+        }
+        let mut table = Table::db_open(file_path).unwrap();
+        let mut sink = MemorySink::new();
+        statement_command("select", &mut table, &mut sink).unwrap();
+        assert_eq!(sink.rows[0][1], Value::Text(String::from("USER1")));
+    }
+
+    #[test]
+    fn file_header() {
+        use std::fs::{self, OpenOptions};
+        use std::io::{Seek, SeekFrom};
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        drop(Table::db_open(file_path.clone()).unwrap());
+        let bytes = fs::read(&file_path).unwrap();
+        assert_eq!(bytes.len(), 2 * PAGE_SIZE);
+        assert_eq!(&bytes[..16], header::MAGIC);
+        assert!(Table::db_open(file_path.clone()).is_ok());
+
+        let text_path = tmp_dir.path().join("notes.txt");
+        fs::write(&text_path, "insert 1 user1 person1@example.com\n").unwrap();
+        match Table::db_open(text_path) {
+            Err(DbError::NotADatabase) => (),
+            _ => panic!("opened a text file"),
+        }
+
+        let mut file = OpenOptions::new().write(true).open(&file_path).unwrap();
+        file.seek(SeekFrom::Start(16)).unwrap();
+        file.write_all(&(header::FORMAT_VERSION + 1).to_le_bytes()).unwrap();
+        match Table::db_open(file_path.clone()) {
+            Err(DbError::UnsupportedVersion(v)) if v == header::FORMAT_VERSION + 1 => (),
+            _ => panic!("opened a newer format"),
+        }
+        file.seek(SeekFrom::Start(16)).unwrap();
+        file.write_all(&header::FORMAT_VERSION.to_le_bytes()).unwrap();
+        file.seek(SeekFrom::End(0)).unwrap();
+        file.write_all(b"trailing").unwrap();
+        match Table::db_open(file_path) {
+            Err(DbError::Corrupt(_)) => (),
+            _ => panic!("opened a file with a partial page"),
+        }
+    }
+
+    #[test]
+    fn free_page_reuse() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let num_pages = {
+            let mut table = Table::db_open(file_path.clone()).unwrap();
+            for i in 0..700 {
+                let id = i * 7919 % 700;
+                let insert_str = format!("insert {} user{} person{}@example.com",
+                                         id, id, id );
+                statement_command(&insert_str, &mut table, &mut MemorySink::new())
+                    .unwrap();
+            }
+            for id in 0..700 {
+                assert!(table.delete(id).unwrap());
+            }
+            assert_eq!(table.check_integrity(), Vec::<String>::new());
+            table.pager.num_pages
+        };
+        // the free list survives a reopen
+        let mut table = Table::db_open(file_path).unwrap();
+        assert_eq!(table.pager.free_pages().unwrap(), num_pages - 2);
+        for i in 0..700 {
+            let id = i * 7919 % 700;
+            let insert_str = format!("insert {} user{} person{}@example.com",
+                                     id, id, id );
+            statement_command(&insert_str, &mut table, &mut MemorySink::new())
+                .unwrap();
+        }
+        assert_eq!(table.check_integrity(), Vec::<String>::new());
+        assert_eq!(table.pager.num_pages, num_pages);
+    }
+
+    #[test]
+    fn page_checksums() {
+        use std::fs::OpenOptions;
+        use std::io::{Seek, SeekFrom};
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        {
+            let mut table = Table::db_open(file_path.clone()).unwrap();
+            for i in 0..100 {
+                let insert_str = format!("insert {} user{} person{}@example.com",
+                                         i, i, i );
+                statement_command(&insert_str, &mut table, &mut MemorySink::new())
+                    .unwrap();
+            }
+        }
+        // flip a bit in the middle of the first leaf
+        let mut file = OpenOptions::new().read(true).write(true)
+                                         .open(&file_path).unwrap();
+        let offset = 3 * PAGE_SIZE + node::leaf_cell_offset(0) + 4 + 6;
+        file.seek(SeekFrom::Start(offset as u64)).unwrap();
+        file.write_all(b"v").unwrap();
+        drop(file);
+        let mut table = Table::db_open(file_path).unwrap();
+        let mut sink = MemorySink::new();
+        match statement_command("select", &mut table, &mut sink) {
+            Err(DbError::Corrupt(_)) => (),
+            other => panic!("expected a corrupt page, got {:?}", other),
+        }
+        let problems = table.check_integrity();
+        assert!(problems.contains(&"page 3: checksum mismatch".to_string()));
+        match table.clone_to(tmp_dir.path().join("clone.db")) {
+            Err(DbError::Corrupt(_)) => (),
+            _ => panic!("cloned a corrupt page"),
+        }
+    }
+
+    // What is in the file right now, without opening it while the table is
+    fn file_snapshot(file_path : &PathBuf) -> Table {
+        let bytes = std::fs::read(file_path).unwrap();
+        Table::open_from_bytes(Box::leak(bytes.into_boxed_slice())).unwrap()
+    }
+
+    #[test]
+    fn explicit_flush() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let mut table = Table::db_open(file_path.clone()).unwrap();
+        for i in 0..100 {
+            let insert_str = format!("insert {} user{} person{}@example.com",
+                                     i, i, i );
+            statement_command(&insert_str, &mut table, &mut MemorySink::new())
+                .unwrap();
+        }
+        table.flush().unwrap();
+        statement_command("insert 100 user100 person100@example.com",
+                          &mut table, &mut MemorySink::new()).unwrap();
+        meta_command(".flush", &mut table, &mut vec![]).unwrap();
+        // a copy of the file only has what made it there
+        let mut other = file_snapshot(&file_path);
+        let mut sink = MemorySink::new();
+        statement_command("select", &mut other, &mut sink).unwrap();
+        assert_eq!(sink.rows.len(), 101);
+    }
+
+    #[test]
+    fn background_flush() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let options = TableOptions {
+            cache_pages : 4,
+            flush_interval : Some(Duration::from_millis(10)),
+            ..TableOptions::default()
+        };
+        let mut table = Table::db_open_with(file_path.clone(), options).unwrap();
+        for i in 0..300 {
+            let id = i * 7919 % 300;
+            let insert_str = format!("insert {} user{} person{}@example.com",
+                                     id, id, id );
+            statement_command(&insert_str, &mut table, &mut MemorySink::new())
+                .unwrap();
+        }
+        assert!(table.delete(150).unwrap());
+        assert_eq!(table.check_integrity(), Vec::<String>::new());
+        std::thread::sleep(Duration::from_millis(200));
+        // nothing was flushed by hand, the thread got it to the file
+        let mut other = file_snapshot(&file_path);
+        let mut sink = MemorySink::new();
+        statement_command("select", &mut other, &mut sink).unwrap();
+        assert_eq!(sink.rows.len(), 299);
+    }
+
+    #[test]
+    fn data_diff() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let mut a = Table::db_open(tmp_dir.path().join("a.db")).unwrap();
+        let mut b = Table::db_open(tmp_dir.path().join("b.db")).unwrap();
+        for i in 0..200 {
+            let insert_str = format!("insert {} user{} person{}@example.com",
+                                     i, i, i );
+            if i % 5 != 0 {
+                statement_command(&insert_str, &mut a, &mut MemorySink::new()).unwrap();
+            }
+            if i % 7 != 0 {
+                statement_command(&insert_str, &mut b, &mut MemorySink::new()).unwrap();
+            }
+        }
+        assert!(b.delete(1).unwrap());
+        statement_command("insert 1 changed changed@example.com",
+                          &mut b, &mut MemorySink::new()).unwrap();
+        let mut buf : Vec<u8> = vec![];
+        diff(&mut a, &mut b, &mut buf).unwrap();
+        let out = String::from_utf8(buf).unwrap();
+        assert!(out.starts_with("delete 1\n\
+                                 insert 1 changed changed@example.com\n\
+                                 insert 5 user5 person5@example.com\n\
+                                 delete 7\n"));
+        for line in out.lines() {
+            statement_command(line, &mut a, &mut MemorySink::new()).unwrap();
+        }
+        let mut rows_a = MemorySink::new();
+        let mut rows_b = MemorySink::new();
+        statement_command("select", &mut a, &mut rows_a).unwrap();
+        statement_command("select", &mut b, &mut rows_b).unwrap();
+        assert_eq!(rows_a.rows, rows_b.rows);
+        let mut buf : Vec<u8> = vec![];
+        diff(&mut a, &mut b, &mut buf).unwrap();
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn sorted_by_id() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let mut table = Table::db_open(file_path).unwrap();
+        let mut buf : Vec<u8> = vec![];
+        for i in &[3, 1, 2] {
+            let insert_str = format!("insert {} user{} person{}@example.com",
+                                     i, i, i );
+            statement_command(&insert_str, &mut table,
+                              &mut TextSink::new(&mut buf)).unwrap();
+        }
+        statement_command("select", &mut table,
+                          &mut TextSink::new(&mut buf)).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(),
+                   String::from("(1, user1, person1@example.com)\n\
+                                 (2, user2, person2@example.com)\n\
+                                 (3, user3, person3@example.com)\n"));
+    }
+
+    #[test]
+    fn random_order() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let mut table = Table::db_open(file_path).unwrap();
+        // 7919 is prime, so this visits every id below 1400 once
+        for i in 0..1400 {
+            let mut buf : Vec<u8> = vec![];
+            let id = i * 7919 % 1400;
+            let insert_str = format!("insert {} user{} person{}@example.com",
+                                     id, id, id );
+            statement_command(&insert_str, &mut table,
+                              &mut TextSink::new(&mut buf)).unwrap();
+        }
+        let mut buf : Vec<u8> = vec![];
+        statement_command("select", &mut table,
+                          &mut TextSink::new(&mut buf)).unwrap();
+        let whole_str = String::from_utf8(buf).unwrap();
+        let mut idx = 0;
+        for rec in whole_str.lines() {
+            assert_eq!(rec, format!("({}, user{}, person{}@example.com)",
+                                    idx, idx, idx));
+            idx += 1;
+        }
+        assert_eq!(idx, 1400);
+    }
+
+    #[test]
+    fn table_max_persist() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        for total_lines in 0..1400 {
+            let path1 = tmp_dir.path().join(format!("test{}.db",total_lines));
+            let path2 = path1.clone();
+            {
+                let mut table = Table::db_open(path1).unwrap();
+                for i in 0..total_lines {
+                    let mut buf : Vec<u8> = vec![];
+                    let insert_str = format!("insert {} user{} person{}@example.com", 
+                                            i, i, i );
+                    statement_command(&insert_str, &mut table,
+                                      &mut TextSink::new(&mut buf)).unwrap();
+                }
+            }
+            {
+                let mut table = Table::db_open(path2).unwrap();
+                let mut buf : Vec<u8> = vec![];
+                statement_command("select", &mut table,
+                                  &mut TextSink::new(&mut buf)).unwrap();
+                let mut idx = 0;
+                let whole_str = String::from_utf8(buf).unwrap();
+                let lines = whole_str.lines();
+                for rec in lines {
+                    assert_eq!(rec, format!("({}, user{}, person{}@example.com)", 
+                                            idx, idx, idx));
+                    idx += 1;
+                }
+                assert_eq!(idx, total_lines);
+            }
+
+        }
+
+    }
+
+    #[test]
+    fn overflow_pages() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let user = "u".repeat(100);
+        let email = format!("{}@example.com", "e".repeat(10000));
+        {
+            let mut table = Table::db_open(file_path.clone()).unwrap();
+            for id in 0..20 {
+                let insert_str = format!("insert {} {}{} {}", id, user, id, email);
+                statement_command(&insert_str, &mut table, &mut MemorySink::new())
+                    .unwrap();
+            }
+            assert_eq!(table.check_integrity(), Vec::<String>::new());
+        }
+        let mut table = Table::db_open(file_path).unwrap();
+        let mut cursor = table.find(7).unwrap();
+        assert_eq!(cursor.value().unwrap(), Some(Row {
+            id : 7,
+            user_id : format!("{}7", user),
+            email : email.clone(),
+        }));
+        // deleting a row gives its overflow pages back
+        let num_pages = table.pager.num_pages;
+        for id in 0..10 {
+            assert!(table.delete(id).unwrap());
+        }
+        assert_eq!(table.check_integrity(), Vec::<String>::new());
+        let insert_str = format!("insert 0 {} {}", user, email);
+        statement_command(&insert_str, &mut table, &mut MemorySink::new()).unwrap();
+        assert_eq!(table.pager.num_pages, num_pages);
+        assert_eq!(table.check_integrity(), Vec::<String>::new());
+        // a value too long to read back never goes in, whichever way it comes
+        let long = Row { id : 100, user_id : user, email : "e".repeat(MAX_VALUE_SIZE + 1) };
+        let replace = Row { id : 7, ..long.clone() };
+        let results = vec![Engine::validate_insert(&mut table, &long),
+                           Engine::insert(&mut table, &long),
+                           Engine::validate_replace(&mut table, &replace),
+                           Engine::insert_or_replace(&mut table, &replace).map(|_| ())];
+        for result in results {
+            match result {
+                Err(DbError::StatementSyntaxError) => (),
+                other => panic!("expected syntax error, got {:?}", other),
+            }
+        }
+        let mut sink = MemorySink::new();
+        statement_command("select", &mut table, &mut sink).unwrap();
+        assert_eq!(sink.rows.len(), 11);
+        assert_eq!(table.check_integrity(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn explain_validate() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let options = TableOptions { max_pages : Some(2), ..TableOptions::default() };
+        let mut table = Table::db_open_with(file_path, options).unwrap();
+        let mut sink = MemorySink::new();
+        statement_command("insert 1 user1 person1@example.com",
+                          &mut table, &mut sink).unwrap();
+        assert_eq!(parse("explain validate delete 1").unwrap(),
+                   Statement::Validate(Box::new(Statement::Delete(1))));
+        statement_command("explain validate insert 2 user2 person2@example.com",
+                          &mut table, &mut sink).unwrap();
+        statement_command("explain validate delete 1", &mut table, &mut sink).unwrap();
+        match statement_command("explain validate insert 1 user1 person1@example.com",
+                                &mut table, &mut sink) {
+            Err(DbError::DuplicateKey) => (),
+            other => panic!("expected DuplicateKey, got {:?}", other),
+        }
+        // a long email needs an overflow page the file has no room for
+        let long_insert = format!("explain validate insert 3 user3 {}", "e".repeat(300));
+        match statement_command(&long_insert, &mut table, &mut sink) {
+            Err(DbError::TableFull) => (),
+            other => panic!("expected TableFull, got {:?}", other),
+        }
+        // so does replacing a row with it
+        let long_replace = format!("explain validate insert or replace 1 user1 {}", "e".repeat(300));
+        match statement_command(&long_replace, &mut table, &mut sink) {
+            Err(DbError::TableFull) => (),
+            other => panic!("expected TableFull, got {:?}", other),
+        }
+        statement_command("explain validate insert or replace 1 user2 person2@example.com",
+                          &mut table, &mut sink).unwrap();
+        match parse("explain validate") {
+            Err(DbError::StatementUnrecognized) => (),
+            other => panic!("expected StatementUnrecognized, got {:?}", other),
+        }
+        statement_command("select", &mut table, &mut sink).unwrap();
+        assert_eq!(sink.rows.len(), 1);
+        assert_eq!(table.pager.num_pages, 2);
+    }
+
+    #[test]
+    fn mmap_pager() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let options = TableOptions { mmap : true, cache_pages : 4, ..TableOptions::default() };
+        let mut expected = MemorySink::new();
+        {
+            let mut table = Table::db_open_with(file_path.clone(), options.clone()).unwrap();
+            for i in 0..300 {
+                let id = i * 7919 % 300;
+                let insert_str = format!("insert {} user{} person{}@example.com",
+                                         id, id, id);
+                statement_command(&insert_str, &mut table, &mut MemorySink::new())
+                    .unwrap();
+            }
+            table.flush().unwrap();
+            for id in 0..100 {
+                assert!(table.delete(id).unwrap());
+            }
+            statement_command("select", &mut table, &mut expected).unwrap();
+            assert_eq!(table.check_integrity(), Vec::<String>::new());
+        }
+        // a clean scan reads every page from the mapping, none get cached
+        let mut table = Table::db_open_with(file_path, options).unwrap();
+        let mut sink = MemorySink::new();
+        statement_command("select", &mut table, &mut sink).unwrap();
+        assert_eq!(sink.rows, expected.rows);
+        assert_eq!(sink.rows.len(), 200);
+        assert_eq!(table.pager.num_cached(), 0);
+        assert_eq!(table.check_integrity(), Vec::<String>::new());
+    }
+
+    // Wraps FileStorage, writes fail while fail is set
+    struct FaultyStorage {
+        inner : FileStorage,
+        fail : std::sync::Arc<std::sync::atomic::AtomicBool>,
+    }
+
+    impl Storage for FaultyStorage {
+        fn read_page(&mut self, page_num : usize, data : &mut [u8]) -> std::io::Result<()> {
+            self.inner.read_page(page_num, data)
+        }
+        fn write_page(&mut self, page_num : usize, data : &[u8]) -> std::io::Result<()> {
+            if self.fail.load(std::sync::atomic::Ordering::SeqCst) {
+                return Err(std::io::Error::other("injected write failure"));
+            }
+            self.inner.write_page(page_num, data)
+        }
+        fn sync(&mut self) -> std::io::Result<()> {
+            self.inner.sync()
+        }
+        fn len(&self) -> std::io::Result<u64> {
+            self.inner.len()
+        }
+    }
+
+    #[test]
+    fn storage_faults() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let fail = Arc::new(AtomicBool::new(false));
+        let storage = FaultyStorage {
+            inner : FileStorage::open(file_path.clone()).unwrap(),
+            fail : fail.clone(),
+        };
+        let mut table = Table::db_open_storage(Box::new(storage),
+                                               TableOptions::default()).unwrap();
+        statement_command("insert 1 user1 person1@example.com",
+                          &mut table, &mut MemorySink::new()).unwrap();
+        fail.store(true, Ordering::SeqCst);
+        match table.flush() {
+            Err(DbError::IoError(_)) => (),
+            other => panic!("expected IoError, got {:?}", other),
+        }
+        // nothing was lost, the pages are still dirty
+        fail.store(false, Ordering::SeqCst);
+        table.flush().unwrap();
+        // an undo that fails on commit goes back on the undo log
+        // as it was, not on top of the change it published
+        table.set_durability(Durability::OnCommit);
+        assert!(table.delete(1).unwrap());
+        let undo_log = table.undo_log.clone();
+        fail.store(true, Ordering::SeqCst);
+        assert!(table.undo().is_err());
+        assert_eq!(table.undo_log, undo_log);
+        fail.store(false, Ordering::SeqCst);
+        table.flush().unwrap();
+        drop(table);
+        let mut table = Table::db_open(file_path).unwrap();
+        let mut sink = MemorySink::new();
+        statement_command("select", &mut table, &mut sink).unwrap();
+        assert_eq!(sink.rows.len(), 1);
+    }
+
+    #[test]
+    fn memory_db() {
+        let mut table = Table::db_open(PathBuf::from(MEMORY_DB)).unwrap();
+        for i in 0..200 {
+            let insert_str = format!("insert {} user{} person{}@example.com", i, i, i);
+            statement_command(&insert_str, &mut table, &mut MemorySink::new()).unwrap();
+        }
+        table.flush().unwrap();
+        let mut sink = MemorySink::new();
+        statement_command("select", &mut table, &mut sink).unwrap();
+        assert_eq!(sink.rows.len(), 200);
+        assert_eq!(table.check_integrity(), Vec::<String>::new());
+        assert!(!PathBuf::from(MEMORY_DB).exists());
+        // every open is a new, empty database
+        let mut table = Table::db_open(PathBuf::from(MEMORY_DB)).unwrap();
+        let mut sink = MemorySink::new();
+        statement_command("select", &mut table, &mut sink).unwrap();
+        assert!(sink.rows.is_empty());
+    }
+
+    #[test]
+    fn size_quota() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let options = TableOptions {
+            max_file_size : Some(8 * PAGE_SIZE as u64),
+            ..TableOptions::default()
+        };
+        let mut table = Table::db_open_with(file_path, options).unwrap();
+        let mut inserted = 0;
+        loop {
+            let insert_str = format!("insert {} user{} person{}@example.com",
+                                     inserted, inserted, inserted);
+            match statement_command(&insert_str, &mut table, &mut MemorySink::new()) {
+                Ok(()) => inserted += 1,
+                Err(DbError::QuotaExceeded) => break,
+                Err(err) => panic!("unexpected error {}", err),
+            }
+        }
+        assert!(inserted > 0);
+        let num_pages = table.pager.num_pages;
+        assert!(num_pages <= 8);
+        let mut buf : Vec<u8> = vec![];
+        meta_command(".dbinfo", &mut table, &mut buf).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(),
+                   format!("Database info:\n\
+                            page size: 4096\n\
+                            pages: {}\n\
+                            free pages: 0\n\
+                            rows: {}\n\
+                            size: {} bytes\n\
+                            rows inserted: {}\n\
+                            vacuums: 0\n\
+                            last checkpoint: never\n\
+                            quota: 32768 bytes\n\
+                            max row size: none\n", num_pages, inserted, num_pages * PAGE_SIZE,
+                           inserted));
+        // deleting makes room again
+        for id in 0..inserted {
+            table.delete(id).unwrap();
+        }
+        statement_command("insert 1 user1 person1@example.com",
+                          &mut table, &mut MemorySink::new()).unwrap();
+    }
+
+    #[test]
+    fn max_row_size() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let options = TableOptions { max_row_size : Some(ROW_SIZE + 100), ..TableOptions::default() };
+        let mut table = Table::db_open_with(tmp_dir.path().join("test1.db"), options).unwrap();
+        let row = |id, extra| Row { id, user_id : format!("user{}", id),
+                                    email : "e".repeat(EMAIL_SIZE + extra) };
+        assert_eq!(row(1, 100).encoded_size(), ROW_SIZE + 100);
+        Engine::insert(&mut table, &row(1, 0)).unwrap();
+        Engine::insert(&mut table, &row(2, 100)).unwrap();
+        let too_large = row(3, 101);
+        let results = vec![Engine::validate_insert(&mut table, &too_large),
+                           Engine::insert(&mut table, &too_large),
+                           Engine::insert_or_replace(&mut table, &row(1, 101)).map(|_| ())];
+        for result in results {
+            match result {
+                Err(DbError::RowTooLarge(size)) => assert_eq!(size, ROW_SIZE + 101),
+                other => panic!("expected RowTooLarge, got {:?}", other),
+            }
+        }
+        // the row that was there stays
+        assert_eq!(Engine::get(&mut table, 1).unwrap(), Some(row(1, 0)));
+        assert_eq!(table.num_rows().unwrap(), 2);
+        let mut buf : Vec<u8> = vec![];
+        meta_command(".dbinfo", &mut table, &mut buf).unwrap();
+        let info = String::from_utf8(buf).unwrap();
+        assert!(info.contains(&format!("max row size: {} bytes\n", ROW_SIZE + 100)), "{}", info);
+    }
+
+    #[test]
+    fn close_table() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let mut table = Table::db_open(file_path.clone()).unwrap();
+        statement_command("insert 1 user1 person1@example.com",
+                          &mut table, &mut MemorySink::new()).unwrap();
+        table.close().unwrap();
+        let mut table = Table::db_open(file_path.clone()).unwrap();
+        let mut sink = MemorySink::new();
+        statement_command("select", &mut table, &mut sink).unwrap();
+        assert_eq!(sink.rows.len(), 1);
+        drop(table);
+        // a failed close is an error, not a panic
+        let fail = Arc::new(AtomicBool::new(false));
+        let storage = FaultyStorage {
+            inner : FileStorage::open(file_path).unwrap(),
+            fail : fail.clone(),
+        };
+        let mut table = Table::db_open_storage(Box::new(storage),
+                                               TableOptions::default()).unwrap();
+        statement_command("insert 2 user2 person2@example.com",
+                          &mut table, &mut MemorySink::new()).unwrap();
+        fail.store(true, Ordering::SeqCst);
+        match table.close() {
+            Err(DbError::IoError(_)) => (),
+            other => panic!("expected IoError, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn drop_never_panics() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let fail = Arc::new(AtomicBool::new(false));
+        let storage = FaultyStorage {
+            inner : FileStorage::open(file_path).unwrap(),
+            fail : fail.clone(),
+        };
+        let mut table = Table::db_open_storage(Box::new(storage),
+                                               TableOptions::default()).unwrap();
+        statement_command("insert 1 user1 person1@example.com",
+                          &mut table, &mut MemorySink::new()).unwrap();
+        fail.store(true, Ordering::SeqCst);
+        drop(table);
+    }
+
+    #[test]
+    fn open_from_bytes() {
+        use std::fs;
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        {
+            let mut table = Table::db_open(file_path.clone()).unwrap();
+            for i in 0..50 {
+                let insert_str = format!("insert {} user{} person{}@example.com", i, i, i);
+                statement_command(&insert_str, &mut table, &mut MemorySink::new()).unwrap();
+            }
+        }
+        let bytes : &'static [u8] = Box::leak(fs::read(&file_path).unwrap().into_boxed_slice());
+        let mut table = Table::open_from_bytes(bytes).unwrap();
+        let mut sink = MemorySink::new();
+        statement_command("select", &mut table, &mut sink).unwrap();
+        assert_eq!(sink.rows.len(), 50);
+        assert_eq!(table.check_integrity(), Vec::<String>::new());
+        for statement in &["insert 50 user50 person50@example.com", "delete 1",
+                           "explain validate delete 1"] {
+            match statement_command(statement, &mut table, &mut MemorySink::new()) {
+                Err(DbError::ReadOnly) => (),
+                other => panic!("expected ReadOnly, got {:?}", other),
+            }
+        }
+        table.close().unwrap();
+        match Table::open_from_bytes(&[]) {
+            Err(DbError::NotADatabase) => (),
+            other => panic!("expected NotADatabase, got {:?}", other.err()),
+        }
+    }
+
+    // An object store that keeps everything in a shared map
+    #[cfg(feature = "object-store")]
+    #[derive(Clone, Default)]
+    struct MapStore {
+        objects : std::sync::Arc<std::sync::Mutex<std::collections::HashMap<String, Vec<u8>>>>,
+    }
+
+    #[cfg(feature = "object-store")]
+    impl ObjectStore for MapStore {
+        fn get(&mut self, key : &str) -> std::io::Result<Option<Vec<u8>>> {
+            Ok(self.objects.lock().unwrap().get(key).cloned())
+        }
+        fn put(&mut self, key : &str, data : &[u8]) -> std::io::Result<()> {
+            self.objects.lock().unwrap().insert(key.to_string(), data.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "object-store")]
+    fn object_storage() {
+        let store = MapStore::default();
+        {
+            let storage = ObjectStorage::open(store.clone(), "db1/").unwrap();
+            let mut table = Table::db_open_storage(Box::new(storage),
+                                                   TableOptions::default()).unwrap();
+            for i in 0..100 {
+                let insert_str = format!("insert {} user{} person{}@example.com", i, i, i);
+                statement_command(&insert_str, &mut table, &mut MemorySink::new()).unwrap();
+            }
+            // nothing is uploaded before a sync
+            assert!(store.objects.lock().unwrap().is_empty());
+            table.close().unwrap();
+        }
+        let num_pages : usize = String::from_utf8(store.objects.lock().unwrap()["db1/pages"]
+                                                  .clone()).unwrap().parse().unwrap();
+        assert_eq!(store.objects.lock().unwrap().len(), num_pages + 1);
+        let storage = ObjectStorage::open(store.clone(), "db1/").unwrap();
+        let mut table = Table::db_open_storage(Box::new(storage),
+                                               TableOptions::default()).unwrap();
+        let mut sink = MemorySink::new();
+        statement_command("select", &mut table, &mut sink).unwrap();
+        assert_eq!(sink.rows.len(), 100);
+        assert_eq!(table.check_integrity(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn log_engine() {
+        use std::fs::{self, OpenOptions};
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.log");
+        {
+            let mut table = LogTable::open(file_path.clone()).unwrap();
+            for i in 0..200 {
+                let id = i * 7919 % 200;
+                let insert_str = format!("insert {} user{} person{}@example.com", id, id, id);
+                statement_command(&insert_str, &mut table, &mut MemorySink::new()).unwrap();
+            }
+            match statement_command("insert 3 user3 person3@example.com",
+                                    &mut table, &mut MemorySink::new()) {
+                Err(DbError::DuplicateKey) => (),
+                other => panic!("expected DuplicateKey, got {:?}", other),
+            }
+            let size = fs::metadata(&file_path).unwrap().len();
+            for id in 0..150 {
+                assert!(Engine::delete(&mut table, id).unwrap());
+            }
+            // most records were dead, the log got compacted along the way
+            assert!(fs::metadata(&file_path).unwrap().len() < size);
+            assert!(table.counts().1 < 64);
+            assert_eq!(table.counts().0, 50);
+            // a value too long to read back never reaches the log, where
+            // it would cut off the records after it on open
+            let long = Row { id : 300, user_id : "u".to_string(), email : "e".repeat(MAX_VALUE_SIZE + 1) };
+            let replace = Row { id : 199, ..long.clone() };
+            let results = vec![Engine::insert(&mut table, &long),
+                               Engine::insert_or_replace(&mut table, &replace).map(|_| ())];
+            for result in results {
+                match result {
+                    Err(DbError::StatementSyntaxError) => (),
+                    other => panic!("expected syntax error, got {:?}", other),
+                }
+            }
+            statement_command("insert 300 user300 person300@example.com",
+                              &mut table, &mut MemorySink::new()).unwrap();
+            table.close().unwrap();
+        }
+        // a record cut short by a crash is dropped on open
+        {
+            let mut file = OpenOptions::new().append(true).open(&file_path).unwrap();
+            file.write_all(&[1, 0, 0]).unwrap();
+        }
+        let mut table = LogTable::open(file_path.clone()).unwrap();
+        let mut sink = MemorySink::new();
+        statement_command("select", &mut table, &mut sink).unwrap();
+        let ids : Vec<Value> = sink.rows.iter().map(|r| r[0].clone()).collect();
+        assert_eq!(ids, (150..200).chain(Some(300)).map(Value::Integer).collect::<Vec<_>>());
+        match Table::db_open(file_path) {
+            Err(DbError::NotADatabase) => (),
+            other => panic!("expected NotADatabase, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn lsm_engine() {
+        use std::fs;
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let dir = tmp_dir.path().join("test1.lsm");
+        let options = LsmOptions { memtable_rows : 20, max_runs : 3, ..LsmOptions::default() };
+        {
+            let mut table = LsmTable::open_with(dir.clone(), options.clone()).unwrap();
+            for i in 0..300 {
+                let id = i * 7919 % 300;
+                let insert_str = format!("insert {} user{} person{}@example.com", id, id, id);
+                statement_command(&insert_str, &mut table, &mut MemorySink::new()).unwrap();
+            }
+            for id in (0..300).filter(|id| id % 3 == 0) {
+                assert!(Engine::delete(&mut table, id).unwrap());
+            }
+            assert!(!Engine::delete(&mut table, 0).unwrap());
+            match statement_command("insert 1 user1 person1@example.com",
+                                    &mut table, &mut MemorySink::new()) {
+                Err(DbError::DuplicateKey) => (),
+                other => panic!("expected DuplicateKey, got {:?}", other),
+            }
+            // a deleted id can be used again
+            statement_command("insert 3 again3 again3@example.com",
+                              &mut table, &mut MemorySink::new()).unwrap();
+            // a value too long to read back stays out of the memtable,
+            // the run it was flushed to wouldn't open
+            let long = Row { id : 300, user_id : "u".to_string(), email : "e".repeat(MAX_VALUE_SIZE + 1) };
+            match Engine::insert(&mut table, &long) {
+                Err(DbError::StatementSyntaxError) => (),
+                other => panic!("expected syntax error, got {:?}", other),
+            }
+            table.wait_for_merge().unwrap();
+            assert!(table.num_runs() <= 4);
+            table.close().unwrap();
+        }
+        // only the engine's own leftovers are removed on open
+        fs::write(dir.join("run-99999999.sst"), b"partial").unwrap();
+        fs::write(dir.join("notes.txt"), b"keep").unwrap();
+        fs::create_dir(dir.join("backup")).unwrap();
+        let mut table = LsmTable::open_with(dir.clone(), options).unwrap();
+        assert!(!dir.join("run-99999999.sst").exists());
+        assert!(dir.join("notes.txt").exists());
+        assert!(dir.join("backup").is_dir());
+        let mut sink = MemorySink::new();
+        statement_command("select", &mut table, &mut sink).unwrap();
+        let ids : Vec<Value> = sink.rows.iter().map(|r| r[0].clone()).collect();
+        let expected : Vec<Value> = (0..300).filter(|id| id % 3 != 0 || *id == 3)
+            .map(Value::Integer).collect();
+        assert_eq!(ids, expected);
+        assert_eq!(sink.rows[2][1], Value::Text(String::from("again3")));
+    }
+
+    #[test]
+    fn directory_sync() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        storage::sync_dir(&tmp_dir.path().join("test1.db")).unwrap();
+        // a bare file name lives in the current directory
+        storage::sync_dir(&PathBuf::from("test1.db")).unwrap();
+        storage::sync_dir(&PathBuf::from("/")).unwrap();
+    }
+
+    #[test]
+    fn replication_stream() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let row = |id : u32| Row {
+            id,
+            user_id : format!("user{}", id),
+            email : format!("person{}@example.com", id),
+        };
+        {
+            let mut table = Table::db_open(file_path.clone()).unwrap();
+            let stream = table.replication_stream();
+            let dropped = table.replication_stream();
+            drop(dropped);
+            for id in 1..3 {
+                let insert_str = format!("insert {} user{} person{}@example.com", id, id, id);
+                statement_command(&insert_str, &mut table, &mut MemorySink::new()).unwrap();
+            }
+            // failed statements and no-op deletes change nothing
+            assert!(statement_command("insert 1 user1 person1@example.com",
+                                      &mut table, &mut MemorySink::new()).is_err());
+            assert!(!table.delete(7).unwrap());
+            assert!(table.delete(1).unwrap());
+            assert_eq!(table.subscribers.len(), 1);
+            let events : Vec<ChangeEvent> = stream.try_iter().collect();
+            assert_eq!(events, vec![
+                ChangeEvent { lsn : 1, change : Change::Insert(row(1)) },
+                ChangeEvent { lsn : 2, change : Change::Insert(row(2)) },
+                ChangeEvent { lsn : 3, change : Change::Delete(row(1)) },
+            ]);
+        }
+        // lsns carry on after a reopen
+        let mut table = Table::db_open(file_path).unwrap();
+        let stream = table.replication_stream();
+        statement_command("insert 3 user3 person3@example.com",
+                          &mut table, &mut MemorySink::new()).unwrap();
+        assert_eq!(stream.try_recv().unwrap(),
+                   ChangeEvent { lsn : 4, change : Change::Insert(row(3)) });
+    }
+
+    #[test]
+    fn catalog() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let catalog = Catalog::open(tmp_dir.path().join("dbs")).unwrap();
+        assert_eq!(catalog.list_databases().unwrap(), Vec::<String>::new());
+        {
+            let mut db1 = catalog.create_database("db1").unwrap();
+            let mut db2 = catalog.create_database("db2").unwrap();
+            statement_command("insert 1 user1 person1@example.com",
+                              &mut db1, &mut MemorySink::new()).unwrap();
+            statement_command("insert 2 user2 person2@example.com",
+                              &mut db2, &mut MemorySink::new()).unwrap();
+        }
+        match catalog.create_database("db1") {
+            Err(DbError::DatabaseExists(ref name)) if name == "db1" => (),
+            other => panic!("expected DatabaseExists, got {:?}", other.err()),
+        }
+        for name in &["", "../db1", "db1.db", "a/b"] {
+            match catalog.open_database(name) {
+                Err(DbError::InvalidDatabaseName(_)) => (),
+                other => panic!("expected InvalidDatabaseName, got {:?}", other.err()),
+            }
+        }
+        assert_eq!(catalog.list_databases().unwrap(), vec!["db1", "db2"]);
+        let tables = catalog.tables().unwrap();
+        assert_eq!(tables.iter().map(|t| t.name.as_str()).collect::<Vec<_>>(),
+                   vec!["db1", "db2"]);
+        assert_eq!(tables[0].columns, columns());
+        let mut db2 = catalog.open_database("db2").unwrap();
+        let mut sink = MemorySink::new();
+        statement_command("select", &mut db2, &mut sink).unwrap();
+        assert_eq!(sink.rows[0][0], Value::Integer(2));
+        drop(db2);
+        catalog.drop_database("db1").unwrap();
+        assert_eq!(catalog.list_databases().unwrap(), vec!["db2"]);
+        match catalog.open_database("db1") {
+            Err(DbError::UnknownDatabase(_)) => (),
+            other => panic!("expected UnknownDatabase, got {:?}", other.err()),
+        }
+
+        // the engine is picked when the database is created
+        {
+            let mut log = catalog.create_log_database("log1").unwrap();
+            statement_command("insert 3 user3 person3@example.com",
+                              &mut log, &mut MemorySink::new()).unwrap();
+        }
+        match catalog.create_log_database("db2") {
+            Err(DbError::DatabaseExists(ref name)) if name == "db2" => (),
+            other => panic!("expected DatabaseExists, got {:?}", other.err()),
+        }
+        assert!(catalog.create_database("log1").is_err());
+        assert_eq!(catalog.list_databases().unwrap(), vec!["db2", "log1"]);
+        for (name, id) in [("log1", 3), ("db2", 2)] {
+            let mut engine = catalog.open_engine(name).unwrap();
+            let mut sink = MemorySink::new();
+            statement_command("select", &mut *engine, &mut sink).unwrap();
+            assert_eq!(sink.rows[0][0], Value::Integer(id));
+        }
+        catalog.drop_database("log1").unwrap();
+        assert_eq!(catalog.list_databases().unwrap(), vec!["db2"]);
+    }
+
+    #[test]
+    fn double_write() {
+        use std::fs;
+        use std::io::{Seek, SeekFrom};
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let options = TableOptions { double_write : true, ..TableOptions::default() };
+        {
+            let mut table = Table::db_open_with(file_path.clone(), options.clone()).unwrap();
+            for i in 0..5 {
+                let insert_str = format!("insert {} user{} person{}@example.com", i, i, i);
+                statement_command(&insert_str, &mut table, &mut MemorySink::new()).unwrap();
+            }
+            table.close().unwrap();
+        }
+        assert!(tmp_dir.path().join("test1.db-dwb").exists());
+        // a crash half way through writing the root leaves half of it zeroed
+        let mut file = OpenOptions::new().write(true).open(&file_path).unwrap();
+        file.seek(SeekFrom::Start((PAGE_SIZE + PAGE_SIZE / 2) as u64)).unwrap();
+        file.write_all(&[0; PAGE_SIZE / 2]).unwrap();
+        drop(file);
+        let torn = fs::read(&file_path).unwrap();
+        let mut sink = MemorySink::new();
+        match Table::db_open(file_path.clone())
+                .and_then(|mut table| statement_command("select", &mut table, &mut sink)) {
+            Err(DbError::Corrupt(_)) => (),
+            other => panic!("expected Corrupt, got {:?}", other.err()),
+        }
+        assert_eq!(fs::read(&file_path).unwrap(), torn);
+        let mut table = Table::db_open_with(file_path, options).unwrap();
+        assert_eq!(table.check_integrity(), Vec::<String>::new());
+        statement_command("select", &mut table, &mut sink).unwrap();
+        assert_eq!(sink.rows.len(), 5);
+    }
+
+    // Wraps FileStorage, counting syncs
+    struct SyncCounter {
+        inner : FileStorage,
+        syncs : std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl Storage for SyncCounter {
+        fn read_page(&mut self, page_num : usize, data : &mut [u8]) -> std::io::Result<()> {
+            self.inner.read_page(page_num, data)
+        }
+        fn write_page(&mut self, page_num : usize, data : &[u8]) -> std::io::Result<()> {
+            self.inner.write_page(page_num, data)
+        }
+        fn sync(&mut self) -> std::io::Result<()> {
+            self.syncs.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            self.inner.sync()
+        }
+        fn len(&self) -> std::io::Result<u64> {
+            self.inner.len()
+        }
+    }
+
+    #[test]
+    fn durability() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let syncs = Arc::new(AtomicUsize::new(0));
+        let storage = SyncCounter {
+            inner : FileStorage::open(tmp_dir.path().join("test1.db")).unwrap(),
+            syncs : syncs.clone(),
+        };
+        let options = TableOptions { durability : Durability::Off, ..TableOptions::default() };
+        let mut table = Table::db_open_storage(Box::new(storage), options).unwrap();
+        let insert = |table : &mut Table, i : u32| {
+            let insert_str = format!("insert {} user{} person{}@example.com", i, i, i);
+            statement_command(&insert_str, table, &mut MemorySink::new()).unwrap();
+        };
+        insert(&mut table, 1);
+        table.flush().unwrap();
+        assert_eq!(syncs.load(Ordering::SeqCst), 0);
+
+        meta_command(".durability on-close", &mut table, &mut vec![]).unwrap();
+        insert(&mut table, 2);
+        assert_eq!(syncs.load(Ordering::SeqCst), 0);
+        table.flush().unwrap();
+        assert_eq!(syncs.load(Ordering::SeqCst), 1);
+
+        // one sync per statement, however many pages it wrote
+        table.set_durability(Durability::OnCommit);
+        insert(&mut table, 3);
+        assert_eq!(syncs.load(Ordering::SeqCst), 2);
+        assert!(table.delete(3).unwrap());
+        assert_eq!(syncs.load(Ordering::SeqCst), 3);
+
+        // a new row dirties both the header and the leaf
+        table.set_durability(Durability::Always);
+        insert(&mut table, 4);
+        assert!(syncs.load(Ordering::SeqCst) >= 6);
+
+        let mut buf = vec![];
+        meta_command(".durability", &mut table, &mut buf).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), "durability: always\n");
+        match meta_command(".durability sometimes", &mut table, &mut vec![]) {
+            Err(DbError::MetaUnrecognized) => (),
+            other => panic!("expected MetaUnrecognized, got {:?}", other.err()),
+        }
+        assert_eq!(table.durability(), Durability::Always);
+    }
+
+    #[test]
+    fn direct_io() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let options = TableOptions { direct_io : true, mmap : true, cache_pages : 4,
+                                     ..TableOptions::default() };
+        {
+            let mut table = Table::db_open_with(file_path.clone(), options.clone()).unwrap();
+            for i in 0..200 {
+                let insert_str = format!("insert {} user{} person{}@example.com", i, i, i);
+                statement_command(&insert_str, &mut table, &mut MemorySink::new()).unwrap();
+            }
+            table.close().unwrap();
+        }
+        // mapping the file would go through the page cache again
+        let storage = FileStorage::open_direct(file_path.clone(), true).unwrap();
+        assert_eq!(storage.file().is_none(), storage.is_direct());
+        let read_only = TableOptions { read_only : true, ..options };
+        let mut table = Table::db_open_with(file_path, read_only).unwrap();
+        assert_eq!(table.check_integrity(), Vec::<String>::new());
+        let mut sink = MemorySink::new();
+        statement_command("select", &mut table, &mut sink).unwrap();
+        assert_eq!(sink.rows.len(), 200);
+    }
+
+    #[test]
+    fn bench() {
+        let results = bench::run(300).unwrap();
+        let names : Vec<&str> = results.iter().map(|result| result.name).collect();
+        assert_eq!(names, vec!["sequential insert", "random point read", "range scan", "mixed"]);
+        assert_eq!(results[0].latencies.len(), 300);
+        assert_eq!(results[2].latencies.len(), 3);
+        assert!(results[0].percentile(50.0) <= results[0].percentile(99.0));
+        let mut buf = vec![];
+        bench::report(&results, &mut buf).unwrap();
+        let report = String::from_utf8(buf).unwrap();
+        assert_eq!(report.lines().count(), 5);
+        assert!(report.lines().nth(1).unwrap().starts_with("sequential insert"));
+    }
+
+    // Wraps FileStorage, recording what it was asked to prefetch
+    struct PrefetchLog {
+        inner : FileStorage,
+        prefetched : std::sync::Arc<std::sync::Mutex<Vec<(usize, usize)>>>,
+    }
+
+    impl Storage for PrefetchLog {
+        fn read_page(&mut self, page_num : usize, data : &mut [u8]) -> std::io::Result<()> {
+            self.inner.read_page(page_num, data)
+        }
+        fn write_page(&mut self, page_num : usize, data : &[u8]) -> std::io::Result<()> {
+            self.inner.write_page(page_num, data)
+        }
+        fn sync(&mut self) -> std::io::Result<()> {
+            self.inner.sync()
+        }
+        fn len(&self) -> std::io::Result<u64> {
+            self.inner.len()
+        }
+        fn prefetch(&mut self, page_num : usize, count : usize) {
+            self.prefetched.lock().unwrap().push((page_num, count));
+            self.inner.prefetch(page_num, count);
+        }
+    }
+
+    #[test]
+    fn read_ahead() {
+        use std::sync::{Arc, Mutex};
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        {
+            let mut table = Table::db_open(file_path.clone()).unwrap();
+            for i in 0..2000 {
+                let insert_str = format!("insert {} user{} person{}@example.com", i, i, i);
+                statement_command(&insert_str, &mut table, &mut MemorySink::new()).unwrap();
+            }
+            table.close().unwrap();
+        }
+        let prefetched = Arc::new(Mutex::new(vec![]));
+        let storage = PrefetchLog {
+            inner : FileStorage::open(file_path).unwrap(),
+            prefetched : prefetched.clone(),
+        };
+        let options = TableOptions { cache_pages : 8, ..TableOptions::default() };
+        let mut table = Table::db_open_storage(Box::new(storage), options).unwrap();
+        let num_pages = table.pager.num_pages;
+        // a point lookup isn't a scan
+        statement_command("explain validate insert 1000 a b", &mut table,
+                          &mut MemorySink::new()).unwrap_err();
+        assert_eq!(*prefetched.lock().unwrap(), vec![]);
+
+        let mut sink = MemorySink::new();
+        statement_command("select", &mut table, &mut sink).unwrap();
+        assert_eq!(sink.rows.len(), 2000);
+        let prefetched = prefetched.lock().unwrap();
+        assert!(prefetched.len() > 1);
+        // every page is asked for at most once and never past the end
+        let mut next = 0;
+        for &(page_num, count) in prefetched.iter() {
+            assert!(page_num >= next && count > 0);
+            next = page_num + count;
+        }
+        assert!(next <= num_pages);
+    }
+
+    #[test]
+    fn cache_stats() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        {
+            let mut table = Table::db_open(file_path.clone()).unwrap();
+            for i in 0..200 {
+                let insert_str = format!("insert {} user{} person{}@example.com", i, i, i);
+                statement_command(&insert_str, &mut table, &mut MemorySink::new()).unwrap();
+            }
+            table.close().unwrap();
+        }
+        let options = TableOptions { cache_pages : 4, ..TableOptions::default() };
+        let mut table = Table::db_open_with(file_path, options).unwrap();
+        let opened = table.cache_stats();
+        assert_eq!(opened.pages_written, 0);
+        statement_command("select", &mut table, &mut MemorySink::new()).unwrap();
+        let first = table.cache_stats();
+        assert!(first.misses > opened.misses);
+        assert_eq!(first.pages_read, first.misses);
+        assert_eq!(first.evictions, first.misses - 4);
+        statement_command("insert 500 user500 person500@example.com",
+                          &mut table, &mut MemorySink::new()).unwrap();
+        table.flush().unwrap();
+        let stats = table.cache_stats();
+        assert!(stats.hits > first.hits);
+        assert!(stats.pages_written >= 2);
+
+        let mut buf = vec![];
+        meta_command(".stats", &mut table, &mut buf).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), format!(
+            "Cache stats:\nhits: {}\nmisses: {}\npages read: {}\npages written: {}\n\
+             evictions: {}\n",
+            stats.hits, stats.misses, stats.pages_read, stats.pages_written, stats.evictions));
+    }
+
+    #[test]
+    fn explain_analyze() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        {
+            let mut table = Table::db_open(file_path.clone()).unwrap();
+            for i in 0..100 {
+                let insert_str = format!("insert {} user{} person{}@example.com", i, i, i);
+                statement_command(&insert_str, &mut table, &mut MemorySink::new()).unwrap();
+            }
+        }
+        let options = TableOptions { cache_pages : 4, ..TableOptions::default() };
+        let mut table = Table::db_open_with(file_path, options).unwrap();
+        assert_eq!(parse("explain analyze delete 1").unwrap(),
+                   Statement::Analyze(Box::new(Statement::Delete(1))));
+        let analyze = |table : &mut Table, statement : &str| {
+            let mut sink = MemorySink::new();
+            statement_command(statement, table, &mut sink).unwrap();
+            assert_eq!(sink.columns, ANALYZE_COLUMNS);
+            assert_eq!(sink.rows.len(), 1);
+            sink.rows.remove(0)
+        };
+        let row = analyze(&mut table, "explain analyze select");
+        assert_eq!(row[..2], [Value::Text("scan".to_string()), Value::Integer(100)]);
+        assert_eq!(row[4], Value::Integer(100 * ROW_SIZE as u32));
+        match row[2] {
+            Value::Integer(pages) => assert!(pages >= 100 / LEAF_NODE_MAX_CELLS as u32),
+            ref other => panic!("expected a page count, got {:?}", other),
+        }
+        // the statement really runs
+        let row = analyze(&mut table, "explain analyze insert 100 user100 person100@example.com");
+        assert_eq!(row[..2], [Value::Text("insert".to_string()), Value::Integer(1)]);
+        assert_eq!(row[4], Value::Integer(ROW_SIZE as u32));
+        let long_insert = format!("explain analyze insert 101 user101 {}", "e".repeat(EMAIL_SIZE + 50));
+        assert_eq!(analyze(&mut table, &long_insert)[4], Value::Integer(ROW_SIZE as u32 + 50));
+        match statement_command("explain analyze delete 500", &mut table, &mut MemorySink::new()) {
+            Err(DbError::NotFound(500)) => (),
+            other => panic!("expected NotFound, got {:?}", other),
+        }
+        let row = analyze(&mut table, "explain analyze delete 100");
+        assert_eq!(row[..2], [Value::Text("delete".to_string()), Value::Integer(1)]);
+
+        let mut log = LogTable::open(tmp_dir.path().join("test1.log")).unwrap();
+        let mut sink = MemorySink::new();
+        statement_command("explain analyze insert 1 user1 person1@example.com",
+                          &mut log, &mut sink).unwrap();
+        assert_eq!(sink.rows[0][..3], [Value::Text("insert".to_string()),
+                                       Value::Integer(1), Value::Integer(0)]);
+    }
+
+    #[test]
+    fn vacuum() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let mut table = Table::db_open(file_path.clone()).unwrap();
+        let stream = table.replication_stream();
+        for i in 0..400 {
+            // every tenth row has an email long enough for an overflow page
+            let email = if i % 10 == 0 { "e".repeat(300) } else { format!("person{}@example.com", i) };
+            let insert_str = format!("insert {} user{} {}", i, i, email);
+            statement_command(&insert_str, &mut table, &mut MemorySink::new()).unwrap();
+        }
+        for i in (0..400).filter(|i| i % 3 != 0 || (100..300).contains(i)) {
+            assert!(table.delete(i).unwrap());
+        }
+        let mut expected = MemorySink::new();
+        statement_command("select", &mut table, &mut expected).unwrap();
+        let pages_before = table.pager.num_pages;
+        assert!(table.pager.free_pages().unwrap() > 0);
+
+        meta_command(".vacuum", &mut table, &mut vec![]).unwrap();
+        assert!(table.pager.num_pages < pages_before);
+        assert_eq!(table.pager.free_pages().unwrap(), 0);
+        assert_eq!(fs::metadata(&file_path).unwrap().len() as usize,
+                   table.pager.num_pages * PAGE_SIZE);
+        assert!(!tmp_dir.path().join("test1.db.vacuum").exists());
+        assert_eq!(table.check_integrity(), Vec::<String>::new());
+        let mut sink = MemorySink::new();
+        statement_command("select", &mut table, &mut sink).unwrap();
+        assert_eq!(sink.rows, expected.rows);
+
+        // the table carries on where it was, lsns included
+        let lsn = stream.try_iter().last().unwrap().lsn;
+        statement_command("insert 1000 user1000 person1000@example.com",
+                          &mut table, &mut MemorySink::new()).unwrap();
+        assert_eq!(stream.try_recv().unwrap().lsn, lsn + 1);
+        table.close().unwrap();
+        let mut table = Table::db_open(file_path).unwrap();
+        assert_eq!(table.check_integrity(), Vec::<String>::new());
+        let mut sink = MemorySink::new();
+        statement_command("select", &mut table, &mut sink).unwrap();
+        assert_eq!(sink.rows.len(), expected.rows.len() + 1);
+
+        let mut memory = Table::db_open(PathBuf::from(MEMORY_DB)).unwrap();
+        assert!(memory.vacuum().is_err());
+    }
+
+    #[test]
+    fn schema_reflection() {
+        let table = Table::db_open(PathBuf::from(MEMORY_DB)).unwrap();
+        let columns = table.columns();
+        let names : Vec<&str> = columns.iter().map(|c| c.name).collect();
+        assert_eq!(names, COLUMNS);
+        assert_eq!(columns[0], ColumnInfo { name : "id", column_type : ColumnType::Integer,
+                                            primary_key : true, max_len : None });
+        for column in &columns[1..] {
+            assert_eq!(column.column_type, ColumnType::Text);
+            assert!(!column.primary_key);
+            assert_eq!(column.max_len, Some(MAX_VALUE_SIZE));
+        }
+    }
+
+    #[test]
+    fn salvage() {
+        use std::io::{Seek, SeekFrom};
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let salvage_path = tmp_dir.path().join("salvaged.db");
+        let (lost, leaf_page_num) = {
+            let mut table = Table::db_open(file_path.clone()).unwrap();
+            for i in 0..300 {
+                let email = if i % 10 == 0 { "e".repeat(300) } else { format!("person{}@example.com", i) };
+                let insert_str = format!("insert {} user{} {}", i, i, email);
+                statement_command(&insert_str, &mut table, &mut MemorySink::new()).unwrap();
+            }
+            let root_page_num = table.root_page_num;
+            let leaf_page_num = table.leftmost_leaf(root_page_num).unwrap();
+            let lost = node::leaf_num_cells(table.pager.get(leaf_page_num).unwrap());
+            table.close().unwrap();
+            (lost, leaf_page_num)
+        };
+        // the root and header go along with a leaf, so there's no tree left
+        let mut file = OpenOptions::new().write(true).open(&file_path).unwrap();
+        for &page_num in &[0, 1, leaf_page_num] {
+            file.seek(SeekFrom::Start((page_num * PAGE_SIZE + 100) as u64)).unwrap();
+            file.write_all(b"garbage").unwrap();
+        }
+        drop(file);
+        assert!(Table::db_open(file_path.clone()).is_err());
+
+        let report = Table::salvage(file_path.clone(), salvage_path.clone()).unwrap();
+        assert_eq!(report, SalvageReport { rows : 300 - lost, bad_pages : 2, bad_rows : 0 });
+        let mut table = Table::db_open(salvage_path.clone()).unwrap();
+        assert_eq!(table.check_integrity(), Vec::<String>::new());
+        let mut sink = MemorySink::new();
+        statement_command("select", &mut table, &mut sink).unwrap();
+        assert_eq!(sink.rows.len(), 300 - lost);
+        assert_eq!(sink.rows[0][0], Value::Integer(lost as u32));
+        assert_eq!(sink.rows[0][2], Value::Text(format!("person{}@example.com", lost)));
+        assert!(Table::salvage(file_path, salvage_path).is_err());
+    }
+
+    #[test]
+    fn row_count() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        {
+            let mut table = Table::db_open(file_path.clone()).unwrap();
+            for i in 0..50 {
+                let insert_str = format!("insert {} user{} person{}@example.com", i, i, i);
+                statement_command(&insert_str, &mut table, &mut MemorySink::new()).unwrap();
+            }
+            // neither a duplicate nor a missing id changes the count
+            assert!(statement_command("insert 1 user1 person1@example.com",
+                                      &mut table, &mut MemorySink::new()).is_err());
+            assert!(!table.delete(100).unwrap());
+            for i in 0..10 {
+                table.delete(i).unwrap();
+            }
+            assert_eq!(table.num_rows().unwrap(), 40);
+        }
+        let mut table = Table::db_open(file_path).unwrap();
+        assert_eq!(table.num_rows().unwrap(), 40);
+        assert_eq!(table.check_integrity(), Vec::<String>::new());
+        header::set_row_count(table.pager.get_mut(0).unwrap(), 41);
+        assert_eq!(table.check_integrity(),
+                   vec!["page 0: row count is 41, the leaves hold 40".to_string()]);
+    }
+
+    #[test]
+    fn file_locking() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let read_only = TableOptions { read_only : true, ..TableOptions::default() };
+        let expect_locked = |result : Result<Table, DbError>| match result {
+            Err(DbError::Locked) => (),
+            other => panic!("expected Locked, got {:?}", other.err()),
+        };
+        {
+            let _table = Table::db_open(file_path.clone()).unwrap();
+            expect_locked(Table::db_open(file_path.clone()));
+            expect_locked(Table::db_open_with(file_path.clone(), read_only.clone()));
+        }
+        {
+            // readers share the file, but keep writers out
+            let _first = Table::db_open_with(file_path.clone(), read_only.clone()).unwrap();
+            let _second = Table::db_open_with(file_path.clone(), read_only.clone()).unwrap();
+            expect_locked(Table::db_open(file_path.clone()));
+        }
+        assert!(Table::db_open(file_path).is_ok());
+    }
+
+    #[test]
+    fn undo() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        {
+            let mut table = Table::db_open(file_path.clone()).unwrap();
+            statement_command("insert 1 user1 person1@example.com",
+                              &mut table, &mut MemorySink::new()).unwrap();
+        }
+        let mut table = Table::db_open(file_path).unwrap();
+        let stream = table.replication_stream();
+        let select = |table : &mut Table| {
+            let mut sink = MemorySink::new();
+            statement_command("select", table, &mut sink).unwrap();
+            sink.rows.iter().map(|row| row[0].clone()).collect::<Vec<Value>>()
+        };
+        statement_command("insert 2 user2 person2@example.com",
+                          &mut table, &mut MemorySink::new()).unwrap();
+        assert!(table.delete(1).unwrap());
+        assert_eq!(select(&mut table), vec![Value::Integer(2)]);
+
+        meta_command(".undo", &mut table, &mut vec![]).unwrap();
+        assert_eq!(select(&mut table), vec![Value::Integer(1), Value::Integer(2)]);
+        assert!(table.undo().unwrap());
+        assert_eq!(select(&mut table), vec![Value::Integer(1)]);
+        // changes from before the table was opened stay
+        let mut buf = vec![];
+        meta_command(".undo", &mut table, &mut buf).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), "Nothing to undo\n");
+        assert_eq!(select(&mut table), vec![Value::Integer(1)]);
+        assert_eq!(table.num_rows().unwrap(), 1);
+        assert_eq!(table.check_integrity(), Vec::<String>::new());
+        // undoing is a change like any other for replicas
+        let changes : Vec<Change> = stream.try_iter().map(|event| event.change).collect();
+        assert_eq!(changes.len(), 4);
+        assert_eq!(changes[3], Change::Delete(Row { id : 2, user_id : "user2".to_string(),
+                                                    email : "person2@example.com".to_string() }));
+
+        // a replace is one change, undoing it brings back the old row
+        statement_command("insert or replace 1 again1 again1@example.com",
+                          &mut table, &mut MemorySink::new()).unwrap();
+        let old = Row { id : 1, user_id : "user1".to_string(),
+                        email : "person1@example.com".to_string() };
+        let new = Row { id : 1, user_id : "again1".to_string(),
+                        email : "again1@example.com".to_string() };
+        assert!(table.undo().unwrap());
+        assert_eq!(Engine::get(&mut table, 1).unwrap(), Some(old.clone()));
+        assert_eq!(table.num_rows().unwrap(), 1);
+        let changes : Vec<Change> = stream.try_iter().map(|event| event.change).collect();
+        assert_eq!(changes, vec![Change::Replace { old : old.clone(), new : new.clone() },
+                                 Change::Replace { old : new, new : old }]);
+        assert!(!table.undo().unwrap());
+    }
+
+    #[test]
+    fn seal() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let sealed_path = tmp_dir.path().join("sealed.db");
+        let mut table = Table::db_open(file_path).unwrap();
+        for i in 0..300 {
+            let insert_str = format!("insert {} user{} person{}@example.com", i, i, i);
+            statement_command(&insert_str, &mut table, &mut MemorySink::new()).unwrap();
+        }
+        for i in 0..100 {
+            table.delete(i).unwrap();
+        }
+        table.seal(sealed_path.clone()).unwrap();
+        assert!(table.seal(sealed_path.clone()).is_err());
+        let mut expected = MemorySink::new();
+        statement_command("select", &mut table, &mut expected).unwrap();
+
+        let mut sealed = Table::db_open(sealed_path.clone()).unwrap();
+        assert!(sealed.pager.num_pages < table.pager.num_pages);
+        assert_eq!(sealed.pager.free_pages().unwrap(), 0);
+        assert_eq!(sealed.check_integrity(), Vec::<String>::new());
+        let mut sink = MemorySink::new();
+        statement_command("select", &mut sealed, &mut sink).unwrap();
+        assert_eq!(sink.rows, expected.rows);
+        for statement in &["insert 1000 a b", "delete 200"] {
+            match statement_command(statement, &mut sealed, &mut MemorySink::new()) {
+                Err(DbError::ReadOnly) => (),
+                other => panic!("expected ReadOnly, got {:?}", other),
+            }
+        }
+        // served from the mapping, the cache is never filled
+        if cfg!(unix) {
+            assert!(sealed.pager.num_cached() <= 2);
+        }
+        drop(sealed);
+        let mut embedded = file_snapshot(&sealed_path);
+        assert_eq!(embedded.num_rows().unwrap(), 200);
+        // a clone can be written to again
+        let clone_path = tmp_dir.path().join("clone.db");
+        embedded.clone_to(clone_path.clone()).unwrap();
+        let mut clone = Table::db_open(clone_path).unwrap();
+        statement_command("insert 1000 a b", &mut clone, &mut MemorySink::new()).unwrap();
+    }
+
+    #[test]
+    fn text_policy() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let mut table = Table::db_open(file_path.clone()).unwrap();
+        statement_command("insert 1 user1 person1@example.com", &mut table, &mut MemorySink::new()).unwrap();
+        table.close().unwrap();
+        // another program wrote something that isn't UTF-8 over the email
+        let mut bytes = std::fs::read(&file_path).unwrap();
+        {
+            let page = &mut bytes[PAGE_SIZE..2 * PAGE_SIZE];
+            let at = page.windows(7).position(|w| w == b"person1").unwrap();
+            page[at] = 0xff;
+            pager::set_checksum(page);
+        }
+        std::fs::write(&file_path, &bytes).unwrap();
+
+        let mut table = Table::db_open(file_path.clone()).unwrap();
+        match statement_command("select", &mut table, &mut MemorySink::new()) {
+            Err(DbError::Corrupt(_)) => (),
+            other => panic!("expected Corrupt, got {:?}", other),
+        }
+        table.close().unwrap();
+        let options = TableOptions { text_policy : TextPolicy::Lossy, ..TableOptions::default() };
+        let mut table = Table::db_open_with(file_path, options).unwrap();
+        let mut sink = MemorySink::new();
+        statement_command("select", &mut table, &mut sink).unwrap();
+        assert_eq!(sink.rows[0][2], Value::Text("\u{fffd}erson1@example.com".to_string()));
+    }
+
+    #[test]
+    fn snapshot() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let options = TableOptions { cache_pages : 8, ..TableOptions::default() };
+        let mut table = Table::db_open_with(file_path, options).unwrap();
+        for i in 0..200 {
+            let insert_str = format!("insert {} user{} person{}@example.com", i, i, i);
+            statement_command(&insert_str, &mut table, &mut MemorySink::new()).unwrap();
+        }
+        let mut snapshot = table.snapshot().unwrap();
+        // splits, merges, reused free pages and evictions all
+        // write over pages the snapshot still reads
+        for i in 0..150 {
+            assert!(table.delete(i).unwrap());
+        }
+        for i in 1000..1300 {
+            let insert_str = format!("insert {} user{} {}", i, i, "e".repeat(200));
+            statement_command(&insert_str, &mut table, &mut MemorySink::new()).unwrap();
+        }
+        table.flush().unwrap();
+        assert!(Engine::insert(&mut snapshot, &Row {
+            id : 5000, user_id : "a".to_string(), email : "b".to_string() }).is_err());
+        table.close().unwrap();
+
+        assert_eq!(snapshot.check_integrity(), Vec::<String>::new());
+        assert_eq!(snapshot.num_rows().unwrap(), 200);
+        let mut sink = MemorySink::new();
+        statement_command("select", &mut snapshot, &mut sink).unwrap();
+        let ids : Vec<_> = sink.rows.iter().map(|row| row[0].clone()).collect();
+        assert_eq!(ids, (0..200).map(Value::Integer).collect::<Vec<_>>());
+        assert_eq!(sink.rows[7][2], Value::Text("person7@example.com".to_string()));
+    }
+
+    #[test]
+    fn select_where_id() {
+        assert_eq!(parse("select where id = 5").unwrap(), Statement::SelectId(5));
+        assert_eq!(parse("select where id=5").unwrap(), Statement::SelectId(5));
+        match parse("select where email = 5") {
+            Err(DbError::StatementSyntaxError) => (),
+            other => panic!("expected syntax error, got {:?}", other),
+        }
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let mut table = Table::db_open(tmp_dir.path().join("test1.db")).unwrap();
+        let mut log = LogTable::open(tmp_dir.path().join("test1.log")).unwrap();
+        let mut lsm = LsmTable::open(tmp_dir.path().join("lsm")).unwrap();
+        let engines : [&mut dyn Engine; 3] = [&mut table, &mut log, &mut lsm];
+        for engine in engines {
+            for i in 0..100 {
+                let insert_str = format!("insert {} user{} person{}@example.com", i, i, i);
+                statement_command(&insert_str, engine, &mut MemorySink::new()).unwrap();
+            }
+            let mut sink = MemorySink::new();
+            statement_command("select where id = 42", engine, &mut sink).unwrap();
+            assert_eq!(sink.rows, vec![vec![Value::Integer(42), Value::Text("user42".to_string()),
+                                            Value::Text("person42@example.com".to_string())]]);
+            let mut sink = MemorySink::new();
+            statement_command("select where id = 100", engine, &mut sink).unwrap();
+            assert!(sink.rows.is_empty());
+        }
+    }
+
+    #[test]
+    fn create_database_as() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let catalog = Catalog::open(tmp_dir.path().join("dbs")).unwrap();
+        let mut source = catalog.create_database("users").unwrap();
+        for i in 0..50 {
+            let insert_str = format!("insert {} user{} person{}@example.com", i, i, i);
+            statement_command(&insert_str, &mut source, &mut MemorySink::new()).unwrap();
+        }
+        let mut copy = catalog.create_database_as("copy", &mut source, &Statement::Select).unwrap();
+        assert_eq!(copy.num_rows().unwrap(), 50);
+        let mut one = catalog.create_database_as("one", &mut source,
+                                                 &parse("select where id = 7").unwrap()).unwrap();
+        let mut sink = MemorySink::new();
+        statement_command("select", &mut one, &mut sink).unwrap();
+        assert_eq!(sink.rows.len(), 1);
+        assert_eq!(sink.rows[0][1], Value::Text("user7".to_string()));
+        let insert = parse("insert 7 user7 person7@example.com").unwrap();
+        match catalog.create_database_as("gone", &mut source, &insert) {
+            Err(DbError::StatementSyntaxError) => (),
+            other => panic!("expected syntax error, got {:?}", other.err()),
+        }
+
+        let statement = parse("create table top as select order by email desc limit 3").unwrap();
+        assert_eq!(statement, Statement::CreateTableAs("top".to_string(), Box::new(
+            Statement::Limited(Box::new(Statement::Sorted(Box::new(Statement::Select),
+                OrderBy { column : 2, descending : true })), 3, 0))));
+        let mut top = catalog.create_table_as(&statement, &mut source).unwrap();
+        let mut sink = MemorySink::new();
+        statement_command("select", &mut top, &mut sink).unwrap();
+        let ids : Vec<Value> = sink.rows.iter().map(|row| row[0].clone()).collect();
+        assert_eq!(ids, vec![Value::Integer(7), Value::Integer(8), Value::Integer(9)]);
+        // every database has all of the columns
+        for input in ["create table names as select user_id",
+                      "create table names as select distinct user_id"] {
+            match catalog.create_table_as(&parse(input).unwrap(), &mut source) {
+                Err(DbError::StatementSyntaxError) => (),
+                other => panic!("expected syntax error, got {:?}", other.err()),
+            }
+        }
+        for input in ["create table top2", "create table top2 as delete 1",
+                      "create table top2 select"] {
+            match parse(input) {
+                Err(DbError::StatementSyntaxError) => (),
+                other => panic!("expected syntax error, got {:?}", other),
+            }
+        }
+        // the new database needs somewhere to go
+        match statement_command("create table top2 as select", &mut source, &mut MemorySink::new()) {
+            Err(DbError::StatementUnrecognized) => (),
+            other => panic!("expected StatementUnrecognized, got {:?}", other),
+        }
+        catalog.statement_command("create table top2 as select where id < 2",
+                                  &mut source, &mut MemorySink::new()).unwrap();
+        let mut buf : Vec<u8> = vec![];
+        match catalog.statement_command("create table names as select user_id",
+                                        &mut source, &mut TextSink::new(&mut buf)) {
+            Err(DbError::StatementSyntaxError) => (),
+            other => panic!("expected syntax error, got {:?}", other),
+        }
+        assert_eq!(String::from_utf8(buf).unwrap(), "Statement has syntax error\n");
+        let mut sink = MemorySink::new();
+        catalog.statement_command("select", &mut source, &mut sink).unwrap();
+        assert_eq!(sink.rows.len(), 50);
+        assert_eq!(catalog.open_database("top2").unwrap().num_rows().unwrap(), 2);
+        assert_eq!(catalog.list_databases().unwrap(), vec!["copy", "one", "top", "top2", "users"]);
+        assert_eq!(source.num_rows().unwrap(), 50);
+    }
+
+    #[test]
+    fn select_range() {
+        assert_eq!(parse("select where id between 10 and 20").unwrap(),
+                   Statement::SelectRange(10, 20));
+        assert_eq!(parse("select where id > 10").unwrap(), Statement::SelectRange(11, u32::MAX));
+        assert_eq!(parse("select where id>=10").unwrap(), Statement::SelectRange(10, u32::MAX));
+        assert_eq!(parse("select where id < 10").unwrap(), Statement::SelectRange(0, 9));
+        assert_eq!(parse("select where id <= 10").unwrap(), Statement::SelectRange(0, 10));
+        assert_eq!(parse("select where id < 0").unwrap(), Statement::SelectRange(1, 0));
+        match parse("select where id between 10") {
+            Err(DbError::StatementSyntaxError) => (),
+            other => panic!("expected syntax error, got {:?}", other),
+        }
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let mut table = Table::db_open(file_path.clone()).unwrap();
+        let mut log = LogTable::open(tmp_dir.path().join("test1.log")).unwrap();
+        let engines : [&mut dyn Engine; 2] = [&mut table, &mut log];
+        for engine in engines {
+            for i in 0..1000 {
+                let insert_str = format!("insert {} user{} person{}@example.com", i * 2, i, i);
+                statement_command(&insert_str, engine, &mut MemorySink::new()).unwrap();
+            }
+            let ids = |engine : &mut dyn Engine, input : &str| {
+                let mut sink = MemorySink::new();
+                statement_command(input, engine, &mut sink).unwrap();
+                sink.rows.iter().map(|row| row[0].clone()).collect::<Vec<_>>()
+            };
+            assert_eq!(ids(engine, "select where id between 11 and 20"),
+                       (12..=20).step_by(2).map(Value::Integer).collect::<Vec<_>>());
+            assert_eq!(ids(engine, "select where id > 1994"),
+                       vec![Value::Integer(1996), Value::Integer(1998)]);
+            assert_eq!(ids(engine, "select where id <= 2"), vec![Value::Integer(0), Value::Integer(2)]);
+            assert!(ids(engine, "select where id between 20 and 10").is_empty());
+        }
+        table.close().unwrap();
+
+        // stops at the upper bound instead of reading every leaf
+        let mut table = Table::db_open(file_path).unwrap();
+        let mut sink = MemorySink::new();
+        statement_command("explain analyze select where id between 10 and 20",
+                          &mut table, &mut sink).unwrap();
+        assert_eq!(sink.rows[0][0], Value::Text("range".to_string()));
+        assert_eq!(sink.rows[0][1], Value::Integer(6));
+        let range_pages = match sink.rows[0][2] { Value::Integer(n) => n, _ => unreachable!() };
+        let mut sink = MemorySink::new();
+        statement_command("explain analyze select", &mut table, &mut sink).unwrap();
+        let scan_pages = match sink.rows[0][2] { Value::Integer(n) => n, _ => unreachable!() };
+        assert!(range_pages * 4 < scan_pages, "{} {}", range_pages, scan_pages);
+    }
+
+    #[test]
+    fn preallocate() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let options = TableOptions { preallocate : 100, ..TableOptions::default() };
+        let mut table = Table::db_open_with(file_path.clone(), options.clone()).unwrap();
+        table.flush().unwrap();
+        let size = fs::metadata(&file_path).unwrap().len();
+        assert_eq!(size, 102 * PAGE_SIZE as u64);
+        for i in 0..300 {
+            let insert_str = format!("insert {} user{} person{}@example.com", i, i, i);
+            statement_command(&insert_str, &mut table, &mut MemorySink::new()).unwrap();
+        }
+        table.close().unwrap();
+        // the rows fit in what was set aside
+        assert_eq!(fs::metadata(&file_path).unwrap().len(), size);
+        let mut table = Table::db_open_with(file_path, options).unwrap();
+        assert_eq!(table.check_integrity(), Vec::<String>::new());
+        let free_pages = table.pager.free_pages().unwrap();
+        assert!(free_pages > 0 && free_pages < 100);
+
+        let options = TableOptions {
+            preallocate : 100,
+            max_pages : Some(50),
+            ..TableOptions::default()
+        };
+        match Table::db_open_with(tmp_dir.path().join("test2.db"), options) {
+            Err(DbError::TableFull) => (),
+            other => panic!("expected TableFull, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn delete_statement() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let mut table = Table::db_open(file_path.clone()).unwrap();
+        for i in 0..3 {
+            let insert_str = format!("insert {} user{} person{}@example.com", i, i, i);
+            statement_command(&insert_str, &mut table, &mut MemorySink::new()).unwrap();
+        }
+        statement_command("delete 1", &mut table, &mut MemorySink::new()).unwrap();
+        for input in &["delete 1", "explain validate delete 1"] {
+            let mut buf : Vec<u8> = vec![];
+            match statement_command(input, &mut table, &mut TextSink::new(&mut buf)) {
+                Err(DbError::NotFound(1)) => (),
+                other => panic!("expected NotFound, got {:?}", other),
+            }
+            assert_eq!(String::from_utf8(buf).unwrap(), "No row with id 1\n");
+        }
+        table.close().unwrap();
+        let mut table = Table::db_open(file_path).unwrap();
+        assert_eq!(table.num_rows().unwrap(), 2);
+        assert_eq!(table.check_integrity(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn history() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let started = SystemTime::now() - Duration::from_secs(1);
+        let mut table = Table::db_open(file_path.clone()).unwrap();
+        assert_eq!(table.history().unwrap(),
+                   History { rows_inserted : 0, vacuums : 0, last_checkpoint : None });
+        for i in 0..10 {
+            let insert_str = format!("insert {} user{} person{}@example.com", i, i, i);
+            statement_command(&insert_str, &mut table, &mut MemorySink::new()).unwrap();
+        }
+        statement_command("delete 3", &mut table, &mut MemorySink::new()).unwrap();
+        table.close().unwrap();
+
+        let mut table = Table::db_open(file_path.clone()).unwrap();
+        let history = table.history().unwrap();
+        assert_eq!((history.rows_inserted, history.vacuums), (10, 0));
+        assert!(history.last_checkpoint.unwrap() >= started);
+        table.vacuum().unwrap();
+        table.close().unwrap();
+
+        let mut table = Table::db_open(file_path).unwrap();
+        let history = table.history().unwrap();
+        assert_eq!((history.rows_inserted, history.vacuums), (10, 1));
+        let mut buf : Vec<u8> = vec![];
+        meta_command(".dbinfo", &mut table, &mut buf).unwrap();
+        let info = String::from_utf8(buf).unwrap();
+        assert!(info.contains("rows inserted: 10\nvacuums: 1\nlast checkpoint: "), "{}", info);
+    }
+
+    #[test]
+    fn insert_or_replace() {
+        assert_eq!(parse("insert or replace 1 user1 person1@example.com").unwrap(),
+                   Statement::InsertOrReplace(Row {
+                       id : 1,
+                       user_id : String::from("user1"),
+                       email : String::from("person1@example.com"),
+                   }));
+        match parse("insert or replace 1 user1") {
+            Err(DbError::StatementSyntaxError) => (),
+            other => panic!("expected syntax error, got {:?}", other),
+        }
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let mut table = Table::db_open(tmp_dir.path().join("test1.db")).unwrap();
+        let mut log = LogTable::open(tmp_dir.path().join("test1.log")).unwrap();
+        let engines : [&mut dyn Engine; 2] = [&mut table, &mut log];
+        for engine in engines {
+            statement_command("insert 1 user1 person1@example.com", engine, &mut MemorySink::new()).unwrap();
+            match statement_command("insert 1 user2 person2@example.com", engine, &mut MemorySink::new()) {
+                Err(DbError::DuplicateKey) => (),
+                other => panic!("expected DuplicateKey, got {:?}", other),
+            }
+            statement_command("insert or replace 1 user2 person2@example.com",
+                              engine, &mut MemorySink::new()).unwrap();
+            statement_command("insert or replace 2 user3 person3@example.com",
+                              engine, &mut MemorySink::new()).unwrap();
+            let mut sink = MemorySink::new();
+            statement_command("select", engine, &mut sink).unwrap();
+            assert_eq!(sink.rows, vec![
+                vec![Value::Integer(1), Value::Text("user2".to_string()),
+                     Value::Text("person2@example.com".to_string())],
+                vec![Value::Integer(2), Value::Text("user3".to_string()),
+                     Value::Text("person3@example.com".to_string())],
+            ]);
+        }
+        assert_eq!(table.num_rows().unwrap(), 2);
+    }
+
+    #[test]
+    fn order_by() {
+        assert_eq!(parse("select order by email desc").unwrap(),
+                   Statement::Sorted(Box::new(Statement::Select),
+                                     OrderBy { column : 2, descending : true }));
+        assert_eq!(parse("select where id > 5 order by user_id").unwrap(),
+                   Statement::Sorted(Box::new(Statement::SelectRange(6, u32::MAX)),
+                                     OrderBy { column : 1, descending : false }));
+        for input in &["select order by name", "select order by email up", "select order by"] {
+            match parse(input) {
+                Err(DbError::StatementSyntaxError) => (),
+                other => panic!("expected syntax error for {}, got {:?}", input, other),
+            }
+        }
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let mut table = Table::db_open(tmp_dir.path().join("test1.db")).unwrap();
+        for &(id, user_id, email) in &[(1, "carol", "c@example.com"), (2, "alice", "b@example.com"),
+                                       (3, "bob", "a@example.com"), (4, "alice", "d@example.com")] {
+            let insert_str = format!("insert {} {} {}", id, user_id, email);
+            statement_command(&insert_str, &mut table, &mut MemorySink::new()).unwrap();
+        }
+        let ids = |table : &mut Table, input : &str| {
+            let mut sink = MemorySink::new();
+            statement_command(input, table, &mut sink).unwrap();
+            sink.rows.iter().map(|row| row[0].clone()).collect::<Vec<_>>()
+        };
+        let expect = |ids : &[u32]| ids.iter().cloned().map(Value::Integer).collect::<Vec<_>>();
+        assert_eq!(ids(&mut table, "select order by user_id"), expect(&[2, 4, 3, 1]));
+        assert_eq!(ids(&mut table, "select order by email desc"), expect(&[4, 1, 2, 3]));
+        assert_eq!(ids(&mut table, "select order by id desc"), expect(&[4, 3, 2, 1]));
+        assert_eq!(ids(&mut table, "select where id <= 3 order by email"), expect(&[3, 2, 1]));
+    }
+
+    #[test]
+    fn limit_offset() {
+        assert_eq!(parse("select limit 10 offset 20").unwrap(),
+                   Statement::Limited(Box::new(Statement::Select), 10, 20));
+        assert_eq!(parse("select where id > 5 order by email desc limit 3").unwrap(),
+                   Statement::Limited(Box::new(Statement::Sorted(
+                       Box::new(Statement::SelectRange(6, u32::MAX)),
+                       OrderBy { column : 2, descending : true })), 3, 0));
+        for input in &["select limit", "select limit ten", "select limit 1 offset", "select limit 1 2"] {
+            match parse(input) {
+                Err(DbError::StatementSyntaxError) | Err(DbError::ParsingError(_)) => (),
+                other => panic!("expected an error for {}, got {:?}", input, other),
+            }
+        }
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let mut table = Table::db_open(file_path.clone()).unwrap();
+        let mut log = LogTable::open(tmp_dir.path().join("test1.log")).unwrap();
+        let engines : [&mut dyn Engine; 2] = [&mut table, &mut log];
+        for engine in engines {
+            for i in 0..1000 {
+                let insert_str = format!("insert {} user{} person{}@example.com", i, i, i);
+                statement_command(&insert_str, engine, &mut MemorySink::new()).unwrap();
+            }
+            let ids = |engine : &mut dyn Engine, input : &str| {
+                let mut sink = MemorySink::new();
+                statement_command(input, engine, &mut sink).unwrap();
+                sink.rows.iter().map(|row| row[0].clone()).collect::<Vec<_>>()
+            };
+            let expect = |ids : std::ops::Range<u32>| ids.map(Value::Integer).collect::<Vec<_>>();
+            assert_eq!(ids(engine, "select limit 3"), expect(0..3));
+            assert_eq!(ids(engine, "select limit 3 offset 20"), expect(20..23));
+            assert_eq!(ids(engine, "select where id >= 990 limit 5 offset 8"), expect(998..1000));
+            assert_eq!(ids(engine, "select where id = 7 limit 1 offset 1"), expect(0..0));
+            assert_eq!(ids(engine, "select order by id desc limit 2"),
+                       vec![Value::Integer(999), Value::Integer(998)]);
+        }
+        table.close().unwrap();
+
+        // the scan stops at the limit
+        let mut table = Table::db_open(file_path).unwrap();
+        let pages_read = |table : &mut Table, input : &str| {
+            let mut sink = MemorySink::new();
+            statement_command(input, table, &mut sink).unwrap();
+            match sink.rows[0][2] { Value::Integer(n) => n, _ => unreachable!() }
+        };
+        let limited = pages_read(&mut table, "explain analyze select limit 5");
+        let scan = pages_read(&mut table, "explain analyze select");
+        assert!(limited * 4 < scan, "{} {}", limited, scan);
+    }
+
+    #[test]
+    fn projection() {
+        assert_eq!(parse("select id, email").unwrap(),
+                   Statement::Projected(Box::new(Statement::Select), vec![0, 2]));
+        assert_eq!(parse("select * where id = 1").unwrap(), Statement::SelectId(1));
+        assert_eq!(parse("select email,id where id < 3 limit 1").unwrap(),
+                   Statement::Projected(Box::new(Statement::Limited(
+                       Box::new(Statement::SelectRange(0, 2)), 1, 0)), vec![2, 0]));
+        for input in &["select name", "select id,", "select id email"] {
+            match parse(input) {
+                Err(DbError::StatementSyntaxError) => (),
+                other => panic!("expected syntax error for {}, got {:?}", input, other),
+            }
+        }
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let mut table = Table::db_open(tmp_dir.path().join("test1.db")).unwrap();
+        for i in 0..3 {
+            let insert_str = format!("insert {} user{} person{}@example.com", i, i, i);
+            statement_command(&insert_str, &mut table, &mut MemorySink::new()).unwrap();
+        }
+        let mut buf : Vec<u8> = vec![];
+        statement_command("select email, id where id >= 1 order by id desc", &mut table,
+                          &mut TextSink::new(&mut buf)).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(),
+                   "(person2@example.com, 2)\n(person1@example.com, 1)\n");
+        let mut buf : Vec<u8> = vec![];
+        statement_command("select user_id limit 2", &mut table, &mut CsvSink::new(&mut buf)).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), "user_id\r\nuser0\r\nuser1\r\n");
+    }
+
+    #[test]
+    fn extent_growth() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let options = TableOptions { extent_pages : 16, ..TableOptions::default() };
+        let mut table = Table::db_open_with(file_path.clone(), options).unwrap();
+        for i in 0..500 {
+            let insert_str = format!("insert {} user{} person{}@example.com", i, i, i);
+            statement_command(&insert_str, &mut table, &mut MemorySink::new()).unwrap();
+            // the header and root, then whole extents
+            assert_eq!((table.pager.num_pages - 2) % 16, 0);
+        }
+        assert!(table.pager.num_pages > 2);
+        table.close().unwrap();
+        let mut table = Table::db_open(file_path).unwrap();
+        assert_eq!(table.check_integrity(), Vec::<String>::new());
+        assert_eq!(table.num_rows().unwrap(), 500);
+
+        // the last extent is cut short by max_pages
+        let options = TableOptions {
+            extent_pages : 16,
+            max_pages : Some(20),
+            ..TableOptions::default()
+        };
+        let mut table = Table::db_open_with(tmp_dir.path().join("test2.db"), options).unwrap();
+        for i in 0.. {
+            let insert_str = format!("insert {} user{} person{}@example.com", i, i, i);
+            match statement_command(&insert_str, &mut table, &mut MemorySink::new()) {
+                Ok(()) => assert!(table.pager.num_pages <= 20),
+                Err(DbError::TableFull) => break,
+                Err(err) => panic!("unexpected error {}", err),
+            }
+        }
+        assert_eq!(table.pager.num_pages, 20);
+        assert_eq!(table.check_integrity(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn select_distinct() {
+        assert_eq!(parse("select distinct user_id limit 2 offset 1").unwrap(),
+                   Statement::Distinct(Box::new(Statement::Projected(
+                       Box::new(Statement::Select), vec![1])), Some((2, 1))));
+        match parse("select distinctuser_id") {
+            Err(DbError::StatementSyntaxError) => (),
+            other => panic!("expected syntax error, got {:?}", other),
+        }
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let mut table = Table::db_open(tmp_dir.path().join("test1.db")).unwrap();
+        for i in 0..20 {
+            let insert_str = format!("insert {} user{} person{}@example.com", i, i % 4, i);
+            statement_command(&insert_str, &mut table, &mut MemorySink::new()).unwrap();
+        }
+        let users = |table : &mut Table, input : &str| {
+            let mut sink = MemorySink::new();
+            statement_command(input, table, &mut sink).unwrap();
+            sink.rows.iter().map(|row| row[0].to_string()).collect::<Vec<_>>()
+        };
+        assert_eq!(users(&mut table, "select distinct user_id"),
+                   vec!["user0", "user1", "user2", "user3"]);
+        assert_eq!(users(&mut table, "select distinct user_id order by user_id desc limit 2 offset 1"),
+                   vec!["user2", "user1"]);
+        assert_eq!(users(&mut table, "select distinct user_id where id >= 10 limit 3"),
+                   vec!["user2", "user3", "user0"]);
+        assert_eq!(users(&mut table, "select distinct").len(), 20);
+    }
+
+    #[test]
+    fn verify_on_open() {
+        use std::io::{Seek, SeekFrom};
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let options = TableOptions { verify_on_open : true, ..TableOptions::default() };
+        let mut table = Table::db_open_with(file_path.clone(), options.clone()).unwrap();
+        for i in 0..300 {
+            let insert_str = format!("insert {} user{} person{}@example.com", i, i, i);
+            statement_command(&insert_str, &mut table, &mut MemorySink::new()).unwrap();
+        }
+        table.close().unwrap();
+        let mut table = Table::db_open_with(file_path.clone(), options.clone()).unwrap();
+        assert_eq!(table.quick_check(), None);
+        let num_pages = table.pager.num_pages;
+        drop(table);
+
+        // a page in the middle that nothing on the way to the edges reads
+        let mut file = OpenOptions::new().write(true).open(&file_path).unwrap();
+        file.seek(SeekFrom::Start((num_pages / 2 * PAGE_SIZE + 100) as u64)).unwrap();
+        file.write_all(b"garbage").unwrap();
+        drop(file);
+        Table::db_open(file_path.clone()).unwrap().close().unwrap();
+        match Table::db_open_with(file_path, options) {
+            Err(DbError::Corrupt(problem)) =>
+                assert_eq!(problem, format!("page {}: checksum mismatch", num_pages / 2)),
+            other => panic!("expected Corrupt, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn shared_table() {
+        let tmp_dir = TempDir::new("simple-db").unwrap();
+        let file_path = tmp_dir.path().join("test1.db");
+        let shared = SharedTable::new(Table::db_open(file_path.clone()).unwrap());
+        let threads : Vec<_> = (0..4u32).map(|t| {
+            let mut handle = shared.clone();
+            std::thread::spawn(move || {
+                for i in 0..50 {
+                    let id = t * 50 + i;
+                    let insert_str = format!("insert {} user{} person{}@example.com", id, id, id);
+                    statement_command(&insert_str, &mut handle, &mut MemorySink::new()).unwrap();
+                }
+                handle.close().unwrap();
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let mut reader = shared.clone();
+        let mut sink = MemorySink::new();
+        statement_command("select", &mut reader, &mut sink).unwrap();
+        assert_eq!(sink.rows.len(), 200);
+        assert!(Engine::delete(&mut shared.clone(), 7).unwrap());
+        assert_eq!(shared.lock().num_rows().unwrap(), 199);
+        assert_eq!(shared.lock().check_integrity(), Vec::<String>::new());
+        // the file stays open, and locked, until the last handle goes
+        reader.close().unwrap();
+        match Table::db_open(file_path.clone()) {
+            Err(DbError::Locked) => (),
+            other => panic!("expected Locked, got {:?}", other.err()),
+        }
+        shared.close().unwrap();
+        let mut table = Table::db_open(file_path).unwrap();
+        assert_eq!(table.num_rows().unwrap(), 199);
     }
 }
\ No newline at end of file
@@ -0,0 +1,323 @@
+// The log engine keeps a table as an append-only log instead of a B-tree.
+// Every insert and delete adds a record to the end of the file and an
+// in-memory index maps each live id to its insert record, rebuilt by
+// replaying the log on open. Once most records are dead the live ones are
+// copied to a fresh log which then replaces the old one.
+//
+// The file is a 16 byte magic and a u32 version, then the records: a kind
+// byte, the id, the user_id and email lengths as u32s, both values and a
+// CRC-32 of everything before it. Delete records have empty values.
+// Replay stops at the first record that is cut short or fails its
+// checksum and drops the rest, that is where a crash left off.
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use node::{read_u32, write_u32};
+use pager::crc32;
+use storage::{sync_dir, sync_file};
+use super::{DbError, Engine, Row, TextPolicy, MAX_VALUE_SIZE};
+
+pub const LOG_MAGIC: &[u8; 16] = b"simple-db log\0\0\0";
+pub const LOG_VERSION: u32 = 1;
+const LOG_HEADER_SIZE: usize = 20;
+
+pub const INSERT: u8 = 1;
+pub const DELETE: u8 = 2;
+const KIND_OFFSET: usize = 0;
+const ID_OFFSET: usize = 1;
+const USERID_LEN_OFFSET: usize = 5;
+const EMAIL_LEN_OFFSET: usize = 9;
+const RECORD_HEADER_SIZE: usize = 13;
+const RECORD_CHECKSUM_SIZE: usize = 4;
+
+// Don't bother compacting until there are at least this many dead records
+const COMPACT_MIN_DEAD: usize = 64;
+
+pub struct LogTable {
+    file : File,
+    path : PathBuf,
+    // id -> offset of its insert record
+    index : BTreeMap<u32, u64>,
+    // end of the last good record, the next one goes here
+    len : u64,
+    // records that don't hold a live row any more
+    dead : usize,
+    text_policy : TextPolicy,
+}
+
+impl LogTable {
+    // Creates the log if the file doesn't exist
+    pub fn open(path : PathBuf) -> Result<LogTable, DbError> {
+        let mut file = OpenOptions::new().read(true)
+                                         .write(true)
+                                         .create(true)
+                                         .truncate(false)
+                                         .open(&path)?;
+        let mut data = vec![];
+        file.read_to_end(&mut data)?;
+        if data.is_empty() {
+            data = log_header();
+            file.write_all(&data)?;
+            sync_dir(&path)?;
+        }
+        if data.len() < LOG_HEADER_SIZE || &data[..LOG_MAGIC.len()] != LOG_MAGIC {
+            return Err(DbError::NotADatabase);
+        }
+        let version = read_u32(&data, LOG_MAGIC.len());
+        if version != LOG_VERSION {
+            return Err(DbError::UnsupportedVersion(version));
+        }
+        let mut table = LogTable {
+            file,
+            path,
+            index : BTreeMap::new(),
+            len : LOG_HEADER_SIZE as u64,
+            dead : 0,
+            text_policy : TextPolicy::default(),
+        };
+        let mut offset = LOG_HEADER_SIZE;
+        while let Some((kind, id, size)) = parse_record(&data[offset..]) {
+            match kind {
+                INSERT => {
+                    if table.index.insert(id, offset as u64).is_some() {
+                        table.dead += 1;
+                    }
+                },
+                _ => {
+                    table.dead += if table.index.remove(&id).is_some() { 2 } else { 1 };
+                },
+            }
+            offset += size;
+        }
+        table.len = offset as u64;
+        if offset < data.len() {
+            table.file.set_len(table.len)?;
+        }
+        Ok(table)
+    }
+
+    pub fn flush(&mut self) -> Result<(), DbError> {
+        sync_file(&self.file)?;
+        Ok(())
+    }
+
+    // Unlike dropping the table this reports a failed fsync
+    pub fn close(mut self) -> Result<(), DbError> {
+        self.flush()
+    }
+
+    // Copy the live records to a new log and swap it in
+    pub fn compact(&mut self) -> Result<(), DbError> {
+        let mut tmp_name = OsString::from(self.path.as_os_str());
+        tmp_name.push(".compact");
+        let tmp_path = PathBuf::from(tmp_name);
+        let mut data = log_header();
+        let mut index = BTreeMap::new();
+        let offsets : Vec<(u32, u64)> = self.index.iter().map(|(&id, &off)| (id, off)).collect();
+        for (id, offset) in offsets {
+            index.insert(id, data.len() as u64);
+            let record = self.read_record(offset)?;
+            data.extend_from_slice(&record);
+        }
+        {
+            let mut tmp = File::create(&tmp_path)?;
+            tmp.write_all(&data)?;
+            sync_file(&tmp)?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+        sync_dir(&self.path)?;
+        self.file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        self.index = index;
+        self.len = data.len() as u64;
+        self.dead = 0;
+        Ok(())
+    }
+
+    // What scans do with records holding text that isn't UTF-8
+    pub fn set_text_policy(&mut self, policy : TextPolicy) {
+        self.text_policy = policy;
+    }
+
+    // How many live rows and dead records the log holds
+    pub fn counts(&self) -> (usize, usize) {
+        (self.index.len(), self.dead)
+    }
+
+    fn append(&mut self, record : &[u8]) -> Result<u64, DbError> {
+        let offset = self.len;
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(record)?;
+        self.len += record.len() as u64;
+        Ok(offset)
+    }
+
+    fn read_record(&mut self, offset : u64) -> Result<Vec<u8>, DbError> {
+        read_record(&mut self.file, offset)
+    }
+}
+
+impl Engine for LogTable {
+    fn insert(&mut self, row : &Row) -> Result<(), DbError> {
+        self.validate_insert(row)?;
+        let offset = self.append(&encode(INSERT, row))?;
+        self.index.insert(row.id, offset);
+        Ok(())
+    }
+
+    fn delete(&mut self, id : u32) -> Result<bool, DbError> {
+        if !self.index.contains_key(&id) {
+            return Ok(false);
+        }
+        let tombstone = Row { id, user_id : String::new(), email : String::new() };
+        self.append(&encode(DELETE, &tombstone))?;
+        self.index.remove(&id);
+        self.dead += 2;
+        if self.dead >= COMPACT_MIN_DEAD && self.dead > self.index.len() {
+            self.compact()?;
+        }
+        Ok(true)
+    }
+
+    fn scan(&mut self, f : &mut dyn FnMut(Row) -> Result<(), DbError>)
+            -> Result<(), DbError> {
+        let offsets : Vec<u64> = self.index.values().cloned().collect();
+        for offset in offsets {
+            let record = self.read_record(offset)?;
+            f(decode(&record, self.text_policy).ok_or_else(|| corrupt(offset))?)?;
+        }
+        Ok(())
+    }
+
+    fn get(&mut self, id : u32) -> Result<Option<Row>, DbError> {
+        let offset = match self.index.get(&id) {
+            Some(&offset) => offset,
+            None => return Ok(None),
+        };
+        let record = self.read_record(offset)?;
+        Ok(Some(decode(&record, self.text_policy).ok_or_else(|| corrupt(offset))?))
+    }
+
+    fn range(&mut self, from : u32, to : u32,
+             f : &mut dyn FnMut(Row) -> Result<(), DbError>) -> Result<(), DbError> {
+        if from > to {
+            return Ok(());
+        }
+        let offsets : Vec<u64> = self.index.range(from..=to).map(|(_, &off)| off).collect();
+        for offset in offsets {
+            let record = self.read_record(offset)?;
+            f(decode(&record, self.text_policy).ok_or_else(|| corrupt(offset))?)?;
+        }
+        Ok(())
+    }
+
+    fn range_limit(&mut self, from : u32, to : u32, offset : usize, limit : usize,
+                   f : &mut dyn FnMut(Row) -> Result<(), DbError>) -> Result<(), DbError> {
+        if from > to {
+            return Ok(());
+        }
+        let offsets : Vec<u64> = self.index.range(from..=to).skip(offset).take(limit)
+            .map(|(_, &off)| off).collect();
+        for offset in offsets {
+            let record = self.read_record(offset)?;
+            f(decode(&record, self.text_policy).ok_or_else(|| corrupt(offset))?)?;
+        }
+        Ok(())
+    }
+
+    fn validate_insert(&mut self, row : &Row) -> Result<(), DbError> {
+        row.check_size()?;
+        if self.index.contains_key(&row.id) {
+            return Err(DbError::DuplicateKey);
+        }
+        Ok(())
+    }
+
+    fn validate_delete(&mut self, _id : u32) -> Result<(), DbError> {
+        Ok(())
+    }
+}
+
+fn log_header() -> Vec<u8> {
+    let mut header = vec![0; LOG_HEADER_SIZE];
+    header[..LOG_MAGIC.len()].copy_from_slice(LOG_MAGIC);
+    write_u32(&mut header, LOG_MAGIC.len(), LOG_VERSION);
+    header
+}
+
+// The whole record at offset, checksum included
+pub fn read_record(file : &mut File, offset : u64) -> Result<Vec<u8>, DbError> {
+    let mut record = vec![0; RECORD_HEADER_SIZE];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut record)?;
+    let size = record_size(&record).ok_or_else(|| corrupt(offset))?;
+    record.resize(size, 0);
+    file.read_exact(&mut record[RECORD_HEADER_SIZE..])?;
+    if parse_record(&record).is_none() {
+        return Err(corrupt(offset));
+    }
+    Ok(record)
+}
+
+pub fn corrupt(offset : u64) -> DbError {
+    DbError::Corrupt(format!("record at {}: bad record", offset))
+}
+
+// Records are shared with the LSM engine's run files
+pub fn encode(kind : u8, row : &Row) -> Vec<u8> {
+    let mut record = vec![0; RECORD_HEADER_SIZE];
+    record[KIND_OFFSET] = kind;
+    write_u32(&mut record, ID_OFFSET, row.id);
+    write_u32(&mut record, USERID_LEN_OFFSET, row.user_id.len() as u32);
+    write_u32(&mut record, EMAIL_LEN_OFFSET, row.email.len() as u32);
+    record.extend_from_slice(row.user_id.as_bytes());
+    record.extend_from_slice(row.email.as_bytes());
+    let crc = crc32(&record);
+    record.extend_from_slice(&[0; RECORD_CHECKSUM_SIZE]);
+    let end = record.len() - RECORD_CHECKSUM_SIZE;
+    write_u32(&mut record, end, crc);
+    record
+}
+
+// Size of the record starting with header, None if the lengths make no sense
+fn record_size(header : &[u8]) -> Option<usize> {
+    let user_id_len = read_u32(header, USERID_LEN_OFFSET) as usize;
+    let email_len = read_u32(header, EMAIL_LEN_OFFSET) as usize;
+    if user_id_len > MAX_VALUE_SIZE || email_len > MAX_VALUE_SIZE {
+        return None;
+    }
+    Some(RECORD_HEADER_SIZE + user_id_len + email_len + RECORD_CHECKSUM_SIZE)
+}
+
+// Kind, id and size of the record at the start of data,
+// None if it is cut short or doesn't check out
+pub fn parse_record(data : &[u8]) -> Option<(u8, u32, usize)> {
+    if data.len() < RECORD_HEADER_SIZE {
+        return None;
+    }
+    let size = record_size(data)?;
+    if data.len() < size {
+        return None;
+    }
+    let end = size - RECORD_CHECKSUM_SIZE;
+    let kind = data[KIND_OFFSET];
+    if (kind != INSERT && kind != DELETE) || read_u32(data, end) != crc32(&data[..end]) {
+        return None;
+    }
+    Some((kind, read_u32(data, ID_OFFSET), size))
+}
+
+pub fn decode(record : &[u8], policy : TextPolicy) -> Option<Row> {
+    let user_id_len = read_u32(record, USERID_LEN_OFFSET) as usize;
+    let email_len = read_u32(record, EMAIL_LEN_OFFSET) as usize;
+    let user_id = &record[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + user_id_len];
+    let email = &record[RECORD_HEADER_SIZE + user_id_len..
+                        RECORD_HEADER_SIZE + user_id_len + email_len];
+    Some(Row {
+        id : read_u32(record, ID_OFFSET),
+        user_id : policy.decode(user_id.to_vec())?,
+        email : policy.decode(email.to_vec())?,
+    })
+}
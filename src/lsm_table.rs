@@ -0,0 +1,409 @@
+// The LSM engine keeps a table in a directory. Writes go to an in-memory
+// memtable, and once that holds memtable_rows entries it is written out as
+// a sorted run: a file of log records (see log_table) in id order, where a
+// delete record is a tombstone hiding the row in older runs. Lookups try
+// the memtable, then the runs from newest to oldest.
+//
+// Once there are more than max_runs runs a background thread merges all of
+// them into one, dropping tombstones since nothing older is left to hide.
+// Writes carry on into the memtable and new runs in the meantime.
+//
+// MANIFEST lists the live runs oldest first and is replaced atomically
+// after every flush and merge, run files it doesn't list and MANIFEST.tmp
+// are left over from a crash and removed on open, anything else in the
+// directory is left alone. Like the B-tree's dirty pages,
+// the memtable only reaches disk on flush, close or drop.
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+
+use log_table::{self, DELETE, INSERT};
+use node::{read_u32, write_u32};
+use storage::{sync_dir, sync_file};
+use super::{DbError, Engine, Row, TextPolicy};
+
+pub const RUN_MAGIC: &[u8; 16] = b"simple-db run\0\0\0";
+pub const RUN_VERSION: u32 = 1;
+const RUN_HEADER_SIZE: usize = 20;
+const MANIFEST: &str = "MANIFEST";
+const MANIFEST_TMP: &str = "MANIFEST.tmp";
+
+#[derive(Debug, Clone)]
+pub struct LsmOptions {
+    // Memtable entries that trigger writing a run
+    pub memtable_rows : usize,
+    // Runs kept before they are merged
+    pub max_runs : usize,
+    // What scans do with records holding text that isn't UTF-8
+    pub text_policy : TextPolicy,
+}
+
+impl Default for LsmOptions {
+    fn default() -> LsmOptions {
+        LsmOptions {
+            memtable_rows : 1000,
+            max_runs : 4,
+            text_policy : TextPolicy::default(),
+        }
+    }
+}
+
+struct Run {
+    name : String,
+    file : File,
+    // id -> offset of its record, None for a tombstone
+    index : BTreeMap<u32, Option<u64>>,
+}
+
+// A merge running in the background and the runs it is merging
+struct Merge {
+    inputs : Vec<String>,
+    output : String,
+    handle : JoinHandle<io::Result<()>>,
+}
+
+pub struct LsmTable {
+    dir : PathBuf,
+    options : LsmOptions,
+    // None is a tombstone
+    memtable : BTreeMap<u32, Option<Row>>,
+    // oldest first
+    runs : Vec<Run>,
+    next_seq : u64,
+    merge : Option<Merge>,
+}
+
+impl LsmTable {
+    pub fn open(dir : PathBuf) -> Result<LsmTable, DbError> {
+        LsmTable::open_with(dir, LsmOptions::default())
+    }
+
+    // Creates the directory if it doesn't exist
+    pub fn open_with(dir : PathBuf, options : LsmOptions) -> Result<LsmTable, DbError> {
+        if !dir.exists() {
+            fs::create_dir_all(&dir)?;
+            sync_dir(&dir)?;
+        }
+        let names : Vec<String> = match fs::read_to_string(dir.join(MANIFEST)) {
+            Ok(manifest) => manifest.lines().map(|name| name.to_string()).collect(),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err.into()),
+        };
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let ours = name == MANIFEST_TMP || run_seq(&name).is_some();
+            if ours && !names.contains(&name) && entry.file_type()?.is_file() {
+                fs::remove_file(dir.join(&name))?;
+            }
+        }
+        let mut runs = vec![];
+        for name in names {
+            runs.push(open_run(&dir, name)?);
+        }
+        let next_seq = runs.iter().filter_map(|run| run_seq(&run.name))
+            .max().map_or(0, |seq| seq + 1);
+        Ok(LsmTable {
+            dir,
+            options,
+            memtable : BTreeMap::new(),
+            runs,
+            next_seq,
+            merge : None,
+        })
+    }
+
+    // Write the memtable out as a run
+    pub fn flush(&mut self) -> Result<(), DbError> {
+        self.finish_merge(false)?;
+        if self.memtable.is_empty() {
+            return Ok(());
+        }
+        let name = self.new_run_name();
+        let records = self.memtable.iter().map(|(&id, row)| match *row {
+            Some(ref row) => log_table::encode(INSERT, row),
+            None => log_table::encode(DELETE, &tombstone(id)),
+        });
+        write_run(&self.dir.join(&name), records)?;
+        let run = open_new_run(&self.dir, name)?;
+        self.runs.push(run);
+        self.write_manifest()?;
+        self.memtable.clear();
+        if self.runs.len() > self.options.max_runs && self.merge.is_none() {
+            self.start_merge();
+        }
+        Ok(())
+    }
+
+    // Flush and wait for any merge, reporting what went wrong
+    pub fn close(mut self) -> Result<(), DbError> {
+        self.flush()?;
+        self.finish_merge(true)
+    }
+
+    // How many runs there are, for tests and curious callers
+    pub fn num_runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    // Block until a background merge, if any, has been swapped in
+    pub fn wait_for_merge(&mut self) -> Result<(), DbError> {
+        self.finish_merge(true)
+    }
+
+    fn new_run_name(&mut self) -> String {
+        self.next_seq += 1;
+        format!("run-{:08}.sst", self.next_seq - 1)
+    }
+
+    fn start_merge(&mut self) {
+        let inputs : Vec<String> = self.runs.iter().map(|run| run.name.clone()).collect();
+        let output = self.new_run_name();
+        let dir = self.dir.clone();
+        let (thread_inputs, thread_output) = (inputs.clone(), output.clone());
+        let handle = thread::Builder::new()
+            .name("simple-db merge".to_string())
+            .spawn(move || merge_runs(&dir, &thread_inputs, &thread_output));
+        match handle {
+            Ok(handle) => self.merge = Some(Merge { inputs, output, handle }),
+            // try again after the next flush
+            Err(_) => self.next_seq -= 1,
+        }
+    }
+
+    // Swap in a merge that is done, or wait for it to be
+    fn finish_merge(&mut self, wait : bool) -> Result<(), DbError> {
+        match self.merge {
+            Some(ref merge) if wait || merge.handle.is_finished() => (),
+            _ => return Ok(()),
+        }
+        let merge = self.merge.take().unwrap();
+        let result = merge.handle.join().unwrap_or_else(|_| {
+            Err(io::Error::other("merge thread panicked"))
+        });
+        if let Err(err) = result {
+            let _ = fs::remove_file(self.dir.join(&merge.output));
+            return Err(err.into());
+        }
+        let merged = open_new_run(&self.dir, merge.output)?;
+        // merged runs are the oldest ones, runs flushed since come after
+        let newer = self.runs.split_off(merge.inputs.len());
+        self.runs = vec![merged];
+        self.runs.extend(newer);
+        self.write_manifest()?;
+        for name in &merge.inputs {
+            fs::remove_file(self.dir.join(name))?;
+        }
+        Ok(())
+    }
+
+    fn write_manifest(&mut self) -> Result<(), DbError> {
+        let mut manifest = String::new();
+        for run in &self.runs {
+            manifest.push_str(&run.name);
+            manifest.push('\n');
+        }
+        let tmp_path = self.dir.join(MANIFEST_TMP);
+        {
+            let mut tmp = File::create(&tmp_path)?;
+            tmp.write_all(manifest.as_bytes())?;
+            sync_file(&tmp)?;
+        }
+        fs::rename(&tmp_path, self.dir.join(MANIFEST))?;
+        // makes the rename and every run file it lists stick
+        sync_dir(&self.dir.join(MANIFEST))?;
+        Ok(())
+    }
+
+    // Whether id has a live row, the newest entry for it wins
+    fn contains(&self, id : u32) -> bool {
+        if let Some(row) = self.memtable.get(&id) {
+            return row.is_some();
+        }
+        for run in self.runs.iter().rev() {
+            if let Some(offset) = run.index.get(&id) {
+                return offset.is_some();
+            }
+        }
+        false
+    }
+
+    fn maybe_flush(&mut self) -> Result<(), DbError> {
+        if self.memtable.len() >= self.options.memtable_rows {
+            self.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Engine for LsmTable {
+    fn insert(&mut self, row : &Row) -> Result<(), DbError> {
+        self.validate_insert(row)?;
+        self.memtable.insert(row.id, Some(row.clone()));
+        self.maybe_flush()
+    }
+
+    fn delete(&mut self, id : u32) -> Result<bool, DbError> {
+        self.finish_merge(false)?;
+        if !self.contains(id) {
+            return Ok(false);
+        }
+        self.memtable.insert(id, None);
+        self.maybe_flush()?;
+        Ok(true)
+    }
+
+    fn scan(&mut self, f : &mut dyn FnMut(Row) -> Result<(), DbError>)
+            -> Result<(), DbError> {
+        self.finish_merge(false)?;
+        // id -> (run, offset), newer runs overwrite older ones
+        let mut live : BTreeMap<u32, Option<(usize, u64)>> = BTreeMap::new();
+        for (i, run) in self.runs.iter().enumerate() {
+            for (&id, offset) in &run.index {
+                live.insert(id, offset.map(|offset| (i, offset)));
+            }
+        }
+        for (&id, row) in &self.memtable {
+            if row.is_none() {
+                live.insert(id, None);
+            } else {
+                live.remove(&id);
+            }
+        }
+        let mut memtable = self.memtable.values().filter_map(|row| row.as_ref()).peekable();
+        for (&id, &found) in &live {
+            while memtable.peek().is_some_and(|row| row.id < id) {
+                f(memtable.next().unwrap().clone())?;
+            }
+            if let Some((i, offset)) = found {
+                let record = log_table::read_record(&mut self.runs[i].file, offset)?;
+                f(log_table::decode(&record, self.options.text_policy).ok_or_else(|| log_table::corrupt(offset))?)?;
+            }
+        }
+        for row in memtable {
+            f(row.clone())?;
+        }
+        Ok(())
+    }
+
+    fn validate_insert(&mut self, row : &Row) -> Result<(), DbError> {
+        row.check_size()?;
+        self.finish_merge(false)?;
+        if self.contains(row.id) {
+            return Err(DbError::DuplicateKey);
+        }
+        Ok(())
+    }
+
+    fn validate_delete(&mut self, _id : u32) -> Result<(), DbError> {
+        Ok(())
+    }
+}
+
+impl Drop for LsmTable {
+    // Best effort, close reports errors
+    fn drop(&mut self) {
+        if let Err(err) = self.finish_merge(true).and_then(|_| self.flush()) {
+            eprintln!("simple-db: could not write the memtable: {}", err);
+        }
+    }
+}
+
+fn tombstone(id : u32) -> Row {
+    Row { id, user_id : String::new(), email : String::new() }
+}
+
+// Sequence number from a run's file name
+fn run_seq(name : &str) -> Option<u64> {
+    let seq = name.strip_prefix("run-")?.strip_suffix(".sst")?;
+    if seq.len() == 8 && seq.bytes().all(|b| b.is_ascii_digit()) {
+        seq.parse().ok()
+    } else {
+        None
+    }
+}
+
+// Write the records, which must be in id order, to a new run file
+fn write_run<I : Iterator<Item = Vec<u8>>>(path : &Path, records : I) -> io::Result<()> {
+    let mut data = vec![0; RUN_HEADER_SIZE];
+    data[..RUN_MAGIC.len()].copy_from_slice(RUN_MAGIC);
+    write_u32(&mut data, RUN_MAGIC.len(), RUN_VERSION);
+    for record in records {
+        data.extend_from_slice(&record);
+    }
+    let mut file = File::create(path)?;
+    file.write_all(&data)?;
+    sync_file(&file)
+}
+
+// Offset and bytes of each record in a run
+type Records = Vec<(u64, Vec<u8>)>;
+
+// Every record of a run, in order
+fn read_run(path : &Path) -> io::Result<(File, Records)> {
+    let mut file = File::open(path)?;
+    let mut data = vec![];
+    file.read_to_end(&mut data)?;
+    let bad = || io::Error::new(io::ErrorKind::InvalidData,
+                                format!("{}: not a valid run", path.display()));
+    if data.len() < RUN_HEADER_SIZE || &data[..RUN_MAGIC.len()] != RUN_MAGIC ||
+       read_u32(&data, RUN_MAGIC.len()) != RUN_VERSION {
+        return Err(bad());
+    }
+    let mut records = vec![];
+    let mut offset = RUN_HEADER_SIZE;
+    while offset < data.len() {
+        let (_, _, size) = log_table::parse_record(&data[offset..]).ok_or_else(bad)?;
+        records.push((offset as u64, data[offset..offset + size].to_vec()));
+        offset += size;
+    }
+    Ok((file, records))
+}
+
+fn open_run(dir : &Path, name : String) -> Result<Run, DbError> {
+    let (file, records) = read_run(&dir.join(&name)).map_err(|err| {
+        if err.kind() == io::ErrorKind::InvalidData {
+            DbError::Corrupt(err.to_string())
+        } else {
+            DbError::IoError(err)
+        }
+    })?;
+    let mut index = BTreeMap::new();
+    for (offset, record) in records {
+        let (kind, id, _) = log_table::parse_record(&record).unwrap();
+        index.insert(id, if kind == INSERT { Some(offset) } else { None });
+    }
+    Ok(Run { name, file, index })
+}
+
+// open_run for a run just written, which is removed again if it doesn't
+// read back instead of lying around until the next open
+fn open_new_run(dir : &Path, name : String) -> Result<Run, DbError> {
+    let path = dir.join(&name);
+    match open_run(dir, name) {
+        Ok(run) => Ok(run),
+        Err(err) => {
+            let _ = fs::remove_file(path);
+            Err(err)
+        },
+    }
+}
+
+// Runs on the merge thread. inputs are every run there was when the merge
+// started, so there is nothing older for a tombstone to hide and they can go.
+fn merge_runs(dir : &Path, inputs : &[String], output : &str) -> io::Result<()> {
+    let mut merged = BTreeMap::new();
+    for name in inputs {
+        for (_, record) in read_run(&dir.join(name))?.1 {
+            let (kind, id, _) = log_table::parse_record(&record).unwrap();
+            merged.insert(id, (kind, record));
+        }
+    }
+    let records = merged.into_iter()
+        .filter(|&(_, (kind, _))| kind == INSERT)
+        .map(|(_, (_, record))| record);
+    write_run(&dir.join(output), records)
+}
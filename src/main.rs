@@ -2,14 +2,52 @@ extern crate simple_db;
 use std::io::{self, Write};
 use std::env;
 use std::path::PathBuf;
+use std::process;
+
+// A .log file is kept by the log engine, anything else by the B-tree.
+// Which one a database uses is settled by the name it is created with.
+enum Database {
+    BTree(Box<simple_db::Table>),
+    Log(simple_db::LogTable),
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
     if args.len() < 2 {
-        panic!("need to supply database file");
+        panic!("need to supply database file, or {} for one in memory",
+               simple_db::MEMORY_DB);
+    }
+    if args[1] == "diff" {
+        diff(&args[2..]);
+        return;
+    }
+    if args[1] == "clone" {
+        clone(&args[2..]);
+        return;
+    }
+    if args[1] == "salvage" {
+        salvage(&args[2..]);
+        return;
+    }
+    if args[1] == "bench" {
+        bench(&args[2..]);
+        return;
     }
 
-    let mut table = simple_db::Table::db_open(PathBuf::from(args[1].as_str()));
+    let mut database = if args[1].ends_with(".log") {
+        Database::Log(open_log(&args[1]))
+    } else {
+        Database::BTree(Box::new(open(&args[1])))
+    };
+    // create table ... as select puts its database next to this one
+    let catalog = if args[1] == simple_db::MEMORY_DB {
+        None
+    } else {
+        let dir = PathBuf::from(&args[1]).parent().map_or(PathBuf::new(), |dir| dir.to_path_buf());
+        let dir = if dir.as_os_str().is_empty() { PathBuf::from(".") } else { dir };
+        simple_db::Catalog::open(dir).ok()
+    };
+    let mut sink = simple_db::TextSink::new(io::stdout());
     loop {
         print!("db > ");
         io::stdout().flush().unwrap();
@@ -23,7 +61,13 @@ fn main() {
             break;
         }
         if input.starts_with(".") {
-            match simple_db::meta_command(&input) {
+            // the meta commands look inside the B-tree
+            let result = match database {
+                Database::BTree(ref mut table) =>
+                    simple_db::meta_command(input, table, &mut io::stdout()),
+                Database::Log(_) => Err(simple_db::DbError::MetaUnrecognized),
+            };
+            match result {
                 Ok(_) => continue,
                 Err(err) => {
                     println!("{}", err);
@@ -32,14 +76,119 @@ fn main() {
             }
         }
         else {
-            let mut stdout = io::stdout();
-            match simple_db::statement_command(&input, &mut table, &mut stdout as &mut Write ) {
-                Ok(_) => println!("Executed."),
-                Err(err) => {
-                    println!("{}", err);
-                    continue;
-                }
+            // errors are already printed by the sink
+            let engine : &mut dyn simple_db::Engine = match database {
+                Database::BTree(ref mut table) => &mut **table,
+                Database::Log(ref mut log) => log,
+            };
+            let result = match catalog {
+                Some(ref catalog) => catalog.statement_command(input, engine, &mut sink),
+                None => simple_db::statement_command(input, engine, &mut sink),
+            };
+            if result.is_ok() {
+                println!("Executed.");
             }
         }
     }
+    let closed = match database {
+        Database::BTree(table) => table.close(),
+        Database::Log(log) => log.close(),
+    };
+    if let Err(err) = closed {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+// simple-db diff a.db b.db --data [table]
+// A database only holds the one table, so the name is optional
+fn diff(args : &[String]) {
+    if args.len() < 3 || args.len() > 4 || args[2] != "--data" {
+        eprintln!("usage: simple-db diff a.db b.db --data [table]");
+        process::exit(2);
+    }
+    for path in &args[..2] {
+        if !PathBuf::from(path).exists() {
+            eprintln!("{}: no such file", path);
+            process::exit(1);
+        }
+    }
+    let mut from = open(&args[0]);
+    let mut to = open(&args[1]);
+    if let Err(err) = simple_db::diff(&mut from, &mut to, &mut io::stdout()) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+// simple-db clone src.db dst.db
+fn clone(args : &[String]) {
+    if args.len() != 2 {
+        eprintln!("usage: simple-db clone src.db dst.db");
+        process::exit(2);
+    }
+    if !PathBuf::from(&args[0]).exists() {
+        eprintln!("{}: no such file", args[0]);
+        process::exit(1);
+    }
+    let mut table = open(&args[0]);
+    if let Err(err) = table.clone_to(PathBuf::from(args[1].as_str())) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+// simple-db salvage damaged.db recovered.db
+fn salvage(args : &[String]) {
+    if args.len() != 2 {
+        eprintln!("usage: simple-db salvage damaged.db recovered.db");
+        process::exit(2);
+    }
+    match simple_db::Table::salvage(PathBuf::from(&args[0]), PathBuf::from(&args[1])) {
+        Ok(report) => println!("recovered {} rows, {} bad pages, {} bad rows",
+                               report.rows, report.bad_pages, report.bad_rows),
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        },
+    }
+}
+
+// simple-db bench [rows]
+fn bench(args : &[String]) {
+    let rows = args.first().map_or(Ok(10_000), |rows| rows.parse::<usize>());
+    let rows = match rows {
+        Ok(rows) if args.len() <= 1 => rows,
+        _ => {
+            eprintln!("usage: simple-db bench [rows]");
+            process::exit(2);
+        },
+    };
+    match simple_db::bench::run(rows) {
+        Ok(results) => simple_db::bench::report(&results, &mut io::stdout()).unwrap(),
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        },
+    }
+}
+
+fn open(path : &str) -> simple_db::Table {
+    match simple_db::Table::db_open(PathBuf::from(path)) {
+        Ok(table) => table,
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            process::exit(1);
+        },
+    }
+}
+
+fn open_log(path : &str) -> simple_db::LogTable {
+    match simple_db::LogTable::open(PathBuf::from(path)) {
+        Ok(log) => log,
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            process::exit(1);
+        },
+    }
 }
@@ -0,0 +1,91 @@
+// A read-only shared mapping of the database file. Pages written through
+// the file show up in it straight away, but anything the file grows by
+// after mapping doesn't, map it again to see that. The file must not be
+// truncated while it is mapped.
+//
+// Only unix has an implementation, elsewhere map never maps anything and
+// the mmap mode reads pages the same way as the default one.
+#[cfg(unix)]
+pub use self::unix::Mmap;
+#[cfg(not(unix))]
+pub use self::other::Mmap;
+
+#[cfg(unix)]
+mod unix {
+    use std::fs::File;
+    use std::io;
+    use std::ops::Deref;
+    use std::os::unix::io::AsRawFd;
+    use std::ptr;
+    use std::slice;
+
+    use libc;
+
+    pub struct Mmap {
+        ptr : *mut libc::c_void,
+        len : usize,
+    }
+
+    // the mapping is never written through, sharing it is as safe as a &[u8]
+    unsafe impl Send for Mmap {}
+    unsafe impl Sync for Mmap {}
+
+    impl Mmap {
+        // None for an empty file, there is nothing to map
+        pub fn map(file : &File) -> io::Result<Option<Mmap>> {
+            let len = file.metadata()?.len() as usize;
+            if len == 0 {
+                return Ok(None);
+            }
+            let ptr = unsafe {
+                libc::mmap(ptr::null_mut(), len, libc::PROT_READ, libc::MAP_SHARED,
+                           file.as_raw_fd(), 0)
+            };
+            if ptr == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Some(Mmap { ptr, len }))
+        }
+    }
+
+    impl Deref for Mmap {
+        type Target = [u8];
+
+        fn deref(&self) -> &[u8] {
+            unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) }
+        }
+    }
+
+    impl Drop for Mmap {
+        fn drop(&mut self) {
+            unsafe {
+                libc::munmap(self.ptr, self.len);
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod other {
+    use std::fs::File;
+    use std::io;
+    use std::ops::Deref;
+
+    pub struct Mmap {
+        _private : (),
+    }
+
+    impl Mmap {
+        pub fn map(_file : &File) -> io::Result<Option<Mmap>> {
+            Ok(None)
+        }
+    }
+
+    impl Deref for Mmap {
+        type Target = [u8];
+
+        fn deref(&self) -> &[u8] {
+            &[]
+        }
+    }
+}
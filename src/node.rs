@@ -0,0 +1,306 @@
+// Every page in the file after the header page is a B-tree node. A node
+// starts with a common header (node type, root flag, parent pointer),
+// followed by a header and body specific to the node type. Leaf cells are
+// a u32 key followed by a serialized row, leaves also point at their left
+// and right siblings (0 meaning there is none, page 0 is the file header
+// and page 1 is always the root). Internal cells are a u32 child page
+// number followed by the largest key found in that child. The right-most
+// child of an internal node has no key and lives in the header. All
+// integers are stored little endian.
+use super::{PAGE_USABLE_SIZE, ROW_SIZE};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum NodeType {
+    Internal,
+    Leaf,
+}
+
+// Common node header layout
+const NODE_TYPE_SIZE: usize = 1;
+const NODE_TYPE_OFFSET: usize = 0;
+const IS_ROOT_SIZE: usize = 1;
+const IS_ROOT_OFFSET: usize = NODE_TYPE_OFFSET + NODE_TYPE_SIZE;
+const PARENT_POINTER_SIZE: usize = 4;
+const PARENT_POINTER_OFFSET: usize = IS_ROOT_OFFSET + IS_ROOT_SIZE;
+pub const COMMON_NODE_HEADER_SIZE: usize =
+    NODE_TYPE_SIZE + IS_ROOT_SIZE + PARENT_POINTER_SIZE;
+
+// Leaf node header layout
+const LEAF_NODE_NUM_CELLS_SIZE: usize = 4;
+const LEAF_NODE_NUM_CELLS_OFFSET: usize = COMMON_NODE_HEADER_SIZE;
+const LEAF_NODE_NEXT_LEAF_SIZE: usize = 4;
+const LEAF_NODE_NEXT_LEAF_OFFSET: usize =
+    LEAF_NODE_NUM_CELLS_OFFSET + LEAF_NODE_NUM_CELLS_SIZE;
+const LEAF_NODE_PREV_LEAF_SIZE: usize = 4;
+const LEAF_NODE_PREV_LEAF_OFFSET: usize =
+    LEAF_NODE_NEXT_LEAF_OFFSET + LEAF_NODE_NEXT_LEAF_SIZE;
+pub const LEAF_NODE_HEADER_SIZE: usize = COMMON_NODE_HEADER_SIZE +
+    LEAF_NODE_NUM_CELLS_SIZE + LEAF_NODE_NEXT_LEAF_SIZE + LEAF_NODE_PREV_LEAF_SIZE;
+
+// Internal node header layout
+const INTERNAL_NODE_NUM_KEYS_SIZE: usize = 4;
+const INTERNAL_NODE_NUM_KEYS_OFFSET: usize = COMMON_NODE_HEADER_SIZE;
+const INTERNAL_NODE_RIGHT_CHILD_SIZE: usize = 4;
+const INTERNAL_NODE_RIGHT_CHILD_OFFSET: usize =
+    INTERNAL_NODE_NUM_KEYS_OFFSET + INTERNAL_NODE_NUM_KEYS_SIZE;
+pub const INTERNAL_NODE_HEADER_SIZE: usize = COMMON_NODE_HEADER_SIZE +
+    INTERNAL_NODE_NUM_KEYS_SIZE + INTERNAL_NODE_RIGHT_CHILD_SIZE;
+
+// Internal node body layout
+const INTERNAL_NODE_CHILD_SIZE: usize = 4;
+const INTERNAL_NODE_KEY_SIZE: usize = 4;
+pub const INTERNAL_NODE_CELL_SIZE: usize =
+    INTERNAL_NODE_CHILD_SIZE + INTERNAL_NODE_KEY_SIZE;
+// Keep internal nodes tiny in tests so a few hundred rows
+// are enough to exercise internal node splits
+#[cfg(not(test))]
+pub const INTERNAL_NODE_MAX_CELLS: usize =
+    (PAGE_USABLE_SIZE - INTERNAL_NODE_HEADER_SIZE) / INTERNAL_NODE_CELL_SIZE;
+#[cfg(test)]
+pub const INTERNAL_NODE_MAX_CELLS: usize = 3;
+
+// Leaf node body layout
+const LEAF_NODE_KEY_SIZE: usize = 4;
+const LEAF_NODE_KEY_OFFSET: usize = 0;
+const LEAF_NODE_VALUE_SIZE: usize = ROW_SIZE;
+const LEAF_NODE_VALUE_OFFSET: usize = LEAF_NODE_KEY_OFFSET + LEAF_NODE_KEY_SIZE;
+pub const LEAF_NODE_CELL_SIZE: usize = LEAF_NODE_KEY_SIZE + LEAF_NODE_VALUE_SIZE;
+pub const LEAF_NODE_SPACE_FOR_CELLS: usize = PAGE_USABLE_SIZE - LEAF_NODE_HEADER_SIZE;
+pub const LEAF_NODE_MAX_CELLS: usize = LEAF_NODE_SPACE_FOR_CELLS / LEAF_NODE_CELL_SIZE;
+
+// Non-root nodes are kept at least half full, deletes borrow from or
+// merge with a sibling when a node drops below these
+pub const LEAF_NODE_MIN_CELLS: usize = LEAF_NODE_MAX_CELLS / 2;
+pub const INTERNAL_NODE_MIN_CELLS: usize = INTERNAL_NODE_MAX_CELLS / 2;
+
+pub fn read_u32(node: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&node[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+pub fn write_u32(node: &mut [u8], offset: usize, value: u32) {
+    node[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+pub fn node_type(node: &[u8]) -> NodeType {
+    match node[NODE_TYPE_OFFSET] {
+        0 => NodeType::Internal,
+        _ => NodeType::Leaf,
+    }
+}
+
+pub fn set_node_type(node: &mut [u8], node_type: NodeType) {
+    node[NODE_TYPE_OFFSET] = match node_type {
+        NodeType::Internal => 0,
+        NodeType::Leaf => 1,
+    };
+}
+
+pub fn is_root(node: &[u8]) -> bool {
+    node[IS_ROOT_OFFSET] != 0
+}
+
+pub fn set_root(node: &mut [u8], is_root: bool) {
+    node[IS_ROOT_OFFSET] = is_root as u8;
+}
+
+pub fn parent(node: &[u8]) -> usize {
+    read_u32(node, PARENT_POINTER_OFFSET) as usize
+}
+
+pub fn set_parent(node: &mut [u8], parent: usize) {
+    write_u32(node, PARENT_POINTER_OFFSET, parent as u32);
+}
+
+pub fn leaf_num_cells(node: &[u8]) -> usize {
+    read_u32(node, LEAF_NODE_NUM_CELLS_OFFSET) as usize
+}
+
+pub fn set_leaf_num_cells(node: &mut [u8], num_cells: usize) {
+    write_u32(node, LEAF_NODE_NUM_CELLS_OFFSET, num_cells as u32);
+}
+
+pub fn leaf_next(node: &[u8]) -> usize {
+    read_u32(node, LEAF_NODE_NEXT_LEAF_OFFSET) as usize
+}
+
+pub fn set_leaf_next(node: &mut [u8], next: usize) {
+    write_u32(node, LEAF_NODE_NEXT_LEAF_OFFSET, next as u32);
+}
+
+pub fn leaf_prev(node: &[u8]) -> usize {
+    read_u32(node, LEAF_NODE_PREV_LEAF_OFFSET) as usize
+}
+
+pub fn set_leaf_prev(node: &mut [u8], prev: usize) {
+    write_u32(node, LEAF_NODE_PREV_LEAF_OFFSET, prev as u32);
+}
+
+pub fn leaf_cell_offset(cell_num: usize) -> usize {
+    LEAF_NODE_HEADER_SIZE + cell_num * LEAF_NODE_CELL_SIZE
+}
+
+pub fn leaf_key(node: &[u8], cell_num: usize) -> u32 {
+    read_u32(node, leaf_cell_offset(cell_num) + LEAF_NODE_KEY_OFFSET)
+}
+
+pub fn set_leaf_key(node: &mut [u8], cell_num: usize, key: u32) {
+    write_u32(node, leaf_cell_offset(cell_num) + LEAF_NODE_KEY_OFFSET, key);
+}
+
+pub fn leaf_value(node: &[u8], cell_num: usize) -> &[u8] {
+    let offset = leaf_cell_offset(cell_num) + LEAF_NODE_VALUE_OFFSET;
+    &node[offset..offset + LEAF_NODE_VALUE_SIZE]
+}
+
+pub fn leaf_value_mut(node: &mut [u8], cell_num: usize) -> &mut [u8] {
+    let offset = leaf_cell_offset(cell_num) + LEAF_NODE_VALUE_OFFSET;
+    &mut node[offset..offset + LEAF_NODE_VALUE_SIZE]
+}
+
+// Whether a page that may be anything at all, an overflow page or
+// garbage say, holds a leaf: the header bytes are ones a leaf could have
+// and its keys are in order. For salvaging a file whose tree is broken.
+pub fn is_plausible_leaf(node: &[u8]) -> bool {
+    if node[NODE_TYPE_OFFSET] != 1 || node[IS_ROOT_OFFSET] > 1 {
+        return false;
+    }
+    let num_cells = leaf_num_cells(node);
+    num_cells <= LEAF_NODE_MAX_CELLS &&
+        (1..num_cells).all(|i| leaf_key(node, i - 1) < leaf_key(node, i))
+}
+
+// Binary search for the first cell with a key >= key,
+// num_cells if every key is smaller
+pub fn leaf_find(node: &[u8], key: u32) -> usize {
+    let mut min = 0;
+    let mut max = leaf_num_cells(node);
+    while min < max {
+        let mid = min + (max - min) / 2;
+        if leaf_key(node, mid) < key {
+            min = mid + 1;
+        } else {
+            max = mid;
+        }
+    }
+    min
+}
+
+// Shift the cells from cell_num on one to the right, node may be
+// larger than a page, splitting uses that to hold one cell more than fits
+fn leaf_make_room(node: &mut [u8], cell_num: usize) {
+    let num_cells = leaf_num_cells(node);
+    if cell_num < num_cells {
+        node.copy_within(leaf_cell_offset(cell_num)..leaf_cell_offset(num_cells),
+                         leaf_cell_offset(cell_num + 1));
+    }
+    set_leaf_num_cells(node, num_cells + 1);
+}
+
+// Store key in a new cell at cell_num and hand back
+// the value slice for the caller to fill in
+pub fn leaf_insert_cell(node: &mut [u8], cell_num: usize, key: u32) -> &mut [u8] {
+    leaf_make_room(node, cell_num);
+    set_leaf_key(node, cell_num, key);
+    leaf_value_mut(node, cell_num)
+}
+
+// The whole cell, key and value
+pub fn leaf_cell(node: &[u8], cell_num: usize) -> &[u8] {
+    &node[leaf_cell_offset(cell_num)..leaf_cell_offset(cell_num + 1)]
+}
+
+pub fn leaf_insert_raw_cell(node: &mut [u8], cell_num: usize, cell: &[u8]) {
+    leaf_make_room(node, cell_num);
+    node[leaf_cell_offset(cell_num)..leaf_cell_offset(cell_num + 1)]
+        .copy_from_slice(cell);
+}
+
+pub fn leaf_remove_cell(node: &mut [u8], cell_num: usize) {
+    let num_cells = leaf_num_cells(node);
+    node.copy_within(leaf_cell_offset(cell_num + 1)..leaf_cell_offset(num_cells),
+                     leaf_cell_offset(cell_num));
+    for b in node[leaf_cell_offset(num_cells - 1)..leaf_cell_offset(num_cells)].iter_mut() {
+        *b = 0;
+    }
+    set_leaf_num_cells(node, num_cells - 1);
+}
+
+pub fn internal_num_keys(node: &[u8]) -> usize {
+    read_u32(node, INTERNAL_NODE_NUM_KEYS_OFFSET) as usize
+}
+
+fn set_internal_num_keys(node: &mut [u8], num_keys: usize) {
+    write_u32(node, INTERNAL_NODE_NUM_KEYS_OFFSET, num_keys as u32);
+}
+
+fn internal_cell_offset(cell_num: usize) -> usize {
+    INTERNAL_NODE_HEADER_SIZE + cell_num * INTERNAL_NODE_CELL_SIZE
+}
+
+// child_num == num_keys is the right-most child
+pub fn internal_child(node: &[u8], child_num: usize) -> usize {
+    if child_num == internal_num_keys(node) {
+        read_u32(node, INTERNAL_NODE_RIGHT_CHILD_OFFSET) as usize
+    } else {
+        read_u32(node, internal_cell_offset(child_num)) as usize
+    }
+}
+
+pub fn internal_key(node: &[u8], key_num: usize) -> u32 {
+    read_u32(node, internal_cell_offset(key_num) + INTERNAL_NODE_CHILD_SIZE)
+}
+
+// Index of the child that key belongs in, the first child whose max
+// key is >= key, or the right-most child if key is beyond all of them
+pub fn internal_find_child(node: &[u8], key: u32) -> usize {
+    let mut min = 0;
+    let mut max = internal_num_keys(node);
+    while min < max {
+        let mid = min + (max - min) / 2;
+        if internal_key(node, mid) < key {
+            min = mid + 1;
+        } else {
+            max = mid;
+        }
+    }
+    min
+}
+
+// All children of an internal node, the right-most child last
+pub fn internal_children(node: &[u8]) -> Vec<usize> {
+    (0..internal_num_keys(node) + 1).map(|i| internal_child(node, i)).collect()
+}
+
+pub fn internal_keys(node: &[u8]) -> Vec<u32> {
+    (0..internal_num_keys(node)).map(|i| internal_key(node, i)).collect()
+}
+
+// Replace the body of an internal node, children must
+// have exactly one more entry than keys
+pub fn set_internal_cells(node: &mut [u8], children: &[usize], keys: &[u32]) {
+    assert_eq!(children.len(), keys.len() + 1);
+    set_internal_num_keys(node, keys.len());
+    for (i, key) in keys.iter().enumerate() {
+        write_u32(node, internal_cell_offset(i), children[i] as u32);
+        write_u32(node, internal_cell_offset(i) + INTERNAL_NODE_CHILD_SIZE, *key);
+    }
+    write_u32(node, INTERNAL_NODE_RIGHT_CHILD_OFFSET, children[keys.len()] as u32);
+}
+
+pub fn initialize_leaf(node: &mut [u8]) {
+    set_node_type(node, NodeType::Leaf);
+    set_root(node, false);
+    set_parent(node, 0);
+    set_leaf_num_cells(node, 0);
+    set_leaf_next(node, 0);
+    set_leaf_prev(node, 0);
+}
+
+pub fn initialize_internal(node: &mut [u8]) {
+    set_node_type(node, NodeType::Internal);
+    set_root(node, false);
+    set_parent(node, 0);
+    set_internal_num_keys(node, 0);
+}
@@ -0,0 +1,104 @@
+// Pages kept in an object store, one object per page under
+// "<prefix>page-<n>" plus a "<prefix>pages" object holding the page count.
+// Written pages stay in a local write-back cache until sync uploads them,
+// the count goes up last so a sync cut short never points at missing
+// pages. The client is whatever can get and put objects by key.
+use std::collections::BTreeMap;
+use std::io;
+
+use storage::Storage;
+use super::PAGE_SIZE;
+
+pub trait ObjectStore : Send {
+    // None if there is no object under key
+    fn get(&mut self, key : &str) -> io::Result<Option<Vec<u8>>>;
+    fn put(&mut self, key : &str, data : &[u8]) -> io::Result<()>;
+}
+
+pub struct ObjectStorage<C : ObjectStore> {
+    client : C,
+    prefix : String,
+    num_pages : usize,
+    // the count in the store, num_pages includes pages not uploaded yet
+    stored_pages : usize,
+    // written but not uploaded yet
+    dirty : BTreeMap<usize, Vec<u8>>,
+}
+
+impl<C : ObjectStore> ObjectStorage<C> {
+    pub fn open(mut client : C, prefix : &str) -> io::Result<ObjectStorage<C>> {
+        let num_pages = match client.get(&format!("{}pages", prefix))? {
+            Some(data) => String::from_utf8(data).ok()
+                .and_then(|count| count.parse().ok())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData,
+                                              "page count object is not a number"))?,
+            None => 0,
+        };
+        Ok(ObjectStorage {
+            client,
+            prefix : prefix.to_string(),
+            num_pages,
+            stored_pages : num_pages,
+            dirty : BTreeMap::new(),
+        })
+    }
+
+    fn page_key(&self, page_num : usize) -> String {
+        format!("{}page-{}", self.prefix, page_num)
+    }
+}
+
+impl<C : ObjectStore> Storage for ObjectStorage<C> {
+    fn read_page(&mut self, page_num : usize, data : &mut [u8]) -> io::Result<()> {
+        for b in data.iter_mut() {
+            *b = 0;
+        }
+        if let Some(page) = self.dirty.get(&page_num) {
+            data.copy_from_slice(page);
+            return Ok(());
+        }
+        if page_num >= self.num_pages {
+            return Ok(());
+        }
+        let key = self.page_key(page_num);
+        if let Some(page) = self.client.get(&key)? {
+            let n = page.len().min(data.len());
+            data[..n].copy_from_slice(&page[..n]);
+        }
+        Ok(())
+    }
+
+    fn write_page(&mut self, page_num : usize, data : &[u8]) -> io::Result<()> {
+        self.dirty.insert(page_num, data.to_vec());
+        self.num_pages = self.num_pages.max(page_num + 1);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        while let Some((&page_num, _)) = self.dirty.iter().next() {
+            let key = self.page_key(page_num);
+            self.client.put(&key, &self.dirty[&page_num])?;
+            self.dirty.remove(&page_num);
+        }
+        if self.stored_pages != self.num_pages {
+            let key = format!("{}pages", self.prefix);
+            self.client.put(&key, self.num_pages.to_string().as_bytes())?;
+            self.stored_pages = self.num_pages;
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok((self.num_pages * PAGE_SIZE) as u64)
+    }
+}
+
+impl<C : ObjectStore> Drop for ObjectStorage<C> {
+    // the table only syncs on flush and close, don't lose
+    // what a table that was just dropped wrote
+    fn drop(&mut self) {
+        if let Err(err) = self.sync() {
+            eprintln!("simple-db: could not upload pages: {}", err);
+        }
+    }
+}
@@ -0,0 +1,75 @@
+// Row values too long for their slot in the leaf cell continue on a chain
+// of overflow pages, the cell keeps the number of the first one. An
+// overflow page starts with the number of the next page in the chain
+// (0 on the last one) followed by as many of the remaining bytes as fit.
+use node::{read_u32, write_u32};
+use pager::Pager;
+use super::{DbError, PAGE_USABLE_SIZE};
+
+const NEXT_OFFSET: usize = 0;
+const DATA_OFFSET: usize = 4;
+pub const OVERFLOW_DATA_SIZE: usize = PAGE_USABLE_SIZE - DATA_OFFSET;
+
+pub fn pages_needed(len: usize) -> usize {
+    len.div_ceil(OVERFLOW_DATA_SIZE)
+}
+
+// Store bytes on new pages, returns the first one or 0 if bytes is empty
+pub fn write(pager: &mut Pager, bytes: &[u8]) -> Result<usize, DbError> {
+    let mut first = 0;
+    let mut prev = 0;
+    for chunk in bytes.chunks(OVERFLOW_DATA_SIZE) {
+        let page_num = pager.allocate()?;
+        {
+            let page = pager.get_mut(page_num)?;
+            write_u32(page, NEXT_OFFSET, 0);
+            page[DATA_OFFSET..DATA_OFFSET + chunk.len()].copy_from_slice(chunk);
+        }
+        if prev == 0 {
+            first = page_num;
+        } else {
+            write_u32(pager.get_mut(prev)?, NEXT_OFFSET, page_num as u32);
+        }
+        prev = page_num;
+    }
+    Ok(first)
+}
+
+pub fn read(pager: &mut Pager, first: usize, len: usize) -> Result<Vec<u8>, DbError> {
+    let mut bytes = Vec::with_capacity(len);
+    let mut page_num = first;
+    while bytes.len() < len {
+        if page_num == 0 || page_num >= pager.num_pages {
+            return Err(DbError::Corrupt(format!("overflow chain from page {} ends after \
+                                                 {} of {} bytes", first, bytes.len(), len)));
+        }
+        let page = pager.get(page_num)?;
+        let n = (len - bytes.len()).min(OVERFLOW_DATA_SIZE);
+        bytes.extend_from_slice(&page[DATA_OFFSET..DATA_OFFSET + n]);
+        page_num = read_u32(page, NEXT_OFFSET) as usize;
+    }
+    Ok(bytes)
+}
+
+// Every page of the chain starting at first, in order
+pub fn pages(pager: &mut Pager, first: usize) -> Result<Vec<usize>, DbError> {
+    let mut pages = vec![];
+    let mut page_num = first;
+    while page_num != 0 {
+        // a chain can't be longer than the file unless it loops
+        if page_num >= pager.num_pages || pages.len() >= pager.num_pages {
+            return Err(DbError::Corrupt(format!("overflow chain from page {} is broken",
+                                                first)));
+        }
+        pages.push(page_num);
+        page_num = read_u32(pager.get(page_num)?, NEXT_OFFSET) as usize;
+    }
+    Ok(pages)
+}
+
+pub fn free(pager: &mut Pager, first: usize) -> Result<(), DbError> {
+    for page_num in pages(pager, first)? {
+        pager.free(page_num)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,613 @@
+// The pager hands out pages of the database, keeping at most
+// cache_pages of them in memory. The pages themselves live in a Storage,
+// normally the database file. When the cache is full the least
+// recently used page is dropped. Only pages handed out through get_mut
+// are marked dirty, and only dirty pages are ever written back.
+//
+// Pages aren't pinned. A page from get or get_mut borrows the pager
+// mutably, so nothing else can be loaded, and nothing evicted, while it is
+// in use. Cursors hold on to page numbers instead of pages and look them
+// up again on every step, reading a page back in if it was dropped since.
+// A dirty page is written out before it is dropped.
+//
+// The last PAGE_CHECKSUM_SIZE bytes of every page hold a CRC-32 of the
+// rest of it. It is filled in as the page is written and checked as it is
+// read back, an all zero page has never been written and has no checksum.
+//
+// Pages dropped from the tree go on a free list and are handed out by
+// allocate before the file is made any longer. A free page is zeroed
+// apart from the number of the next free page in its first four bytes.
+//
+// With a flush interval the pager also owns a background thread. After
+// every change the table hands copies of the dirty pages over, and the
+// thread writes and fsyncs them once per interval. The storage mutex is held
+// for every read and write so a copy can never overwrite a newer version
+// of the same page.
+//
+// Opened with mmap the file is also mapped into memory, and get hands out
+// clean pages straight from the mapping instead of copying them into the
+// cache. Pages are still changed in the cache and written with write, the
+// mapping is refreshed on flush_all to take in pages the file grew by.
+//
+// A scan reads leaves in about the order they were allocated, so when the
+// last few cache misses were each a page or two past the one before, the
+// pager tells the storage which pages are coming next. The storage can then
+// read them ahead, the file one asks the OS to.
+//
+// The durability level decides when the storage is synced. The pager
+// itself only syncs from the flusher, skipped with Off, and after every
+// page it writes with Always. The table syncs on flush and commit.
+//
+// A snapshot starts out with copies of the dirty pages only, every other
+// page is the same as in the file. From then on get_mut copies a page for
+// each live snapshot the first time it is changed, so a snapshot reads
+// its own copy where there is one and the file, or a copy still waiting
+// for the flusher, everywhere else.
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use header;
+use mmap::Mmap;
+use node::{read_u32, write_u32};
+use storage::Storage;
+use super::{DbError, Durability, PAGE_SIZE, PAGE_USABLE_SIZE};
+
+// Counts since the pager was opened
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CacheStats {
+    // get or get_mut found the page in memory, mapped pages included
+    pub hits : u64,
+    pub misses : u64,
+    // misses that had to go to the storage, the
+    // rest were still waiting for the flusher
+    pub pages_read : u64,
+    // by the pager and the flusher
+    pub pages_written : u64,
+    pub evictions : u64,
+}
+
+// Misses in a row that make access look sequential
+const SEQUENTIAL_MISSES: usize = 3;
+// How far ahead to prefetch, the next batch is asked for half way through
+const READ_AHEAD_PAGES: usize = 32;
+
+struct Page {
+    data : Vec<u8>,
+    last_used : u64,
+    dirty : bool,
+}
+
+// Page copies waiting for the flusher, a newer copy replaces an older one
+#[derive(Default)]
+struct Pending {
+    pages : BTreeMap<usize, Vec<u8>>,
+    stop : bool,
+    // neither the flusher nor eviction can return errors,
+    // the next take_error or flush_all reports them
+    error : Option<io::Error>,
+}
+
+// Pages as they were when a snapshot was taken, for those changed since
+struct Frozen {
+    num_pages : usize,
+    pages : HashMap<usize, Vec<u8>>,
+}
+
+// Always lock storage before pending when both are needed
+struct Shared {
+    storage : Mutex<Box<dyn Storage>>,
+    pending : Mutex<Pending>,
+    wake : Condvar,
+    durability : Mutex<Durability>,
+    pages_written : AtomicU64,
+}
+
+pub struct Pager {
+    shared : Arc<Shared>,
+    flusher : Option<JoinHandle<()>>,
+    pub num_pages : usize,
+    // false if the file ended part way into a page when it was opened
+    pub whole_pages : bool,
+    pages : HashMap<usize, Page>,
+    // last_used -> page number, the first entry is the next to evict
+    lru : BTreeMap<u64, usize>,
+    clock : u64,
+    cache_pages : usize,
+    use_mmap : bool,
+    map : Option<Mmap>,
+    // mapped pages whose checksum has been checked
+    verified : HashSet<usize>,
+    last_miss : usize,
+    sequential_misses : usize,
+    // everything before this has already been prefetched
+    read_ahead_to : usize,
+    // all but pages_written, which lives in shared
+    stats : CacheStats,
+    // dropped once their snapshot is
+    snapshots : Vec<Weak<Mutex<Frozen>>>,
+}
+
+impl Pager {
+    pub fn open(storage : Box<dyn Storage>, cache_pages : usize,
+                flush_interval : Option<Duration>, mmap : bool) -> io::Result<Pager> {
+        let file_length = storage.len()?;
+        let map = match storage.file() {
+            Some(file) if mmap => Mmap::map(file)?,
+            _ => None,
+        };
+        let shared = Arc::new(Shared {
+            storage : Mutex::new(storage),
+            pending : Mutex::new(Pending::default()),
+            wake : Condvar::new(),
+            durability : Mutex::new(Durability::default()),
+            pages_written : AtomicU64::new(0),
+        });
+        let flusher = flush_interval.map(|interval| {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name("simple-db flusher".to_string())
+                .spawn(move || run_flusher(&shared, interval))
+        }).transpose()?;
+        Ok(Pager {
+            shared,
+            flusher,
+            // a partial last page reads as if it was zero padded
+            num_pages : file_length.div_ceil(PAGE_SIZE as u64) as usize,
+            whole_pages : file_length.is_multiple_of(PAGE_SIZE as u64),
+            pages : HashMap::new(),
+            lru : BTreeMap::new(),
+            clock : 0,
+            // a page is only ever borrowed one at a time,
+            // so a single slot is enough to make progress
+            cache_pages : cache_pages.max(1),
+            use_mmap : mmap,
+            map,
+            verified : HashSet::new(),
+            last_miss : 0,
+            sequential_misses : 0,
+            read_ahead_to : 0,
+            stats : CacheStats::default(),
+            snapshots : vec![],
+        })
+    }
+
+    // Switch to the mmap mode after opening, for a file that asks for it
+    pub fn enable_mmap(&mut self) -> io::Result<()> {
+        if !self.use_mmap {
+            self.map = match self.shared.storage.lock().unwrap().file() {
+                Some(file) => Mmap::map(file)?,
+                None => None,
+            };
+            self.use_mmap = true;
+        }
+        Ok(())
+    }
+
+    pub fn get(&mut self, page_num : usize) -> Result<&[u8], DbError> {
+        if self.mapped(page_num) {
+            let map = self.map.as_ref().unwrap();
+            let data = &map[page_num * PAGE_SIZE..(page_num + 1) * PAGE_SIZE];
+            if !self.verified.contains(&page_num) {
+                if !checksum_ok(data) {
+                    return Err(DbError::Corrupt(format!("page {}: checksum mismatch",
+                                                        page_num)));
+                }
+                self.verified.insert(page_num);
+            }
+            self.stats.hits += 1;
+            return Ok(data);
+        }
+        Ok(&self.load(page_num)?.data[..])
+    }
+
+    // Whether get can use the mapping, a cached or pending copy is newer
+    fn mapped(&self, page_num : usize) -> bool {
+        match self.map {
+            Some(ref map) if (page_num + 1) * PAGE_SIZE <= map.len() => (),
+            _ => return false,
+        }
+        !self.pages.contains_key(&page_num) &&
+            (self.flusher.is_none() ||
+             !self.shared.pending.lock().unwrap().pages.contains_key(&page_num))
+    }
+
+    // Same as get, but the page will be written back on flush
+    pub fn get_mut(&mut self, page_num : usize) -> Result<&mut [u8], DbError> {
+        if !self.snapshots.is_empty() {
+            self.preserve(page_num)?;
+        }
+        let page = self.load(page_num)?;
+        page.dirty = true;
+        Ok(&mut page.data[..])
+    }
+
+    // Hand the page as it is now to every snapshot that doesn't have it yet
+    fn preserve(&mut self, page_num : usize) -> Result<(), DbError> {
+        let live : Vec<_> = self.snapshots.iter().filter_map(Weak::upgrade).collect();
+        self.snapshots.retain(|frozen| frozen.strong_count() > 0);
+        for frozen in live {
+            let mut frozen = frozen.lock().unwrap();
+            if page_num < frozen.num_pages && !frozen.pages.contains_key(&page_num) {
+                let mut data = self.load(page_num)?.data.clone();
+                set_checksum(&mut data);
+                frozen.pages.insert(page_num, data);
+            }
+        }
+        Ok(())
+    }
+
+    // Storage reading the pages as they are now, whatever happens to them later
+    pub fn snapshot(&mut self) -> SnapshotStorage {
+        let pages = self.pages.iter()
+            .filter(|(_, page)| page.dirty)
+            .map(|(&page_num, page)| {
+                // checksums are only filled in as a page is written
+                let mut data = page.data.clone();
+                set_checksum(&mut data);
+                (page_num, data)
+            })
+            .collect();
+        let frozen = Arc::new(Mutex::new(Frozen { num_pages : self.num_pages, pages }));
+        self.snapshots.push(Arc::downgrade(&frozen));
+        SnapshotStorage { shared : self.shared.clone(), frozen }
+    }
+
+    fn load(&mut self, page_num : usize) -> Result<&mut Page, DbError> {
+        if self.pages.contains_key(&page_num) {
+            self.stats.hits += 1;
+        } else {
+            self.stats.misses += 1;
+            if self.lru.len() >= self.cache_pages {
+                self.evict();
+            }
+            // a copy still waiting for the flusher is newer than the file
+            let pending = self.shared.pending.lock().unwrap()
+                .pages.get(&page_num).cloned();
+            let data = match pending {
+                Some(data) => data,
+                None => {
+                    self.read_ahead(page_num);
+                    self.stats.pages_read += 1;
+                    let data = self.read_page(page_num)?;
+                    if !checksum_ok(&data) {
+                        return Err(DbError::Corrupt(format!("page {}: checksum mismatch",
+                                                            page_num)));
+                    }
+                    data
+                },
+            };
+            if page_num >= self.num_pages {
+                self.num_pages = page_num + 1;
+            }
+            self.pages.insert(page_num, Page { data, last_used : 0, dirty : false });
+        }
+        self.clock += 1;
+        let page = self.pages.get_mut(&page_num).unwrap();
+        self.lru.remove(&page.last_used);
+        page.last_used = self.clock;
+        self.lru.insert(self.clock, page_num);
+        Ok(page)
+    }
+
+    // A page for a new node, taken off the free list when there is one
+    pub fn allocate(&mut self) -> Result<usize, DbError> {
+        let head = header::free_list_head(self.get(0)?);
+        if head == 0 {
+            self.num_pages += 1;
+            return Ok(self.num_pages - 1);
+        }
+        let next = read_u32(self.get(head)?, 0) as usize;
+        let count = header::free_page_count(self.get(0)?);
+        let page = self.get_mut(0)?;
+        header::set_free_list_head(page, next);
+        header::set_free_page_count(page, count - 1);
+        for b in self.get_mut(head)?.iter_mut() {
+            *b = 0;
+        }
+        Ok(head)
+    }
+
+    pub fn free(&mut self, page_num : usize) -> Result<(), DbError> {
+        let head = header::free_list_head(self.get(0)?);
+        let count = header::free_page_count(self.get(0)?);
+        let page = self.get_mut(page_num)?;
+        for b in page.iter_mut() {
+            *b = 0;
+        }
+        write_u32(page, 0, head as u32);
+        let page = self.get_mut(0)?;
+        header::set_free_list_head(page, page_num);
+        header::set_free_page_count(page, count + 1);
+        Ok(())
+    }
+
+    // Pages allocate can hand out without growing the file
+    pub fn free_pages(&mut self) -> Result<usize, DbError> {
+        Ok(header::free_page_count(self.get(0)?))
+    }
+
+    // Next page on the free list after page_num, 0 at the end
+    pub fn next_free(&mut self, page_num : usize) -> Result<usize, DbError> {
+        Ok(read_u32(self.get(page_num)?, 0) as usize)
+    }
+
+    // Straight from the file, without the cache or a checksum check.
+    // Pages past the end of the file read as zeroes.
+    pub fn read_page(&self, page_num : usize) -> io::Result<Vec<u8>> {
+        let mut data = vec![0; PAGE_SIZE];
+        self.shared.storage.lock().unwrap().read_page(page_num, &mut data)?;
+        Ok(data)
+    }
+
+    // Whether anything changed hasn't been written yet
+    pub fn is_dirty(&self) -> bool {
+        self.pages.values().any(|page| page.dirty) ||
+            !self.shared.pending.lock().unwrap().pages.is_empty()
+    }
+
+    // Called on every page that has to come from the storage
+    fn read_ahead(&mut self, page_num : usize) {
+        if page_num > self.last_miss && page_num <= self.last_miss + 2 {
+            self.sequential_misses += 1;
+        } else {
+            self.sequential_misses = 0;
+        }
+        self.last_miss = page_num;
+        if self.sequential_misses < SEQUENTIAL_MISSES ||
+            page_num + READ_AHEAD_PAGES / 2 < self.read_ahead_to {
+            return;
+        }
+        let start = self.read_ahead_to.max(page_num + 1);
+        let end = (page_num + 1 + READ_AHEAD_PAGES).min(self.num_pages);
+        if start < end {
+            self.shared.storage.lock().unwrap().prefetch(start, end - start);
+            self.read_ahead_to = end;
+        }
+    }
+
+    // If the page can't be written (disk full, say) it stays cached and
+    // the cache grows past cache_pages until a write succeeds again
+    fn evict(&mut self) {
+        let (&last_used, &page_num) = self.lru.iter().next().unwrap();
+        if let Err(err) = self.flush(page_num) {
+            let mut pending = self.shared.pending.lock().unwrap();
+            if pending.error.is_none() {
+                pending.error = Some(err);
+            }
+            return;
+        }
+        self.lru.remove(&last_used);
+        self.pages.remove(&page_num);
+        self.stats.evictions += 1;
+    }
+
+    pub fn flush(&mut self, page_num : usize) -> io::Result<()> {
+        let page = match self.pages.get_mut(&page_num) {
+            Some(page) if page.dirty => page,
+            _ => return Ok(()),
+        };
+        let always = *self.shared.durability.lock().unwrap() == Durability::Always;
+        let mut storage = self.shared.storage.lock().unwrap();
+        // the cached page is newer than anything handed to the flusher
+        self.shared.pending.lock().unwrap().pages.remove(&page_num);
+        write_page(&mut **storage, page_num, &mut page.data, always)?;
+        self.shared.pages_written.fetch_add(1, Ordering::Relaxed);
+        page.dirty = false;
+        Ok(())
+    }
+
+    // Write whatever the flusher hasn't got to yet, then every dirty page
+    pub fn flush_all(&mut self) -> io::Result<()> {
+        self.take_error()?;
+        write_pending(&self.shared)?;
+        let cached : Vec<usize> = self.lru.values().cloned().collect();
+        for page_num in cached {
+            self.flush(page_num)?;
+        }
+        if self.use_mmap {
+            let storage = self.shared.storage.lock().unwrap();
+            if let Some(file) = storage.file() {
+                if file.metadata()?.len() as usize
+                    > self.map.as_ref().map_or(0, |map| map.len()) {
+                    self.map = Mmap::map(file)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // The first write that failed in the background since the last call
+    pub fn take_error(&mut self) -> io::Result<()> {
+        match self.shared.pending.lock().unwrap().error.take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    // Give copies of the dirty pages to the flusher, a no-op without one
+    pub fn hand_off(&mut self) {
+        if self.flusher.is_none() {
+            return;
+        }
+        let mut pending = self.shared.pending.lock().unwrap();
+        for &page_num in self.lru.values() {
+            let page = self.pages.get_mut(&page_num).unwrap();
+            if page.dirty {
+                pending.pages.insert(page_num, page.data.clone());
+                page.dirty = false;
+            }
+        }
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.shared.storage.lock().unwrap().sync()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            pages_written : self.shared.pages_written.load(Ordering::Relaxed),
+            ..self.stats
+        }
+    }
+
+    pub fn durability(&self) -> Durability {
+        *self.shared.durability.lock().unwrap()
+    }
+
+    pub fn set_durability(&mut self, durability : Durability) {
+        *self.shared.durability.lock().unwrap() = durability;
+    }
+
+    #[cfg(test)]
+    pub fn num_cached(&self) -> usize {
+        self.lru.len()
+    }
+
+}
+
+impl Drop for Pager {
+    // the flusher writes what it still holds before exiting
+    fn drop(&mut self) {
+        if let Some(handle) = self.flusher.take() {
+            self.shared.pending.lock().unwrap().stop = true;
+            self.shared.wake.notify_one();
+            let _ = handle.join();
+        }
+    }
+}
+
+// Read-only, holds on to the pager's storage so it outlives the table
+pub struct SnapshotStorage {
+    shared : Arc<Shared>,
+    frozen : Arc<Mutex<Frozen>>,
+}
+
+impl Storage for SnapshotStorage {
+    fn read_page(&mut self, page_num : usize, data : &mut [u8]) -> io::Result<()> {
+        if let Some(page) = self.frozen.lock().unwrap().pages.get(&page_num) {
+            data.copy_from_slice(page);
+            return Ok(());
+        }
+        if let Some(page) = self.shared.pending.lock().unwrap().pages.get(&page_num) {
+            data.copy_from_slice(page);
+            return Ok(());
+        }
+        self.shared.storage.lock().unwrap().read_page(page_num, data)
+    }
+
+    fn write_page(&mut self, _page_num : usize, _data : &[u8]) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, "snapshots are read-only"))
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok((self.frozen.lock().unwrap().num_pages * PAGE_SIZE) as u64)
+    }
+}
+
+// With sync the page is on the disk by the time this returns
+fn write_page(storage : &mut dyn Storage, page_num : usize,
+              data : &mut [u8], sync : bool) -> io::Result<()> {
+    set_checksum(data);
+    storage.write_page(page_num, data)?;
+    if sync {
+        storage.sync()?;
+    }
+    Ok(())
+}
+
+// Returns how many pages were written. Pages that couldn't be written
+// go back to pending unless a newer copy showed up in the meantime.
+fn write_pending(shared : &Shared) -> io::Result<usize> {
+    let always = *shared.durability.lock().unwrap() == Durability::Always;
+    let mut storage = shared.storage.lock().unwrap();
+    let pages = mem::take(&mut shared.pending.lock().unwrap().pages);
+    let count = pages.len();
+    let mut pages = pages.into_iter();
+    while let Some((page_num, mut data)) = pages.next() {
+        if let Err(err) = write_page(&mut **storage, page_num, &mut data, always) {
+            let mut pending = shared.pending.lock().unwrap();
+            pending.pages.entry(page_num).or_insert(data);
+            for (page_num, data) in pages {
+                pending.pages.entry(page_num).or_insert(data);
+            }
+            return Err(err);
+        }
+        shared.pages_written.fetch_add(1, Ordering::Relaxed);
+    }
+    Ok(count)
+}
+
+fn run_flusher(shared : &Shared, interval : Duration) {
+    loop {
+        let stop = {
+            let pending = shared.pending.lock().unwrap();
+            if pending.stop {
+                true
+            } else {
+                shared.wake.wait_timeout(pending, interval).unwrap().0.stop
+            }
+        };
+        let result = write_pending(shared).and_then(|count| {
+            if count > 0 && *shared.durability.lock().unwrap() != Durability::Off {
+                shared.storage.lock().unwrap().sync()?;
+            }
+            Ok(())
+        });
+        if let Err(err) = result {
+            let mut pending = shared.pending.lock().unwrap();
+            if pending.error.is_none() {
+                pending.error = Some(err);
+            }
+        }
+        if stop {
+            return;
+        }
+    }
+}
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { 0xedb8_8320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+// CRC-32 as used by zlib and friends
+pub fn crc32(data : &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc = CRC_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+pub fn set_checksum(page : &mut [u8]) {
+    let crc = crc32(&page[..PAGE_USABLE_SIZE]);
+    write_u32(page, PAGE_USABLE_SIZE, crc);
+}
+
+pub fn checksum_ok(page : &[u8]) -> bool {
+    page.iter().all(|&b| b == 0) ||
+        read_u32(page, PAGE_USABLE_SIZE) == crc32(&page[..PAGE_USABLE_SIZE])
+}
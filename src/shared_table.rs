@@ -0,0 +1,84 @@
+// Several handles on one open table, one per REPL session or connection
+// say. The file can only be opened once, and this way every handle goes
+// through the same pager: one page cache instead of a copy per handle,
+// and a change made through one handle is there for the next statement
+// on any other. Statements run one at a time, each holds the table for
+// as long as it runs.
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::{CacheStats, DbError, Engine, Row, Table};
+
+#[derive(Clone)]
+pub struct SharedTable {
+    table : Arc<Mutex<Table>>,
+}
+
+impl SharedTable {
+    pub fn new(table : Table) -> SharedTable {
+        SharedTable { table : Arc::new(Mutex::new(table)) }
+    }
+
+    // The table itself, for anything that isn't a statement
+    // such as meta commands. Other handles wait until it's dropped.
+    pub fn lock(&self) -> MutexGuard<'_, Table> {
+        self.table.lock().unwrap()
+    }
+
+    // Closes the table once the last handle is closed
+    // or dropped, until then this only drops the handle
+    pub fn close(self) -> Result<(), DbError> {
+        match Arc::try_unwrap(self.table) {
+            Ok(table) => table.into_inner().unwrap().close(),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+impl Engine for SharedTable {
+    fn insert(&mut self, row : &Row) -> Result<(), DbError> {
+        self.lock().insert(row)
+    }
+
+    fn delete(&mut self, id : u32) -> Result<bool, DbError> {
+        self.lock().delete(id)
+    }
+
+    fn insert_or_replace(&mut self, row : &Row) -> Result<bool, DbError> {
+        self.lock().insert_or_replace(row)
+    }
+
+    fn scan(&mut self, f : &mut dyn FnMut(Row) -> Result<(), DbError>)
+            -> Result<(), DbError> {
+        self.lock().scan(f)
+    }
+
+    fn validate_insert(&mut self, row : &Row) -> Result<(), DbError> {
+        self.lock().validate_insert(row)
+    }
+
+    fn validate_delete(&mut self, id : u32) -> Result<(), DbError> {
+        Engine::validate_delete(&mut *self.lock(), id)
+    }
+
+    fn validate_replace(&mut self, row : &Row) -> Result<(), DbError> {
+        Engine::validate_replace(&mut *self.lock(), row)
+    }
+
+    fn get(&mut self, id : u32) -> Result<Option<Row>, DbError> {
+        self.lock().get(id)
+    }
+
+    fn range(&mut self, from : u32, to : u32,
+             f : &mut dyn FnMut(Row) -> Result<(), DbError>) -> Result<(), DbError> {
+        self.lock().range(from, to, f)
+    }
+
+    fn range_limit(&mut self, from : u32, to : u32, offset : usize, limit : usize,
+                   f : &mut dyn FnMut(Row) -> Result<(), DbError>) -> Result<(), DbError> {
+        self.lock().range_limit(from, to, offset, limit, f)
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        Some(self.lock().cache_stats())
+    }
+}
@@ -0,0 +1,239 @@
+// Statement results are pushed into a ResultSink instead of being written
+// straight to a Write, so the executor doesn't need to know anything about
+// how rows end up being displayed.
+use std::fmt;
+use std::io::Write;
+
+use super::DbError;
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub enum Value {
+    Integer(u32),
+    Text(String),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Integer(i) => write!(f, "{}", i),
+            Value::Text(ref s) => write!(f, "{}", s),
+        }
+    }
+}
+
+pub trait ResultSink {
+    // Called once before the first row of a statement that returns rows
+    fn begin_rows(&mut self, columns : &[&str]) -> Result<(), DbError>;
+    fn push_row(&mut self, values : &[Value]) -> Result<(), DbError>;
+    // Called once after the last row
+    fn end(&mut self) -> Result<(), DbError>;
+    // Called instead of end when the statement failed
+    fn error(&mut self, err : &DbError) -> Result<(), DbError>;
+}
+
+// The REPL format, (1, user1, person1@example.com)
+pub struct TextSink<W: Write> {
+    writer : W,
+}
+
+impl<W: Write> TextSink<W> {
+    pub fn new(writer : W) -> TextSink<W> {
+        TextSink { writer }
+    }
+}
+
+impl<W: Write> ResultSink for TextSink<W> {
+    fn begin_rows(&mut self, _columns : &[&str]) -> Result<(), DbError> {
+        Ok(())
+    }
+
+    fn push_row(&mut self, values : &[Value]) -> Result<(), DbError> {
+        let values : Vec<String> = values.iter().map(|v| v.to_string()).collect();
+        writeln!(self.writer, "({})", values.join(", "))?;
+        Ok(())
+    }
+
+    fn end(&mut self) -> Result<(), DbError> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn error(&mut self, err : &DbError) -> Result<(), DbError> {
+        writeln!(self.writer, "{}", err)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+// RFC 4180: comma separated with a header line and CRLF after every
+// record, fields containing a comma, quote or line break are quoted.
+// Every row is written as it comes.
+pub struct CsvSink<W: Write> {
+    writer : W,
+}
+
+impl<W: Write> CsvSink<W> {
+    pub fn new(writer : W) -> CsvSink<W> {
+        CsvSink { writer }
+    }
+
+    fn write_record<T: AsRef<str>>(&mut self, fields : &[T]) -> Result<(), DbError> {
+        let fields : Vec<String> = fields.iter().map(|f| csv_field(f.as_ref())).collect();
+        write!(self.writer, "{}\r\n", fields.join(","))?;
+        Ok(())
+    }
+}
+
+fn csv_field(field : &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+impl<W: Write> ResultSink for CsvSink<W> {
+    fn begin_rows(&mut self, columns : &[&str]) -> Result<(), DbError> {
+        self.write_record(columns)
+    }
+
+    fn push_row(&mut self, values : &[Value]) -> Result<(), DbError> {
+        let values : Vec<String> = values.iter().map(|v| v.to_string()).collect();
+        self.write_record(&values)
+    }
+
+    fn end(&mut self) -> Result<(), DbError> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn error(&mut self, _err : &DbError) -> Result<(), DbError> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+// A JSON array with one object per row. Each object is written as its row
+// comes, so no export has to fit in memory. The closing bracket is only
+// written once the statement is done, one that fails part way leaves the
+// array open rather than passing for the whole result.
+pub struct JsonSink<W: Write> {
+    writer : W,
+    columns : Vec<String>,
+    // one object per line instead of all on one
+    pretty : bool,
+    rows_written : usize,
+}
+
+impl<W: Write> JsonSink<W> {
+    pub fn new(writer : W) -> JsonSink<W> {
+        JsonSink {
+            writer,
+            columns : vec![],
+            pretty : false,
+            rows_written : 0,
+        }
+    }
+
+    pub fn pretty(writer : W) -> JsonSink<W> {
+        JsonSink { pretty : true, ..JsonSink::new(writer) }
+    }
+}
+
+fn json_string(s : &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_value(value : &Value) -> String {
+    match *value {
+        Value::Integer(i) => i.to_string(),
+        Value::Text(ref s) => json_string(s),
+    }
+}
+
+impl<W: Write> ResultSink for JsonSink<W> {
+    fn begin_rows(&mut self, columns : &[&str]) -> Result<(), DbError> {
+        self.columns = columns.iter().map(|c| c.to_string()).collect();
+        self.rows_written = 0;
+        write!(self.writer, "[")?;
+        Ok(())
+    }
+
+    fn push_row(&mut self, values : &[Value]) -> Result<(), DbError> {
+        let separator = match (self.rows_written, self.pretty) {
+            (0, false) => "",
+            (_, false) => ",",
+            (0, true) => "\n  ",
+            (_, true) => ",\n  ",
+        };
+        let fields : Vec<String> = self.columns.iter().zip(values.iter())
+            .map(|(c, v)| format!("{}:{}", json_string(c), json_value(v)))
+            .collect();
+        write!(self.writer, "{}{{{}}}", separator, fields.join(","))?;
+        self.rows_written += 1;
+        Ok(())
+    }
+
+    fn end(&mut self) -> Result<(), DbError> {
+        if self.pretty && self.rows_written > 0 {
+            writeln!(self.writer)?;
+        }
+        writeln!(self.writer, "]")?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn error(&mut self, _err : &DbError) -> Result<(), DbError> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+// Keeps everything in memory, handy for tests and embedding
+#[derive(Debug, Default)]
+pub struct MemorySink {
+    pub columns : Vec<String>,
+    pub rows : Vec<Vec<Value>>,
+    pub error : Option<String>,
+}
+
+impl MemorySink {
+    pub fn new() -> MemorySink {
+        MemorySink::default()
+    }
+}
+
+impl ResultSink for MemorySink {
+    fn begin_rows(&mut self, columns : &[&str]) -> Result<(), DbError> {
+        self.columns = columns.iter().map(|c| c.to_string()).collect();
+        Ok(())
+    }
+
+    fn push_row(&mut self, values : &[Value]) -> Result<(), DbError> {
+        self.rows.push(values.to_vec());
+        Ok(())
+    }
+
+    fn end(&mut self) -> Result<(), DbError> {
+        Ok(())
+    }
+
+    fn error(&mut self, err : &DbError) -> Result<(), DbError> {
+        self.error = Some(err.to_string());
+        Ok(())
+    }
+}
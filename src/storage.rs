@@ -0,0 +1,312 @@
+// Where the pager keeps its pages. Reads and writes are always whole
+// pages, a page past the end reads as zeroes and writing it makes the
+// storage longer. len is in bytes so a file cut short part way into a page
+// can still be told apart.
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use super::PAGE_SIZE;
+
+// fsync all the way to the disk. sync_all already does that on Linux and
+// Windows (FlushFileBuffers), but on macOS fsync leaves the data in the
+// drive's cache unless asked with F_FULLFSYNC. Not every filesystem there
+// supports it, those get a plain fsync.
+pub fn sync_file(file : &File) -> io::Result<()> {
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    {
+        use std::os::unix::io::AsRawFd;
+        if unsafe { ::libc::fcntl(file.as_raw_fd(), ::libc::F_FULLFSYNC) } != -1 {
+            return Ok(());
+        }
+    }
+    file.sync_all()
+}
+
+// fsync the directory holding path, so that creating or renaming path
+// survives a crash as well. Linux needs this, Windows can't open a
+// directory to sync it and doesn't need to.
+pub fn sync_dir(path : &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let dir = match path.parent() {
+            Some(dir) if dir.as_os_str().is_empty() => Path::new("."),
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        File::open(dir)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+pub trait Storage : Send {
+    fn read_page(&mut self, page_num : usize, data : &mut [u8]) -> io::Result<()>;
+    fn write_page(&mut self, page_num : usize, data : &[u8]) -> io::Result<()>;
+    fn sync(&mut self) -> io::Result<()>;
+    fn len(&self) -> io::Result<u64>;
+
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    // The file behind the storage, if there is one, for the mmap mode
+    fn file(&self) -> Option<&File> {
+        None
+    }
+
+    // A hint that the count pages from page_num are about to be read
+    fn prefetch(&mut self, _page_num : usize, _count : usize) {}
+}
+
+// O_DIRECT wants buffers aligned the same way as the file offsets
+#[repr(align(4096))]
+struct AlignedPage([u8; PAGE_SIZE]);
+
+pub struct FileStorage {
+    file : File,
+    // only with direct I/O, every page goes through it
+    aligned : Option<Box<AlignedPage>>,
+}
+
+impl FileStorage {
+    // Creates the file if it doesn't exist
+    pub fn open(filename : PathBuf) -> io::Result<FileStorage> {
+        FileStorage::open_file(filename, false, false)
+    }
+
+    // The file must already exist, writes fail
+    pub fn open_read_only(filename : PathBuf) -> io::Result<FileStorage> {
+        FileStorage::open_file(filename, true, false)
+    }
+
+    // Bypass the OS page cache, to measure the pager's own cache.
+    // That's O_DIRECT on Linux and F_NOCACHE on macOS, anywhere else or on
+    // a filesystem without it (tmpfs for one) this is a normal open.
+    pub fn open_direct(filename : PathBuf, read_only : bool) -> io::Result<FileStorage> {
+        FileStorage::open_file(filename, read_only, true)
+    }
+
+    // Whether pages really skip the OS page cache
+    pub fn is_direct(&self) -> bool {
+        self.aligned.is_some()
+    }
+
+    fn open_file(filename : PathBuf, read_only : bool,
+                 direct : bool) -> io::Result<FileStorage> {
+        let created = !read_only && !filename.exists();
+        let mut options = OpenOptions::new();
+        options.read(true);
+        if !read_only {
+            options.write(true).create(true).truncate(false);
+        }
+        let (file, direct) = if direct {
+            open_direct(&options, &filename)?
+        } else {
+            (options.open(&filename)?, false)
+        };
+        if created {
+            sync_dir(&filename)?;
+        }
+        lock(&file, !read_only)?;
+        let aligned = if direct { Some(Box::new(AlignedPage([0; PAGE_SIZE]))) } else { None };
+        Ok(FileStorage { file, aligned })
+    }
+}
+
+// An advisory lock on the whole file, held until it is closed. Writers
+// take it exclusively and readers shared, so any number of readers or a
+// single writer can have the file open. The lock belongs to the open file,
+// so a second open in the same process is turned away as well. Fails with
+// WouldBlock rather than waiting when the file is busy. Only unix has
+// flock, elsewhere nothing is locked.
+#[cfg(unix)]
+fn lock(file : &File, exclusive : bool) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let operation = if exclusive { ::libc::LOCK_EX } else { ::libc::LOCK_SH };
+    loop {
+        if unsafe { ::libc::flock(file.as_raw_fd(), operation | ::libc::LOCK_NB) } == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn lock(_file : &File, _exclusive : bool) -> io::Result<()> {
+    Ok(())
+}
+
+// The file and whether direct I/O could be turned on for it
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn open_direct(options : &OpenOptions, filename : &Path) -> io::Result<(File, bool)> {
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut direct = options.clone();
+    direct.custom_flags(::libc::O_DIRECT);
+    match direct.open(filename) {
+        Ok(file) => Ok((file, true)),
+        Err(ref err) if err.raw_os_error() == Some(::libc::EINVAL) =>
+            Ok((options.open(filename)?, false)),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn open_direct(options : &OpenOptions, filename : &Path) -> io::Result<(File, bool)> {
+    use std::os::unix::io::AsRawFd;
+    let file = options.open(filename)?;
+    let direct = unsafe { ::libc::fcntl(file.as_raw_fd(), ::libc::F_NOCACHE, 1) } != -1;
+    Ok((file, direct))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android",
+              target_os = "macos", target_os = "ios")))]
+fn open_direct(options : &OpenOptions, filename : &Path) -> io::Result<(File, bool)> {
+    Ok((options.open(filename)?, false))
+}
+
+impl Storage for FileStorage {
+    fn read_page(&mut self, page_num : usize, data : &mut [u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start((page_num * PAGE_SIZE) as u64))?;
+        let buf = match self.aligned {
+            Some(ref mut aligned) => &mut aligned.0[..],
+            None => &mut *data,
+        };
+        let mut read = 0;
+        while read < buf.len() {
+            match self.file.read(&mut buf[read..]) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+        for b in buf[read..].iter_mut() {
+            *b = 0;
+        }
+        if let Some(ref aligned) = self.aligned {
+            data.copy_from_slice(&aligned.0);
+        }
+        Ok(())
+    }
+
+    fn write_page(&mut self, page_num : usize, data : &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start((page_num * PAGE_SIZE) as u64))?;
+        match self.aligned {
+            Some(ref mut aligned) => {
+                aligned.0.copy_from_slice(data);
+                self.file.write_all(&aligned.0)
+            },
+            None => self.file.write_all(data),
+        }
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        sync_file(&self.file)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    // a mapping would read through the page cache direct I/O skips
+    fn file(&self) -> Option<&File> {
+        if self.is_direct() { None } else { Some(&self.file) }
+    }
+
+    // Asks the OS to start reading the pages into its page cache,
+    // which direct I/O doesn't use
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn prefetch(&mut self, page_num : usize, count : usize) {
+        use std::os::unix::io::AsRawFd;
+        if self.is_direct() {
+            return;
+        }
+        unsafe {
+            ::libc::posix_fadvise(self.file.as_raw_fd(), (page_num * PAGE_SIZE) as ::libc::off_t,
+                                  (count * PAGE_SIZE) as ::libc::off_t,
+                                  ::libc::POSIX_FADV_WILLNEED);
+        }
+    }
+}
+
+// Pages held in memory, gone once the table is dropped
+#[derive(Default)]
+pub struct MemoryStorage {
+    data : Vec<u8>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn read_page(&mut self, page_num : usize, data : &mut [u8]) -> io::Result<()> {
+        let start = (page_num * PAGE_SIZE).min(self.data.len());
+        let end = (start + data.len()).min(self.data.len());
+        data[..end - start].copy_from_slice(&self.data[start..end]);
+        for b in data[end - start..].iter_mut() {
+            *b = 0;
+        }
+        Ok(())
+    }
+
+    fn write_page(&mut self, page_num : usize, data : &[u8]) -> io::Result<()> {
+        let start = page_num * PAGE_SIZE;
+        if self.data.len() < start + data.len() {
+            self.data.resize(start + data.len(), 0);
+        }
+        self.data[start..start + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.data.len() as u64)
+    }
+}
+
+// A database image that is only ever read, such as one from include_bytes!
+pub struct ByteStorage {
+    data : &'static [u8],
+}
+
+impl ByteStorage {
+    pub fn new(data : &'static [u8]) -> ByteStorage {
+        ByteStorage { data }
+    }
+}
+
+impl Storage for ByteStorage {
+    fn read_page(&mut self, page_num : usize, data : &mut [u8]) -> io::Result<()> {
+        let start = (page_num * PAGE_SIZE).min(self.data.len());
+        let end = (start + data.len()).min(self.data.len());
+        data[..end - start].copy_from_slice(&self.data[start..end]);
+        for b in data[end - start..].iter_mut() {
+            *b = 0;
+        }
+        Ok(())
+    }
+
+    fn write_page(&mut self, _page_num : usize, _data : &[u8]) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, "storage is read-only"))
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.data.len() as u64)
+    }
+}